@@ -1,19 +1,236 @@
-use std::io::{self, BufRead, Read, Seek, Write};
+use std::{
+    fmt::Display,
+    io::{self, BufRead, Cursor, Seek, SeekFrom, Write},
+};
 
 use crate::{
-    decoder::Codec,
-    instruction::{Instruction, JumpType, Location, Memory, Register},
+    decoder::{CodeSource, Codec, CustomDecoder, DecodeError},
+    instruction::{
+        Immediate, Instruction, JumpType, Location, Memory, Port, Register, Repeat, ShiftCount,
+        ShiftType, StringType,
+    },
+    ports::{NoPorts, PortBus},
+    trace::{Event, Listing, Tracer},
 };
 
-enum Bits {
-    High,
-    Low,
-    All,
+/// Why `run` stopped executing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The instruction stream was exhausted
+    EndOfProgram,
+    /// The step limit passed to `run_with_limit` was reached
+    StepLimit,
+    /// A `hlt` was executed
+    Halted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunOutcome {
+    pub reason: StopReason,
+    /// Instruction pointer at the moment execution stopped
+    pub ip: u16,
+    /// Number of instructions executed
+    pub instructions: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuError {
+    /// The instruction at `ip` could not be decoded
+    Decode { error: DecodeError, ip: u16 },
+    /// Interrupt `vector` was raised by the instruction at `ip`, but its vector
+    /// table entry is 0000:0000, so no handler is installed
+    UnhandledInterrupt { vector: u8, ip: u16 },
+    /// The instruction at `ip` decoded but the CPU can't execute it, e.g. because
+    /// it stores into an immediate
+    Unimplemented {
+        opcode: u8,
+        mnemonic: &'static str,
+        ip: u16,
+    },
+}
+
+impl Display for CpuError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CpuError::Decode { error, ip } => write!(f, "decode error at {:#06x}: {}", ip, error),
+            CpuError::UnhandledInterrupt { vector, ip } => {
+                write!(f, "unhandled interrupt {:#04x} at {:#06x}", vector, ip)
+            }
+            CpuError::Unimplemented {
+                opcode,
+                mnemonic,
+                ip,
+            } => write!(
+                f,
+                "unimplemented instruction {} (opcode {:#04x}) at {:#06x}",
+                mnemonic, opcode, ip
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CpuError {}
+
+/// Programs are loaded at offset 0 of their segment, so they can use all of it
+pub const MAX_PROGRAM_SIZE: u64 = 1 << 16;
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    /// The program doesn't fit in a single segment and would wrap around
+    ProgramTooLarge {
+        actual: u64,
+        max: u64,
+    },
+    /// Memory handed to `Cpu::with_memory` can't hold the whole address space
+    MemoryTooSmall {
+        actual: usize,
+        required: usize,
+    },
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "failed to read program: {}", e),
+            LoadError::ProgramTooLarge { actual, max } => write!(
+                f,
+                "program is {} bytes but at most {} fit in a segment",
+                actual, max
+            ),
+            LoadError::MemoryTooSmall { actual, required } => write!(
+                f,
+                "memory is {} bytes but the CPU needs {}",
+                actual, required
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> Self {
+        LoadError::Io(e)
+    }
+}
+
+/// PF is set when the low byte of a result has an even number of set bits
+fn parity(byte: u8) -> bool {
+    byte.count_ones().is_multiple_of(2)
+}
+
+/// Physical address of the BIOS data area tick count (0040:006C)
+const BIOS_TICK_COUNT: usize = 0x046C;
+
+/// The 8086 has 20 address lines, so addresses past 1 MiB wrap to the bottom
+pub const MEMORY_SIZE: usize = 1 << 20;
+
+/// Physical address of `segment:offset`
+pub fn physical_address(segment: u16, offset: u16) -> u32 {
+    (((segment as u32) << 4) + offset as u32) & (MEMORY_SIZE as u32 - 1)
+}
+
+/// Upper bound on the length of an instruction fetched from memory, prefixes included
+const MAX_INSTRUCTION_LEN: u16 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Flags {
+    /// Unsigned carry or borrow out of the top bit
+    pub cf: bool,
+    pub sf: bool,
+    pub zf: bool,
+    pub pf: bool,
+    /// Carry or borrow out of bit 3, for BCD adjustments
+    pub af: bool,
+    pub of: bool,
+    /// Direction: string instructions step SI and DI down instead of up
+    pub df: bool,
+    /// Interrupt enable, external interrupts are only taken while it is set
+    pub if_: bool,
 }
 
-pub struct Cpu<T>
+impl Flags {
+    /// The flags register as `pushf` stores it. The unused high bits and bit 1 read
+    /// as 1 like on the 8086, and the trap flag isn't modelled so it is always 0.
+    pub fn to_word(&self) -> u16 {
+        0xF002
+            | self.cf as u16
+            | (self.pf as u16) << 2
+            | (self.af as u16) << 4
+            | (self.zf as u16) << 6
+            | (self.sf as u16) << 7
+            | (self.if_ as u16) << 9
+            | (self.df as u16) << 10
+            | (self.of as u16) << 11
+    }
+    /// The flags in a flags register word, ignoring the bits `to_word` fixes
+    pub fn from_word(word: u16) -> Self {
+        let bit = |n: u16| (word >> n) & 1 == 1;
+        Self {
+            cf: bit(0),
+            pf: bit(2),
+            af: bit(4),
+            zf: bit(6),
+            sf: bit(7),
+            if_: bit(9),
+            df: bit(10),
+            of: bit(11),
+        }
+    }
+    /// Each flag with its letter, in the order of their bits in the flags register
+    fn letters(&self) -> [(bool, char); 8] {
+        [
+            (self.cf, 'C'),
+            (self.pf, 'P'),
+            (self.af, 'A'),
+            (self.zf, 'Z'),
+            (self.sf, 'S'),
+            (self.if_, 'I'),
+            (self.df, 'D'),
+            (self.of, 'O'),
+        ]
+    }
+}
+
+/// Letters of the set flags in the order `CPAZSIDO`, e.g. `PZI`
+impl Display for Flags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (set, letter) in self.letters() {
+            if set {
+                write!(f, "{}", letter)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Names of `CpuState::registers` by index
+pub const REGISTER_NAMES: [&str; 12] = [
+    "ax", "bx", "cx", "dx", "sp", "bp", "si", "di", "ss", "ds", "es", "cs",
+];
+
+/// Snapshot of the architectural state, as handed to tracers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuState {
+    /// Same layout as the CPU's register file, see `REGISTER_NAMES`
+    pub registers: [u16; 12],
+    pub flags: Flags,
+}
+
+fn mask(width: u8) -> u16 {
+    if width == 8 {
+        0xFF
+    } else {
+        0xFFFF
+    }
+}
+
+/// `M` is the 1 MiB of memory, owned by default but e.g. a borrowed `&mut [u8]`
+/// when the host wants to inspect it while the program runs
+pub struct Cpu<T, M = Vec<u8>>
 where
-    T: BufRead,
+    T: CodeSource,
 {
     /// 0: ax
     /// 1: bx
@@ -26,244 +243,3157 @@ where
     /// 8: ss
     /// 9: ds
     /// 10: es
-    registers: [u16; 11],
+    /// 11: cs
+    registers: [u16; 12],
+    ip: u16,
     instructions: Codec<T>,
-    memory: [u8; 1 << 16],
+    /// Segment the instruction stream is mapped at, code in any other segment
+    /// is fetched from memory
+    code_segment: Option<u16>,
+    memory: M,
+    /// Address, opcode and bytes of the instruction being executed
+    instruction_ip: u16,
+    opcode: u8,
+    fetched: Vec<u8>,
+    cf: bool,
     sf: bool,
     zf: bool,
     pf: bool,
+    af: bool,
     of: bool,
+    df: bool,
+    if_: bool,
+    /// Instructions between BIOS timer ticks, `None` leaves the tick counter alone
+    timer_interval: Option<usize>,
+    timer_elapsed: usize,
+    /// Raise INT 8 on each tick, like the 8253 timer wired to IRQ 0
+    timer_interrupt: bool,
+    /// A tick whose INT 8 is held off until IF is set and no SS load shadows it
+    timer_pending: bool,
+    /// Set by a write to SS so the SS:SP pair can be loaded without an interrupt in between
+    interrupt_shadow: bool,
+    /// Set by `hlt`, after which nothing more is executed until a timer interrupt
+    halted: bool,
+    report_misaligned: bool,
+    last_instruction: Option<(u16, Instruction)>,
+    /// Mnemonic of the instruction being executed, for errors raised while executing it
+    mnemonic: &'static str,
+    tracer: Option<Box<dyn Tracer>>,
+    ports: Box<dyn PortBus>,
 }
 
 impl<T: BufRead + Seek> Cpu<T> {
+    /// Like `new`, but first checks that the program fits in its segment
+    pub fn load(mut instructions: T) -> Result<Self, LoadError> {
+        let start = instructions.stream_position()?;
+        let end = instructions.seek(SeekFrom::End(0))?;
+        instructions.seek(SeekFrom::Start(start))?;
+        let actual = end - start;
+        if actual > MAX_PROGRAM_SIZE {
+            return Err(LoadError::ProgramTooLarge {
+                actual,
+                max: MAX_PROGRAM_SIZE,
+            });
+        }
+        Ok(Self::new(instructions))
+    }
+}
+
+/// Step limit of `run_program`, so a program stuck in a loop stops with `StepLimit`
+pub const RUN_PROGRAM_MAX_STEPS: usize = 1_000_000;
+
+/// Outcome and final registers and flags of a `run_program` run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunResult {
+    pub outcome: Result<RunOutcome, CpuError>,
+    pub state: CpuState,
+    /// Where execution stopped, which `outcome` doesn't say when it is an error
+    pub ip: u16,
+}
+
+/// Run `code` quietly on a fresh CPU after `setup` has prepared registers or memory
+pub fn run_program(code: &[u8], setup: impl FnOnce(&mut Cpu<Cursor<Vec<u8>>>)) -> RunResult {
+    let mut cpu = Cpu::load_code_and_data(code, &[]);
+    setup(&mut cpu);
+    let outcome = cpu.run_with_limit(RUN_PROGRAM_MAX_STEPS);
+    RunResult {
+        outcome,
+        state: cpu.state(),
+        ip: cpu.ip,
+    }
+}
+
+impl Cpu<Cursor<Vec<u8>>> {
+    /// Build a CPU running `code` from memory, without going through a file
+    ///
+    /// ```
+    /// use simulator_8086::{cpu::Cpu, decoder::decode};
+    ///
+    /// // mov ax, 5; add ax, 3
+    /// let code = [0xB8, 0x05, 0x00, 0x05, 0x03, 0x00];
+    /// let (first, rest) = decode(&code).unwrap();
+    /// assert_eq!(first.to_string(), "mov ax, 5");
+    /// assert_eq!(decode(rest).unwrap().0.to_string(), "add ax, 3");
+    ///
+    /// let mut cpu = Cpu::from_bytes(&code);
+    /// cpu.run().unwrap();
+    /// cpu.expect().ax(8);
+    /// ```
+    pub fn from_bytes(code: &[u8]) -> Self {
+        Self::new(Cursor::new(code.to_vec()))
+    }
+    /// Build a CPU running `code` with each `(address, bytes)` blob preloaded into memory.
+    /// Instructions are fetched from their own stream, so data never overlaps them.
+    pub fn load_code_and_data(code: &[u8], data: &[(u16, &[u8])]) -> Self {
+        let mut cpu = Self::from_bytes(code);
+        for (address, bytes) in data {
+            cpu.write_mem(*address, bytes);
+        }
+        cpu
+    }
+    /// Build a CPU for a multi-segment image, placing each `(segment, bytes)` blob at
+    /// physical address `segment * 16` and starting execution at `cs:ip`. All code is
+    /// fetched from memory, so there is no end of program and `run` needs a step limit.
+    pub fn load_segments(segments: &[(u16, &[u8])], cs: u16, ip: u16) -> Self {
+        let mut cpu = Self::new(Cursor::new(Vec::new()));
+        cpu.code_segment = None;
+        for (segment, bytes) in segments {
+            let base = physical_address(*segment, 0) as usize;
+            for (offset, byte) in bytes.iter().enumerate() {
+                cpu.memory[(base + offset) % MEMORY_SIZE] = *byte;
+            }
+        }
+        cpu.registers[11] = cs;
+        cpu.ip = ip;
+        cpu
+    }
+}
+
+impl<T: CodeSource> Cpu<T> {
     pub fn new(instructions: T) -> Self {
+        Self::from_parts(instructions, vec![0; MEMORY_SIZE])
+    }
+}
+
+impl<T: CodeSource, M: AsRef<[u8]> + AsMut<[u8]>> Cpu<T, M> {
+    /// Run over memory provided by the caller, which must be at least `MEMORY_SIZE`
+    /// bytes. Its contents are kept, so it can be preloaded.
+    pub fn with_memory(instructions: T, memory: M) -> Result<Self, LoadError> {
+        let actual = memory.as_ref().len();
+        if actual < MEMORY_SIZE {
+            return Err(LoadError::MemoryTooSmall {
+                actual,
+                required: MEMORY_SIZE,
+            });
+        }
+        Ok(Self::from_parts(instructions, memory))
+    }
+    fn from_parts(instructions: T, memory: M) -> Self {
         Self {
-            registers: [0; 11],
+            registers: [0; 12],
+            ip: 0,
             instructions: Codec::new(instructions),
-            memory: [0; 1 << 16],
+            code_segment: Some(0),
+            memory,
+            instruction_ip: 0,
+            opcode: 0,
+            fetched: Vec::new(),
+            cf: false,
             sf: false,
             zf: false,
             pf: false,
+            af: false,
             of: false,
+            df: false,
+            if_: false,
+            timer_interval: None,
+            timer_elapsed: 0,
+            timer_interrupt: false,
+            timer_pending: false,
+            interrupt_shadow: false,
+            halted: false,
+            report_misaligned: false,
+            last_instruction: None,
+            mnemonic: "",
+            tracer: None,
+            ports: Box::new(NoPorts),
+        }
+    }
+    pub fn run(&mut self) -> Result<RunOutcome, CpuError> {
+        self.run_with_limit(usize::MAX)
+    }
+    /// Run with no tracer, so nothing is printed. An installed tracer is put back after.
+    pub fn run_quiet(&mut self) -> Result<RunOutcome, CpuError> {
+        let tracer = self.tracer.take();
+        let outcome = self.run();
+        self.tracer = tracer;
+        outcome
+    }
+    /// Run printing a hex bytes + mnemonic listing line per instruction to stdout,
+    /// in place of an installed tracer, which is put back after
+    pub fn run_verbose(&mut self) -> Result<RunOutcome, CpuError> {
+        let tracer = self.tracer.replace(Box::new(Listing::stdout()));
+        let outcome = self.run();
+        self.tracer = tracer;
+        outcome
+    }
+    pub fn run_with_limit(&mut self, max_steps: usize) -> Result<RunOutcome, CpuError> {
+        let mut instructions = 0;
+        let reason = loop {
+            if self.halted && !self.timer_can_wake() {
+                break StopReason::Halted;
+            }
+            if instructions == max_steps {
+                break StopReason::StepLimit;
+            }
+            if self.step()?.is_none() {
+                break StopReason::EndOfProgram;
+            }
+            instructions += 1;
+        };
+        Ok(RunOutcome {
+            reason,
+            ip: self.ip,
+            instructions,
+        })
+    }
+    /// Fetch and execute a single instruction and return it, or `None` at the end of
+    /// the program or once halted with nothing to wake the CPU
+    pub fn step(&mut self) -> Result<Option<&Instruction>, CpuError> {
+        if self.halted {
+            if !self.timer_can_wake() {
+                return Ok(None);
+            }
+            // hlt idles until the timer's INT 8, then resumes in its handler
+            while self.halted {
+                self.tick_timer()?;
+            }
         }
+        let ip = self.ip;
+        let instruction = match self.fetch() {
+            Ok(Some(instruction)) => instruction,
+            Ok(None) => return Ok(None),
+            Err(error) => return Err(CpuError::Decode { error, ip }),
+        };
+        if let Some(tracer) = &mut self.tracer {
+            tracer.instruction(ip, &self.fetched, &instruction);
+        }
+        self.execute_instruction(instruction)?;
+        if self.tracer.is_some() {
+            let state = self.state();
+            if let Some(tracer) = &mut self.tracer {
+                tracer.executed(&state);
+            }
+        }
+        self.tick_timer()?;
+        Ok(self
+            .last_instruction
+            .as_ref()
+            .map(|(_, instruction)| instruction))
+    }
+    /// Decode the instruction at CS:IP and step IP past it. It comes from the
+    /// instruction stream while CS is the segment the stream is mapped at, and
+    /// from memory otherwise.
+    fn fetch(&mut self) -> Result<Option<Instruction>, DecodeError> {
+        self.instruction_ip = self.ip;
+        let cs = self.registers[11];
+        let instruction = if self.code_segment == Some(cs) {
+            if self.instructions.position()? != self.ip as u64 {
+                self.instructions.jump_to(self.ip as u64)?;
+            }
+            let instruction = self.instructions.next_op()?;
+            self.opcode = self.instructions.opcode();
+            self.fetched.clear();
+            self.fetched
+                .extend_from_slice(self.instructions.instruction_bytes());
+            instruction
+        } else {
+            let window: Vec<u8> = (0..MAX_INSTRUCTION_LEN)
+                .map(|offset| self.read_byte(cs, self.ip.wrapping_add(offset)))
+                .collect();
+            let mut codec = Codec::new(Cursor::new(window));
+            let instruction = codec.next_op()?;
+            self.opcode = codec.opcode();
+            self.fetched = codec.instruction_bytes().to_vec();
+            instruction
+        };
+        self.ip = self.ip.wrapping_add(self.fetched.len() as u16);
+        Ok(instruction)
     }
-    pub fn run(&mut self) {
-        while let Some(instruction) = self.instructions.next_op() {
-            self.execute_instruction(instruction);
+    /// Increment the BIOS tick count at 0040:006C every `interval` instructions,
+    /// so programs polling the clock see time pass
+    pub fn set_timer_interval(&mut self, interval: Option<usize>) {
+        self.timer_interval = interval.filter(|&interval| interval > 0);
+        self.timer_elapsed = 0;
+    }
+    /// Also raise INT 8 on every timer tick, through the handler the program installed
+    /// in the vector table. A tick while IF is clear stays pending until `sti`.
+    pub fn set_timer_interrupt(&mut self, enabled: bool) {
+        self.timer_interrupt = enabled;
+        self.timer_pending = false;
+    }
+    fn tick_timer(&mut self) -> Result<(), CpuError> {
+        let Some(interval) = self.timer_interval else {
+            return Ok(());
+        };
+        self.timer_elapsed += 1;
+        if self.timer_elapsed >= interval {
+            self.timer_elapsed = 0;
+            let counter = &mut self.memory.as_mut()[BIOS_TICK_COUNT..BIOS_TICK_COUNT + 4];
+            let ticks = u32::from_le_bytes(counter.try_into().unwrap()).wrapping_add(1);
+            counter.copy_from_slice(&ticks.to_le_bytes());
+            self.timer_pending |= self.timer_interrupt;
+        }
+        if self.timer_pending && self.if_ && !self.interrupt_shadow {
+            self.timer_pending = false;
+            self.halted = false;
+            self.interrupt(8)?;
         }
+        Ok(())
     }
-    pub fn execute_instruction(&mut self, instruction: Instruction) {
-        match instruction {
-            Instruction::Mov(src, dest) => self.execute_mov(src, dest),
-            Instruction::Add(src, dest) => self.execute_add(src, dest),
-            Instruction::Adc(_, _) => todo!(),
-            Instruction::Sbb(_, _) => todo!(),
-            Instruction::Sub(src, dest) => self.execute_sub(src, dest),
+    /// Whether a halted CPU will be woken by a timer interrupt
+    fn timer_can_wake(&self) -> bool {
+        self.timer_interrupt && self.timer_interval.is_some() && self.if_
+    }
+    pub fn execute_instruction(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        let ip = self.instruction_ip;
+        let shadowed = self.interrupt_shadow;
+        self.interrupt_shadow = false;
+        self.mnemonic = instruction.mnemonic();
+        match &instruction {
+            Instruction::Mov(src, dest) => self.execute_mov(src, dest)?,
+            Instruction::Add(src, dest) => self.execute_add(src, dest)?,
+            Instruction::Sub(src, dest) => self.execute_sub(src, dest)?,
             Instruction::Cmp(src, dest) => self.execute_cmp(src, dest),
-            Instruction::Jump(ty, offset) => self.execute_jump(ty, offset),
-            Instruction::Daa => todo!(),
-            Instruction::Aaa => todo!(),
-            Instruction::Inc(_, _) => todo!(),
-            Instruction::Dec(_, _) => todo!(),
-        }
-    }
-    fn get_location<'a>(&'a mut self, src: &Location, dest: &Location) -> (u16, &'a mut u16, Bits) {
-        let val = match src {
-            Location::Register(ref reg) => {
-                let (reg, w) = self.decode_register(reg);
-
-                match w {
-                    Bits::High => *reg >> 8,
-                    Bits::Low => *reg & 0xFF,
-                    Bits::All => *reg,
-                }
+            Instruction::Jump(ty, offset) => self.execute_jump(ty, *offset),
+            Instruction::JumpNear(offset) => self.ip = self.ip.wrapping_add(*offset as u16),
+            Instruction::Inc(dest, width, amount) => {
+                self.execute_inc_dec(dest, *width, amount.unwrap_or(1), false)?
             }
-            Location::Memory(Memory {
-                reg1,
-                reg2,
-                displacement,
-            }) => {
-                println!(
-                    "{} {} {}",
-                    displacement,
-                    self.memory[*displacement as usize],
-                    self.memory[*displacement as usize + 1]
+            Instruction::Dec(dest, width, amount) => {
+                self.execute_inc_dec(dest, *width, amount.unwrap_or(1), true)?
+            }
+            Instruction::Bound(index, bounds) => self.execute_bound(index, bounds)?,
+            Instruction::Lea(dest, address) => {
+                let before = self.read_register(dest);
+                let offset = self.effective_address(address);
+                self.write_register(dest, offset);
+                self.emit_operation(
+                    "lea",
+                    &Location::Register(dest.clone()),
+                    before,
+                    offset,
+                    false,
                 );
-                let mut out = *displacement as u16;
-                if let Some(reg1) = &reg1 {
-                    let (val, _) = self.decode_register(reg1);
-                    out += *val;
+            }
+            Instruction::Push(src) => self.execute_push(src),
+            Instruction::Pop(dest) => self.execute_pop(dest)?,
+            Instruction::JumpFar(pointer) => self.execute_jump_far(pointer, false),
+            Instruction::CallFar(pointer) => self.execute_jump_far(pointer, true),
+            Instruction::JumpFarDirect(segment, offset) => {
+                self.transfer_far(*segment, *offset, false)
+            }
+            Instruction::CallFarDirect(segment, offset) => {
+                self.transfer_far(*segment, *offset, true)
+            }
+            Instruction::RetFar(bytes) => {
+                self.ip = self.pop_word();
+                self.registers[11] = self.pop_word();
+                self.registers[4] = self.registers[4].wrapping_add(bytes.unwrap_or(0));
+            }
+            Instruction::Daa => self.execute_daa(),
+            Instruction::Aaa => self.execute_aaa(),
+            Instruction::Salc => self.write_register(&Register::AL, if self.cf { 0xFF } else { 0 }),
+            Instruction::Call(offset) => {
+                self.push_word(self.ip);
+                self.ip = self.ip.wrapping_add(*offset as u16);
+            }
+            Instruction::Ret(bytes) => {
+                self.ip = self.pop_word();
+                self.registers[4] = self.registers[4].wrapping_add(bytes.unwrap_or(0));
+            }
+            Instruction::Hlt => self.halted = true,
+            Instruction::Adc(src, dest) => self.execute_adc(src, dest)?,
+            Instruction::Sbb(src, dest) => self.execute_sbb(src, dest)?,
+            Instruction::And(src, dest) => self.execute_logic("and", src, dest, |a, b| a & b)?,
+            Instruction::Or(src, dest) => self.execute_logic("or", src, dest, |a, b| a | b)?,
+            Instruction::Xor(src, dest) => self.execute_logic("xor", src, dest, |a, b| a ^ b)?,
+            Instruction::Test(src, dest) => self.execute_logic("test", src, dest, |a, b| a & b)?,
+            Instruction::Xchg(src, dest) => self.execute_xchg(src, dest)?,
+            Instruction::Shift(ty, dest, width, count) => {
+                self.execute_shift(ty, dest, *width, count)?
+            }
+            Instruction::Not(dest, width) => self.execute_not(dest, *width)?,
+            Instruction::Neg(dest, width) => self.execute_neg(dest, *width)?,
+            Instruction::Mul(src, width) => self.execute_mul(src, *width, false),
+            Instruction::Imul(src, width) => self.execute_mul(src, *width, true),
+            Instruction::Div(src, width) => self.execute_div(src, *width, false)?,
+            Instruction::Idiv(src, width) => self.execute_div(src, *width, true)?,
+            Instruction::StringOp(ty, width, segment, repeat) => {
+                self.execute_repeated(ty, *width, segment, repeat, instruction.mnemonic())?
+            }
+            Instruction::Cld => self.df = false,
+            Instruction::Std => self.df = true,
+            Instruction::Clc => self.cf = false,
+            Instruction::Stc => self.cf = true,
+            Instruction::Cmc => self.cf = !self.cf,
+            Instruction::Cli => self.if_ = false,
+            Instruction::Sti => self.if_ = true,
+            Instruction::Pushf => self.push_word(self.flags_word()),
+            Instruction::Popf => {
+                let word = self.pop_word();
+                self.set_flags_word(word);
+            }
+            Instruction::Lahf => self.write_register(&Register::AH, self.flags_word() & 0xFF),
+            Instruction::Sahf => {
+                let ah = self.read_register(&Register::AH);
+                self.set_flags_word(self.flags_word() & 0xFF00 | ah);
+            }
+            Instruction::Cbw => {
+                let al = self.read_register(&Register::AL) as u8 as i8;
+                self.write_register(&Register::AX, al as i16 as u16);
+            }
+            Instruction::Cwd => {
+                let ax = self.read_register(&Register::AX) as i16;
+                self.write_register(&Register::DX, if ax < 0 { 0xFFFF } else { 0 });
+            }
+            Instruction::In(width, port) => {
+                let port = self.port_number(port);
+                let accumulator = Location::Register(Self::accumulator(*width));
+                let before = self.peek_location(&accumulator, *width);
+                let value = self.ports.read(port, *width == 16) & mask(*width);
+                self.write_location(&accumulator, *width, value)?;
+                self.emit_operation("in", &accumulator, before, value, false);
+            }
+            Instruction::Out(port, width) => {
+                let port = self.port_number(port);
+                let value = self.read_register(&Self::accumulator(*width));
+                self.ports.write(port, *width == 16, value);
+            }
+            Instruction::Int(vector) => self.interrupt(*vector)?,
+            Instruction::Int3 => self.interrupt(3)?,
+            Instruction::Into => {
+                if self.of {
+                    self.interrupt(4)?;
                 }
-                if let Some(reg2) = &reg2 {
-                    let (val, _) = self.decode_register(reg2);
-                    out += *val;
+            }
+            Instruction::Iret => {
+                self.ip = self.pop_word();
+                self.registers[11] = self.pop_word();
+                let flags = self.pop_word();
+                self.set_flags_word(flags);
+            }
+        }
+        if let Instruction::Mov(_, Location::Register(Register::SS))
+        | Instruction::Pop(Location::Register(Register::SS)) = &instruction
+        {
+            // Back-to-back SS writes don't extend the shadow past the next instruction
+            self.interrupt_shadow = !shadowed;
+        }
+        self.last_instruction = Some((ip, instruction));
+        Ok(())
+    }
+    /// Whether the instruction just executed loaded SS, in which case the 8086
+    /// holds off external interrupts until after the next one
+    pub fn interrupts_inhibited(&self) -> bool {
+        self.interrupt_shadow
+    }
+    /// Only code fetched from the instruction stream goes through the custom decoder
+    pub fn set_custom_decoder(&mut self, decode: CustomDecoder<T>) {
+        self.instructions.set_custom_decoder(decode);
+    }
+    pub fn set_tracer(&mut self, tracer: impl Tracer + 'static) {
+        self.tracer = Some(Box::new(tracer));
+    }
+    pub fn remove_tracer(&mut self) {
+        self.tracer = None;
+    }
+    /// Connect the peripherals `in` and `out` talk to
+    pub fn set_port_bus(&mut self, ports: impl PortBus + 'static) {
+        self.ports = Box::new(ports);
+    }
+    fn emit(&mut self, event: Event) {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.event(&event);
+        }
+    }
+    fn emit_wraparound(&mut self, mnemonic: &'static str, result: u16) {
+        let ip = self.instruction_ip;
+        self.emit(Event::Wraparound {
+            ip,
+            mnemonic,
+            result,
+        });
+    }
+    fn emit_operation(
+        &mut self,
+        mnemonic: &'static str,
+        dest: &Location,
+        before: u16,
+        after: u16,
+        sets_flags: bool,
+    ) {
+        if self.tracer.is_none() {
+            return;
+        }
+        let flags = sets_flags.then(|| self.flags());
+        self.emit(Event::Operation {
+            mnemonic,
+            dest: dest.clone(),
+            before,
+            after,
+            flags,
+        });
+    }
+    /// Zero every register, leaving flags, memory and the instruction pointer alone
+    pub fn clear_registers(&mut self) {
+        self.registers = [0; 12];
+    }
+    /// Clear every flag, leaving registers and memory alone
+    pub fn clear_flags(&mut self) {
+        self.cf = false;
+        self.sf = false;
+        self.zf = false;
+        self.pf = false;
+        self.af = false;
+        self.of = false;
+        self.df = false;
+        self.if_ = false;
+    }
+    pub fn state(&self) -> CpuState {
+        CpuState {
+            registers: self.registers,
+            flags: self.flags(),
+        }
+    }
+    fn port_number(&self, port: &Port) -> u16 {
+        match port {
+            Port::Immediate(port) => *port as u16,
+            Port::Dx => self.read_register(&Register::DX),
+        }
+    }
+    /// AL or AX
+    fn accumulator(width: u8) -> Register {
+        if width == 8 {
+            Register::AL
+        } else {
+            Register::AX
+        }
+    }
+    /// The flags packed as in the flags register, see `Flags::to_word`
+    fn flags_word(&self) -> u16 {
+        self.flags().to_word()
+    }
+    fn set_flags_word(&mut self, word: u16) {
+        let flags = Flags::from_word(word);
+        self.cf = flags.cf;
+        self.sf = flags.sf;
+        self.zf = flags.zf;
+        self.pf = flags.pf;
+        self.af = flags.af;
+        self.of = flags.of;
+        self.df = flags.df;
+        self.if_ = flags.if_;
+    }
+    pub fn flags(&self) -> Flags {
+        Flags {
+            cf: self.cf,
+            sf: self.sf,
+            zf: self.zf,
+            pf: self.pf,
+            af: self.af,
+            of: self.of,
+            df: self.df,
+            if_: self.if_,
+        }
+    }
+    /// Offset of the next instruction in the code segment
+    pub fn ip(&self) -> u16 {
+        self.ip
+    }
+    /// Value of any register, where the byte registers are their half of the word
+    pub fn register(&self, reg: Register) -> u16 {
+        self.read_register(&reg)
+    }
+    /// Set any register, a byte register only changing its half of the word
+    pub fn set_register(&mut self, reg: Register, value: u16) {
+        self.write_register(&reg, value);
+    }
+    /// Address and instruction of the last successfully executed instruction
+    pub fn last_instruction(&self) -> Option<&(u16, Instruction)> {
+        self.last_instruction.as_ref()
+    }
+    fn effective_address(&self, memory: &Memory) -> u16 {
+        let mut address = memory.displacement as u16;
+        if let Some(reg1) = &memory.reg1 {
+            address = address.wrapping_add(self.read_register(reg1));
+        }
+        if let Some(reg2) = &memory.reg2 {
+            address = address.wrapping_add(self.read_register(reg2));
+        }
+        address
+    }
+    /// Segment register value and offset a memory operand accesses
+    fn operand_address(&self, memory: &Memory) -> (u16, u16) {
+        (
+            self.read_register(&memory.segment()),
+            self.effective_address(memory),
+        )
+    }
+    fn read_byte(&self, segment: u16, offset: u16) -> u8 {
+        self.memory.as_ref()[physical_address(segment, offset) as usize]
+    }
+    /// Words are little-endian and may sit at odd offsets, a word at offset 0xFFFF
+    /// takes its high byte from offset 0x0000 of the same segment
+    fn read_word(&self, segment: u16, offset: u16) -> u16 {
+        u16::from_le_bytes([
+            self.read_byte(segment, offset),
+            self.read_byte(segment, offset.wrapping_add(1)),
+        ])
+    }
+    fn write_byte(&mut self, segment: u16, offset: u16, value: u8) {
+        self.memory.as_mut()[physical_address(segment, offset) as usize] = value;
+    }
+    fn write_word(&mut self, segment: u16, offset: u16, value: u16) {
+        let [low, high] = value.to_le_bytes();
+        self.write_byte(segment, offset, low);
+        self.write_byte(segment, offset.wrapping_add(1), high);
+    }
+    /// Like `read_word`, but reported to the tracer as a memory access
+    fn load_word(&mut self, segment: u16, offset: u16) -> u16 {
+        let value = self.read_word(segment, offset);
+        self.emit_access(segment, offset, 16, value, false);
+        value
+    }
+    fn store_word(&mut self, segment: u16, offset: u16, value: u16) {
+        self.write_word(segment, offset, value);
+        self.emit_access(segment, offset, 16, value, true);
+    }
+    fn emit_access(&mut self, segment: u16, offset: u16, width: u8, value: u16, write: bool) {
+        if self.tracer.is_none() {
+            return;
+        }
+        let address = physical_address(segment, offset);
+        self.emit(Event::MemoryAccess {
+            address,
+            width,
+            value,
+            write,
+        });
+        if self.report_misaligned && width == 16 && address % 2 == 1 {
+            let ip = self.instruction_ip;
+            self.emit(Event::MisalignedAccess { ip, address, write });
+        }
+    }
+    /// Emit `Event::MisalignedAccess` for word accesses at odd addresses, which
+    /// cost the 8086 an extra bus cycle
+    pub fn set_report_misaligned(&mut self, report: bool) {
+        self.report_misaligned = report;
+    }
+    /// Read an operand as the instruction does, reporting memory reads to the tracer
+    fn read_location(&mut self, location: &Location, width: u8) -> u16 {
+        let value = self.peek_location(location, width);
+        if let Location::Memory(memory) = location {
+            let (segment, offset) = self.operand_address(memory);
+            self.emit_access(segment, offset, width, value, false);
+        }
+        value
+    }
+    /// Read an operand without it counting as an access, e.g. for the value it had before
+    fn peek_location(&self, location: &Location, width: u8) -> u16 {
+        match location {
+            Location::Register(reg) => self.read_register(reg),
+            Location::Memory(memory) => {
+                let (segment, offset) = self.operand_address(memory);
+                if width == 8 {
+                    self.read_byte(segment, offset) as u16
+                } else {
+                    self.read_word(segment, offset)
                 }
-                u16::from_le_bytes([self.memory[out as usize], self.memory[out as usize + 1]])
             }
-            Location::Immediate(val) => val.data as u16,
-        };
-        let (mov_to, w) = match dest {
-            Location::Register(ref reg) => self.decode_register(reg),
-            Location::Memory(Memory {
-                reg1,
-                reg2,
-                displacement,
-            }) => {
-                let mut out = *displacement as u16;
-                if let Some(reg1) = &reg1 {
-                    let (val, _) = self.decode_register(reg1);
-                    out += *val;
+            Location::Immediate(val) => {
+                if width == 8 {
+                    val.data as u16 & 0xFF
+                } else {
+                    val.data as u16
                 }
-                if let Some(reg2) = &reg2 {
-                    let (val, _) = self.decode_register(reg2);
-                    out += *val;
+            }
+        }
+    }
+    /// Fails for an immediate, which only a hand-built or custom-decoded
+    /// instruction like `Mov(reg, imm)` can have as its destination
+    fn write_location(
+        &mut self,
+        location: &Location,
+        width: u8,
+        value: u16,
+    ) -> Result<(), CpuError> {
+        match location {
+            Location::Register(reg) => self.write_register(reg, value),
+            Location::Memory(memory) => {
+                let (segment, offset) = self.operand_address(memory);
+                if width == 8 {
+                    self.write_byte(segment, offset, value as u8);
+                } else {
+                    self.write_word(segment, offset, value);
                 }
-                (
-                    unsafe { std::mem::transmute(&mut self.memory[out as usize]) },
-                    Bits::All,
-                )
+                self.emit_access(segment, offset, width, value, true);
+            }
+            Location::Immediate(_) => {
+                return Err(CpuError::Unimplemented {
+                    opcode: self.opcode,
+                    mnemonic: self.mnemonic,
+                    ip: self.instruction_ip,
+                })
+            }
+        }
+        Ok(())
+    }
+    /// Registers decide the width of an operation, then an explicitly sized immediate
+    fn operand_width(src: &Location, dest: &Location) -> u8 {
+        match (dest, src) {
+            (Location::Register(reg), _) | (_, Location::Register(reg)) => reg.width(),
+            (_, Location::Immediate(Immediate { w: Some(0), .. })) => 8,
+            _ => 16,
+        }
+    }
+    fn execute_mov(&mut self, src: &Location, dest: &Location) -> Result<(), CpuError> {
+        let width = Self::operand_width(src, dest);
+        let val = self.read_location(src, width);
+
+        let before = self.peek_location(dest, width);
+        self.write_location(dest, width, val)?;
+        self.emit_operation("mov", dest, before, val, false);
+        Ok(())
+    }
+    fn execute_add(&mut self, src: &Location, dest: &Location) -> Result<(), CpuError> {
+        let width = Self::operand_width(src, dest);
+        let val = self.read_location(src, width);
+        let to = self.read_location(dest, width);
+        let result = to.wrapping_add(val) & mask(width);
+        self.write_location(dest, width, result)?;
+        let carried = to as u32 + val as u32 > mask(width) as u32;
+        if carried {
+            self.emit_wraparound("add", result);
+        }
+
+        self.cf = carried;
+        self.of = Self::addition_overflow(to, val, result, width);
+        self.af = Self::auxiliary_carry(to, val, result);
+        self.set_flags(result, width);
+        self.emit_operation("add", dest, to, result, true);
+        Ok(())
+    }
+    fn execute_adc(&mut self, src: &Location, dest: &Location) -> Result<(), CpuError> {
+        let width = Self::operand_width(src, dest);
+        let val = self.read_location(src, width);
+        let to = self.read_location(dest, width);
+        let sum = to as u32 + val as u32 + self.cf as u32;
+        let result = sum as u16 & mask(width);
+        self.write_location(dest, width, result)?;
+        let carried = sum > mask(width) as u32;
+        if carried {
+            self.emit_wraparound("adc", result);
+        }
+
+        self.cf = carried;
+        self.of = Self::addition_overflow(to, val, result, width);
+        self.af = Self::auxiliary_carry(to, val, result);
+        self.set_flags(result, width);
+        self.emit_operation("adc", dest, to, result, true);
+        Ok(())
+    }
+    fn execute_sub(&mut self, src: &Location, dest: &Location) -> Result<(), CpuError> {
+        let width = Self::operand_width(src, dest);
+        let val = self.read_location(src, width);
+        let to = self.read_location(dest, width);
+        let (result, borrowed) = to.overflowing_sub(val);
+        let result = result & mask(width);
+
+        self.write_location(dest, width, result)?;
+        if borrowed {
+            self.emit_wraparound("sub", result);
+        }
+        self.cf = borrowed;
+        self.of = Self::subtraction_overflow(to, val, result, width);
+        self.af = Self::auxiliary_carry(to, val, result);
+        self.set_flags(result, width);
+        self.emit_operation("sub", dest, to, result, true);
+        Ok(())
+    }
+    fn execute_sbb(&mut self, src: &Location, dest: &Location) -> Result<(), CpuError> {
+        let width = Self::operand_width(src, dest);
+        let val = self.read_location(src, width);
+        let to = self.read_location(dest, width);
+        let subtrahend = val as u32 + self.cf as u32;
+        let result = (to as u32).wrapping_sub(subtrahend) as u16 & mask(width);
+        let borrowed = subtrahend > to as u32;
+
+        self.write_location(dest, width, result)?;
+        if borrowed {
+            self.emit_wraparound("sbb", result);
+        }
+        self.cf = borrowed;
+        self.of = Self::subtraction_overflow(to, val, result, width);
+        self.af = Self::auxiliary_carry(to, val, result);
+        self.set_flags(result, width);
+        self.emit_operation("sbb", dest, to, result, true);
+        Ok(())
+    }
+    fn execute_cmp(&mut self, src: &Location, dest: &Location) {
+        let width = Self::operand_width(src, dest);
+        self.compare("cmp", src, dest, width);
+    }
+    /// Set the flags from `dest - src` without storing the result
+    fn compare(&mut self, mnemonic: &'static str, src: &Location, dest: &Location, width: u8) {
+        let val = self.read_location(src, width);
+        let to = self.read_location(dest, width);
+        let (result, borrowed) = to.overflowing_sub(val);
+        let result = result & mask(width);
+        self.cf = borrowed;
+        self.of = Self::subtraction_overflow(to, val, result, width);
+        self.af = Self::auxiliary_carry(to, val, result);
+        self.set_flags(result, width);
+        if self.tracer.is_some() {
+            self.emit(Event::Compare {
+                mnemonic,
+                left: dest.clone(),
+                right: src.clone(),
+                left_value: to,
+                right_value: val,
+                width,
+                result,
+                flags: self.flags(),
+            });
+        }
+    }
+    fn execute_xchg(&mut self, src: &Location, dest: &Location) -> Result<(), CpuError> {
+        let width = Self::operand_width(src, dest);
+        let a = self.read_location(src, width);
+        let b = self.read_location(dest, width);
+        self.write_location(src, width, b)?;
+        self.write_location(dest, width, a)?;
+        self.emit_operation("xchg", dest, b, a, false);
+        self.emit_operation("xchg", src, a, b, false);
+        Ok(())
+    }
+    /// Bitwise operations never carry or overflow. `test` only keeps the flags.
+    fn execute_logic(
+        &mut self,
+        mnemonic: &'static str,
+        src: &Location,
+        dest: &Location,
+        op: fn(u16, u16) -> u16,
+    ) -> Result<(), CpuError> {
+        let width = Self::operand_width(src, dest);
+        let val = self.read_location(src, width);
+        let to = self.read_location(dest, width);
+        let result = op(to, val) & mask(width);
+        if mnemonic != "test" {
+            self.write_location(dest, width, result)?;
+        }
+        self.cf = false;
+        self.of = false;
+        self.set_flags(result, width);
+        self.emit_operation(mnemonic, dest, to, result, true);
+        Ok(())
+    }
+    /// Run a string instruction once, or with a repeat prefix until CX runs out,
+    /// checking CX before each iteration and ZF after each comparing one
+    fn execute_repeated(
+        &mut self,
+        ty: &StringType,
+        width: u8,
+        segment: &Option<Register>,
+        repeat: &Option<Repeat>,
+        mnemonic: &'static str,
+    ) -> Result<(), CpuError> {
+        let Some(repeat) = repeat else {
+            return self.execute_string(ty, width, segment, mnemonic);
+        };
+        while self.read_register(&Register::CX) != 0 {
+            self.execute_string(ty, width, segment, mnemonic)?;
+            let cx = self.read_register(&Register::CX).wrapping_sub(1);
+            self.write_register(&Register::CX, cx);
+            if ty.compares() && self.zf != matches!(repeat, Repeat::Rep) {
+                break;
+            }
+        }
+        Ok(())
+    }
+    /// Source at DS:SI, or the override, and destination at ES:DI. Both pointers
+    /// step by the operand size, down when the direction flag is set.
+    fn execute_string(
+        &mut self,
+        ty: &StringType,
+        width: u8,
+        segment: &Option<Register>,
+        mnemonic: &'static str,
+    ) -> Result<(), CpuError> {
+        let size = width as u16 / 8;
+        let step = if self.df { size.wrapping_neg() } else { size };
+        let src = Location::Memory(Memory::with_segment(
+            Some(Register::SI),
+            None,
+            0,
+            segment.clone(),
+        ));
+        let dest = Location::Memory(Memory::with_segment(
+            Some(Register::DI),
+            None,
+            0,
+            Some(Register::ES),
+        ));
+        let accumulator = Location::Register(Self::accumulator(width));
+        match ty {
+            StringType::Movs | StringType::Stos => {
+                let from = if matches!(ty, StringType::Movs) {
+                    &src
+                } else {
+                    &accumulator
+                };
+                let value = self.read_location(from, width);
+                let before = self.peek_location(&dest, width);
+                self.write_location(&dest, width, value)?;
+                self.emit_operation(mnemonic, &dest, before, value, false);
+            }
+            // Flags from `[si] - [di]` and `al - [di]`
+            StringType::Cmps => self.compare(mnemonic, &dest, &src, width),
+            StringType::Scas => self.compare(mnemonic, &dest, &accumulator, width),
+        }
+        if matches!(ty, StringType::Movs | StringType::Cmps) {
+            let si = self.read_register(&Register::SI);
+            self.write_register(&Register::SI, si.wrapping_add(step));
+        }
+        let di = self.read_register(&Register::DI);
+        self.write_register(&Register::DI, di.wrapping_add(step));
+        Ok(())
+    }
+    /// CF gets the last bit shifted or rotated out. OF is only meaningful for a count
+    /// of 1, where it tells whether the sign changed. Rotates leave SF, ZF and PF alone.
+    fn execute_shift(
+        &mut self,
+        ty: &ShiftType,
+        dest: &Location,
+        width: u8,
+        count: &ShiftCount,
+    ) -> Result<(), CpuError> {
+        let count = match count {
+            ShiftCount::One => 1,
+            ShiftCount::Cl => self.read_register(&Register::CL),
+        };
+        // The 186 only looks at the low 5 bits of the count
+        #[cfg(feature = "target_186")]
+        let count = count & 0x1F;
+        let before = self.read_location(dest, width);
+        if count == 0 {
+            return Ok(());
+        }
+        let sign = 1 << (width - 1);
+        let mut result = before;
+        let mut cf = self.cf;
+        for _ in 0..count {
+            let (msb, lsb) = (result & sign != 0, result & 1 != 0);
+            result = match ty {
+                ShiftType::Rol => result << 1 | msb as u16,
+                ShiftType::Ror => result >> 1 | if lsb { sign } else { 0 },
+                ShiftType::Rcl => result << 1 | cf as u16,
+                ShiftType::Rcr => result >> 1 | if cf { sign } else { 0 },
+                ShiftType::Shl => result << 1,
+                ShiftType::Shr => result >> 1,
+                ShiftType::Sar => result >> 1 | result & sign,
+            } & mask(width);
+            cf = match ty {
+                ShiftType::Rol | ShiftType::Rcl | ShiftType::Shl => msb,
+                _ => lsb,
+            };
+        }
+        self.write_location(dest, width, result)?;
+        let msb = result & sign != 0;
+        let next = result & (sign >> 1) != 0;
+        self.cf = cf;
+        self.of = match ty {
+            ShiftType::Rol | ShiftType::Rcl | ShiftType::Shl => msb != cf,
+            ShiftType::Ror | ShiftType::Rcr => msb != next,
+            // The sign bit shifted into the one below it
+            ShiftType::Shr => next,
+            ShiftType::Sar => false,
+        };
+        let rotate = matches!(
+            ty,
+            ShiftType::Rol | ShiftType::Ror | ShiftType::Rcl | ShiftType::Rcr
+        );
+        if !rotate {
+            self.set_flags(result, width);
+        }
+        self.emit_operation(ty.mnemonic(), dest, before, result, true);
+        Ok(())
+    }
+    fn execute_not(&mut self, dest: &Location, width: u8) -> Result<(), CpuError> {
+        let before = self.read_location(dest, width);
+        let result = !before & mask(width);
+        self.write_location(dest, width, result)?;
+        self.emit_operation("not", dest, before, result, false);
+        Ok(())
+    }
+    /// Subtract the operand from zero, which borrows unless it is zero
+    fn execute_neg(&mut self, dest: &Location, width: u8) -> Result<(), CpuError> {
+        let before = self.read_location(dest, width);
+        let result = before.wrapping_neg() & mask(width);
+        self.write_location(dest, width, result)?;
+        self.cf = before != 0;
+        self.of = Self::subtraction_overflow(0, before, result, width);
+        self.af = Self::auxiliary_carry(0, before, result);
+        self.set_flags(result, width);
+        self.emit_operation("neg", dest, before, result, true);
+        Ok(())
+    }
+    /// AL * operand into AX, or AX * operand into DX:AX. CF and OF tell whether the upper
+    /// half is needed, i.e. isn't just zero or, for `imul`, the sign of the lower half.
+    fn execute_mul(&mut self, src: &Location, width: u8, signed: bool) {
+        let mnemonic = if signed { "imul" } else { "mul" };
+        let val = self.read_location(src, width) as u32;
+        let before_ax = self.registers[0];
+        let before_dx = self.registers[3];
+        let extend = |value: u32| -> u32 {
+            match (signed, width) {
+                (false, _) => value,
+                (true, 8) => value as u8 as i8 as u32,
+                (true, _) => value as u16 as i16 as u32,
             }
-            Location::Immediate(_) => unimplemented!(),
         };
-        (val, mov_to, w)
-    }
-    fn execute_mov(&mut self, src: Location, dest: Location) {
-        let (val, mov_to, w) = self.get_location(&src, &dest);
-        let val_to_mov = match w {
-            Bits::High => (val << 8) | (*mov_to & 0xFF),
-            Bits::Low => (val & 0xFF) | (*mov_to & 0xFF00),
-            Bits::All => val,
+        let acc = (before_ax & mask(width)) as u32;
+        let product = extend(acc).wrapping_mul(extend(val));
+        let upper_needed = if width == 8 {
+            product as u16 != extend(product & 0xFF) as u16
+        } else {
+            product != extend(product & 0xFFFF)
         };
-
-        println!("mov {}: {:#06x}->{:#06x}", dest, *mov_to, val_to_mov);
-        *mov_to = val_to_mov;
+        self.registers[0] = product as u16;
+        if width == 16 {
+            self.registers[3] = (product >> 16) as u16;
+        }
+        self.cf = upper_needed;
+        self.of = upper_needed;
+        self.emit_operation(
+            mnemonic,
+            &Location::Register(Register::AX),
+            before_ax,
+            self.registers[0],
+            true,
+        );
+        if width == 16 {
+            self.emit_operation(
+                mnemonic,
+                &Location::Register(Register::DX),
+                before_dx,
+                self.registers[3],
+                true,
+            );
+        }
     }
-    fn execute_add(&mut self, src: Location, dest: Location) {
-        let (val, to, w) = self.get_location(&src, &dest);
-        let result = match w {
-            Bits::High => (val << 8) + (*to & 0xFF),
-            _ => val + *to,
+    /// AX by a byte into AL and AH, or DX:AX by a word into AX and DX. Dividing by zero
+    /// or getting a quotient too large for its half raises interrupt 0. Like the 8086
+    /// manual says, a signed quotient of -128 or -32768 is also too large.
+    fn execute_div(&mut self, src: &Location, width: u8, signed: bool) -> Result<(), CpuError> {
+        let mnemonic = if signed { "idiv" } else { "div" };
+        let divisor = self.read_location(src, width) as i64;
+        let before_ax = self.registers[0];
+        let before_dx = self.registers[3];
+        let dividend = if width == 8 {
+            before_ax as u32
+        } else {
+            (before_dx as u32) << 16 | before_ax as u32
         };
-        *to = result;
-        print!("add {}: {:#06x}->{:#06x} ", dest, *to, result);
-
-        self.of = result < *to;
-        self.set_flags(result);
-        self.print_flags();
-    }
-    fn execute_sub(&mut self, src: Location, dest: Location) {
-        let (val, to, w) = self.get_location(&src, &dest);
-        let (result, overflowed) = match w {
-            Bits::High => to.overflowing_sub(val << 8),
-            _ => to.overflowing_sub(val),
+        let (dividend, divisor, limit) = match (signed, width) {
+            (false, _) => (dividend as i64, divisor, mask(width) as i64),
+            (true, 8) => (
+                dividend as u16 as i16 as i64,
+                divisor as u8 as i8 as i64,
+                0x7F,
+            ),
+            (true, _) => (dividend as i32 as i64, divisor as u16 as i16 as i64, 0x7FFF),
         };
-
-        print!("sub {}: {:#06x}->{:#06x} ", dest, *to, result);
-        *to = result;
-        self.of = overflowed;
-        self.set_flags(result);
-        self.print_flags();
-    }
-    fn execute_cmp(&mut self, src: Location, dest: Location) {
-        let (val, to, w) = self.get_location(&src, &dest);
-        let (result, overflowed) = match w {
-            Bits::High => to.overflowing_sub(val << 8),
-            _ => to.overflowing_sub(val),
+        if divisor == 0 {
+            return self.interrupt(0);
+        }
+        let (quotient, remainder) = (dividend / divisor, dividend % divisor);
+        if quotient.abs() > limit {
+            return self.interrupt(0);
+        }
+        let (quotient, remainder) = (
+            quotient as u16 & mask(width),
+            remainder as u16 & mask(width),
+        );
+        if width == 8 {
+            self.registers[0] = remainder << 8 | quotient;
+        } else {
+            self.registers[0] = quotient;
+            self.registers[3] = remainder;
+        }
+        self.emit_operation(
+            mnemonic,
+            &Location::Register(Register::AX),
+            before_ax,
+            self.registers[0],
+            false,
+        );
+        if width == 16 {
+            self.emit_operation(
+                mnemonic,
+                &Location::Register(Register::DX),
+                before_dx,
+                self.registers[3],
+                false,
+            );
+        }
+        Ok(())
+    }
+    /// Carry is deliberately left alone, only OF reports crossing the signed boundary
+    fn execute_inc_dec(
+        &mut self,
+        dest: &Location,
+        width: u8,
+        amount: u8,
+        decrement: bool,
+    ) -> Result<(), CpuError> {
+        let sign = 1 << (width - 1);
+        let before = self.read_location(dest, width);
+        let amount = amount as u16;
+        let (result, wrapped, mnemonic) = if decrement {
+            (before.wrapping_sub(amount), amount > before, "dec")
+        } else {
+            (
+                before.wrapping_add(amount),
+                before as u32 + amount as u32 > mask(width) as u32,
+                "inc",
+            )
+        };
+        let result = result & mask(width);
+        self.write_location(dest, width, result)?;
+        if wrapped {
+            self.emit_wraparound(mnemonic, result);
+        }
+        self.of = if decrement {
+            before & sign != 0 && result & sign == 0
+        } else {
+            before & sign == 0 && result & sign != 0
         };
-        print!("cmp {}: {:#06x}->{:#06x} ", dest, *to, result);
-        self.of = overflowed;
-        self.set_flags(result);
-        self.print_flags();
+        self.af = Self::auxiliary_carry(before, amount, result);
+        self.set_flags(result, width);
+        self.emit_operation(mnemonic, dest, before, result, true);
+        Ok(())
+    }
+    /// Turn AL back into two packed BCD digits after adding two of them, using AF and
+    /// CF to spot digits that carried. The 8086 manual checks AL > 0x9F after the low
+    /// digit is adjusted.
+    fn execute_daa(&mut self) {
+        let before = self.read_register(&Register::AL) as u8;
+        let mut al = before;
+        let mut cf = self.cf;
+        if al & 0x0F > 9 || self.af {
+            let (adjusted, carried) = al.overflowing_add(6);
+            al = adjusted;
+            cf |= carried;
+            self.af = true;
+        } else {
+            self.af = false;
+        }
+        if al > 0x9F || cf {
+            al = al.wrapping_add(0x60);
+            cf = true;
+        }
+        self.cf = cf;
+        self.write_register(&Register::AL, al as u16);
+        self.set_flags(al as u16, 8);
+        self.emit_operation(
+            "daa",
+            &Location::Register(Register::AL),
+            before as u16,
+            al as u16,
+            true,
+        );
+    }
+    /// Turn AL into a single unpacked BCD digit after an addition, carrying into AH
+    fn execute_aaa(&mut self) {
+        let before = self.registers[0];
+        let [mut al, mut ah] = before.to_le_bytes();
+        let adjust = al & 0x0F > 9 || self.af;
+        if adjust {
+            al = al.wrapping_add(6);
+            ah = ah.wrapping_add(1);
+        }
+        self.af = adjust;
+        self.cf = adjust;
+        let after = u16::from_le_bytes([al & 0x0F, ah]);
+        self.registers[0] = after;
+        self.emit_operation(
+            "aaa",
+            &Location::Register(Register::AX),
+            before,
+            after,
+            true,
+        );
+    }
+    /// `push sp` stores SP as it is after the decrement, as the 8086 does
+    fn execute_push(&mut self, src: &Location) {
+        let sp = self.registers[4].wrapping_sub(2);
+        self.registers[4] = sp;
+        let val = self.read_location(src, 16);
+        self.store_word(self.registers[8], sp, val);
+        self.emit_operation(
+            "push",
+            &Location::Register(Register::SP),
+            sp.wrapping_add(2),
+            sp,
+            false,
+        );
+    }
+    /// The value is read before SP is incremented, so `pop sp` leaves SP
+    /// equal to the popped word rather than the word plus two
+    fn execute_pop(&mut self, dest: &Location) -> Result<(), CpuError> {
+        let val = self.pop_word();
+        let before = self.peek_location(dest, 16);
+        self.write_location(dest, 16, val)?;
+        self.emit_operation("pop", dest, before, val, false);
+        Ok(())
+    }
+    /// The pointer holds the new IP followed by the new CS
+    fn execute_jump_far(&mut self, pointer: &Memory, call: bool) {
+        let (segment, offset) = self.operand_address(pointer);
+        let ip = self.load_word(segment, offset);
+        let cs = self.load_word(segment, offset.wrapping_add(2));
+        self.transfer_far(cs, ip, call);
+    }
+    /// Continue at `cs:ip`, for a call first pushing CS and IP to return to
+    fn transfer_far(&mut self, cs: u16, ip: u16, call: bool) {
+        if call {
+            self.push_word(self.registers[11]);
+            self.push_word(self.ip);
+        }
+        self.registers[11] = cs;
+        self.ip = ip;
     }
-    fn set_flags(&mut self, result: u16) {
+    fn push_word(&mut self, value: u16) {
+        let sp = self.registers[4].wrapping_sub(2);
+        self.registers[4] = sp;
+        self.store_word(self.registers[8], sp, value);
+    }
+    fn pop_word(&mut self) -> u16 {
+        let sp = self.registers[4];
+        let value = self.load_word(self.registers[8], sp);
+        self.registers[4] = sp.wrapping_add(2);
+        value
+    }
+    fn execute_bound(&mut self, index: &Register, bounds: &Memory) -> Result<(), CpuError> {
+        let index = self.read_register(index) as i16;
+        let (segment, offset) = self.operand_address(bounds);
+        let lower = self.load_word(segment, offset) as i16;
+        let upper = self.load_word(segment, offset.wrapping_add(2)) as i16;
+        if index < lower || index > upper {
+            // Unlike a divide error, the handler returns to the `bound` itself
+            self.ip = self.instruction_ip;
+            return self.interrupt(5);
+        }
+        Ok(())
+    }
+    /// Push the flags, CS and IP, clear IF and continue at the handler in the vector
+    /// table at 0000:0000. The trap flag isn't modelled, so there is none to clear.
+    fn interrupt(&mut self, vector: u8) -> Result<(), CpuError> {
+        let entry = vector as u16 * 4;
+        let ip = self.load_word(0, entry);
+        let cs = self.load_word(0, entry + 2);
+        if ip == 0 && cs == 0 {
+            return Err(CpuError::UnhandledInterrupt {
+                vector,
+                ip: self.instruction_ip,
+            });
+        }
+        self.push_word(self.flags_word());
+        self.if_ = false;
+        self.push_word(self.registers[11]);
+        self.push_word(self.ip);
+        self.registers[11] = cs;
+        self.ip = ip;
+        Ok(())
+    }
+    /// Whether `a + b` gave `result` with the sign no signed addition could produce:
+    /// the operands share a sign and the result's sign differs from it
+    fn addition_overflow(a: u16, b: u16, result: u16, width: u8) -> bool {
+        let sign = 1 << (width - 1);
+        (a ^ result) & (b ^ result) & sign != 0
+    }
+    /// Whether `a - b` gave `result` with the sign no signed subtraction could produce:
+    /// the operands differ in sign and the result's sign isn't `a`'s
+    fn subtraction_overflow(a: u16, b: u16, result: u16, width: u8) -> bool {
+        let sign = 1 << (width - 1);
+        (a ^ b) & (a ^ result) & sign != 0
+    }
+    /// Whether adding or subtracting `a` and `b` into `result` carried across bit 3
+    fn auxiliary_carry(a: u16, b: u16, result: u16) -> bool {
+        (a ^ b ^ result) & 0x10 != 0
+    }
+    /// ZF, SF and PF from a `width`-bit result, SF being its top bit
+    fn set_flags(&mut self, result: u16, width: u8) {
         self.zf = result == 0;
-        self.sf = (result & 0x8000) > 0;
-        self.pf = result.count_ones() % 2 == 0;
+        self.sf = result & (1 << (width - 1)) != 0;
+        self.pf = parity(result as u8);
     }
-    fn decode_register(&mut self, reg: &Register) -> (&mut u16, Bits) {
+    /// Index into `registers` and, for byte registers, the shift of the byte within it
+    fn decode_register(reg: &Register) -> (usize, u16) {
         match *reg {
-            Register::AL => (&mut self.registers[0], Bits::Low),
-            Register::CL => (&mut self.registers[2], Bits::Low),
-            Register::DL => (&mut self.registers[3], Bits::Low),
-            Register::BL => (&mut self.registers[1], Bits::Low),
-            Register::AH => (&mut self.registers[0], Bits::High),
-            Register::CH => (&mut self.registers[2], Bits::High),
-            Register::DH => (&mut self.registers[3], Bits::High),
-            Register::BH => (&mut self.registers[1], Bits::High),
-            Register::AX => (&mut self.registers[0], Bits::All),
-            Register::CX => (&mut self.registers[2], Bits::All),
-            Register::DX => (&mut self.registers[3], Bits::All),
-            Register::BX => (&mut self.registers[1], Bits::All),
-            Register::SP => (&mut self.registers[4], Bits::All),
-            Register::BP => (&mut self.registers[5], Bits::All),
-            Register::SI => (&mut self.registers[6], Bits::All),
-            Register::DI => (&mut self.registers[7], Bits::All),
-            Register::SS => (&mut self.registers[8], Bits::All),
-            Register::DS => (&mut self.registers[9], Bits::All),
-            Register::ES => (&mut self.registers[10], Bits::All),
+            Register::AL => (0, 0),
+            Register::CL => (2, 0),
+            Register::DL => (3, 0),
+            Register::BL => (1, 0),
+            Register::AH => (0, 8),
+            Register::CH => (2, 8),
+            Register::DH => (3, 8),
+            Register::BH => (1, 8),
+            Register::AX => (0, 0),
+            Register::CX => (2, 0),
+            Register::DX => (3, 0),
+            Register::BX => (1, 0),
+            Register::SP => (4, 0),
+            Register::BP => (5, 0),
+            Register::SI => (6, 0),
+            Register::DI => (7, 0),
+            Register::SS => (8, 0),
+            Register::DS => (9, 0),
+            Register::ES => (10, 0),
+            Register::CS => (11, 0),
+        }
+    }
+    fn read_register(&self, reg: &Register) -> u16 {
+        let (index, shift) = Self::decode_register(reg);
+        if reg.width() == 8 {
+            (self.registers[index] >> shift) & 0xFF
+        } else {
+            self.registers[index]
+        }
+    }
+    fn write_register(&mut self, reg: &Register, value: u16) {
+        let (index, shift) = Self::decode_register(reg);
+        if reg.width() == 8 {
+            let kept = self.registers[index] & !(0xFF << shift);
+            self.registers[index] = kept | ((value & 0xFF) << shift);
+        } else {
+            self.registers[index] = value;
         }
     }
     pub fn print_registers(&self) {
-        println!("ax: {:#04x} ({})", self.registers[0], self.registers[0]);
-        println!("bx: {:#04x} ({})", self.registers[1], self.registers[1]);
-        println!("cx: {:#04x} ({})", self.registers[2], self.registers[2]);
-        println!("dx: {:#04x} ({})", self.registers[3], self.registers[3]);
-        println!("sp: {:#04x} ({})", self.registers[4], self.registers[4]);
-        println!("bp: {:#04x} ({})", self.registers[5], self.registers[5]);
-        println!("si: {:#04x} ({})", self.registers[6], self.registers[6]);
-        println!("di: {:#04x} ({})", self.registers[7], self.registers[7]);
-        println!("ss: {:#04x} ({})", self.registers[7], self.registers[8]);
-        println!("ds: {:#04x} ({})", self.registers[7], self.registers[9]);
-        println!("es: {:#04x} ({})", self.registers[7], self.registers[10]);
-        self.print_flags();
+        self.write_registers(&mut io::stdout())
+            .expect("failed printing to stdout");
     }
-    pub fn print_flags(&self) {
-        print!("flags: ");
-        if self.zf {
-            print!("Z");
+    /// What `print_registers` prints, one `name: value` line per register, IP and flags
+    pub fn write_registers(&self, out: &mut impl Write) -> Result<(), io::Error> {
+        for (name, value) in REGISTER_NAMES.iter().zip(self.registers) {
+            writeln!(out, "{}: {:#04x} ({})", name, value, value)?;
         }
-        if self.sf {
-            print!("S");
+        writeln!(out, "ip: {:#04x} ({})", self.ip, self.ip)?;
+        writeln!(out, "flags: {}", self.flags())
+    }
+    pub fn print_flags(&self) {
+        println!("flags: {}", self.flags());
+    }
+    /// Every register and flag on one line for diffing runs, e.g.
+    /// `AX=0005 BX=0000 .. CS=0000 IP=0003 FLAGS=-P--S---`. Flags keep a fixed position
+    /// in the order `CPAZSIDO`, with `-` for clear ones.
+    pub fn registers_line(&self) -> String {
+        let mut line = String::new();
+        for (name, value) in REGISTER_NAMES.iter().zip(self.registers) {
+            line += &format!("{}={:04X} ", name.to_uppercase(), value);
         }
-        println!();
+        let letters: String = self
+            .flags()
+            .letters()
+            .iter()
+            .map(|&(set, letter)| if set { letter } else { '-' })
+            .collect();
+        line + &format!("IP={:04X} FLAGS={}", self.ip, letters)
     }
 
-    fn execute_jump(&mut self, ty: JumpType, offset: i8) {
-        let should_jump = match ty {
+    /// Whether a jump of type `ty` would be taken with the current flags and CX. The
+    /// `loop` family judges CX as it will be after their decrement.
+    pub fn jump_would_be_taken(&self, ty: &JumpType) -> bool {
+        let cf = self.cf;
+        let cx = self.registers[2];
+        match ty {
             JumpType::Je => self.zf,
-            JumpType::Jl => todo!(),
-            JumpType::Jle => todo!(),
-            JumpType::Jb => self.of,
-            JumpType::Jbe => todo!(),
-            JumpType::Jp => self.pf,
-            JumpType::Jo => todo!(),
-            JumpType::Js => todo!(),
             JumpType::Jne => !self.zf,
-            JumpType::Jnl => todo!(),
-            JumpType::Jnle => todo!(),
-            JumpType::Jnb => todo!(),
-            JumpType::Jnbe => todo!(),
-            JumpType::Jnp => todo!(),
-            JumpType::Jno => todo!(),
-            JumpType::Jns => todo!(),
-            JumpType::Loop => {
-                self.registers[2] = self.registers[2].overflowing_sub(1).0;
-                self.zf = self.registers[2] == 0;
-                self.zf
-            }
-            JumpType::Jnloopzs => todo!(),
-            JumpType::Loopnz => {
-                self.registers[2] = self.registers[2].overflowing_sub(1).0;
-                self.zf = self.registers[2] == 0;
-                !self.zf
-            }
-            JumpType::Jcxz => todo!(),
-        };
+            JumpType::Jl => self.sf != self.of,
+            JumpType::Jnl => self.sf == self.of,
+            JumpType::Jle => self.zf || self.sf != self.of,
+            JumpType::Jnle => !self.zf && self.sf == self.of,
+            JumpType::Jb => cf,
+            JumpType::Jnb => !cf,
+            JumpType::Jbe => cf || self.zf,
+            JumpType::Jnbe => !cf && !self.zf,
+            JumpType::Jp => self.pf,
+            JumpType::Jnp => !self.pf,
+            JumpType::Jo => self.of,
+            JumpType::Jno => !self.of,
+            JumpType::Js => self.sf,
+            JumpType::Jns => !self.sf,
+            JumpType::Loop => cx != 1,
+            JumpType::Jnloopzs => cx != 1 && self.zf,
+            JumpType::Loopnz => cx != 1 && !self.zf,
+            JumpType::Jcxz => cx == 0,
+            JumpType::Jmp => true,
+        }
+    }
+    /// The loops decrement CX without touching any flags, so whether they jump is
+    /// decided the same way as for a jump that is only being looked at
+    fn execute_jump(&mut self, ty: &JumpType, offset: i8) {
+        let should_jump = self.jump_would_be_taken(ty);
+        if let JumpType::Loop | JumpType::Jnloopzs | JumpType::Loopnz = ty {
+            self.registers[2] = self.registers[2].wrapping_sub(1);
+        }
         if should_jump {
-            self.instructions.jump(offset);
+            self.ip = self.ip.wrapping_add(offset as i16 as u16);
         }
     }
     pub fn dump_memory(&self, out: &mut impl Write) -> Result<(), io::Error> {
-        out.write_all(&self.memory[..])
+        out.write_all(self.memory())
+    }
+    /// The whole address space, e.g. for rendering it between steps
+    pub fn memory(&self) -> &[u8] {
+        &self.memory.as_ref()[..MEMORY_SIZE]
+    }
+    /// `len` bytes at offset `addr` of segment 0, wrapping around within the segment
+    /// like `write_mem`. The bytes are copied out since a range running past 0xFFFF
+    /// continues at offset 0 and isn't contiguous in memory; see `memory` for a slice.
+    pub fn read_mem(&self, addr: u16, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|offset| self.read_byte(0, addr.wrapping_add(offset as u16)))
+            .collect()
+    }
+    /// Copy `data` to offset `addr` of segment 0, wrapping around within the segment
+    pub fn write_mem(&mut self, addr: u16, data: &[u8]) {
+        for (offset, byte) in data.iter().enumerate() {
+            self.write_byte(0, addr.wrapping_add(offset as u16), *byte);
+        }
+    }
+    /// `read_mem` as 16 bytes per line of offset, hex and ASCII, e.g.
+    /// `0100  48 69 21 00 ...  Hi!.` with `.` for unprintable bytes
+    pub fn hexdump(&self, start: u16, len: usize) -> String {
+        let mut out = String::new();
+        for (line, bytes) in self.read_mem(start, len).chunks(16).enumerate() {
+            let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            let ascii: String = bytes
+                .iter()
+                .map(|&byte| {
+                    if byte.is_ascii_graphic() || byte == b' ' {
+                        byte as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            out += &format!(
+                "{:04X}  {:<49}{}\n",
+                start.wrapping_add((line as u16).wrapping_mul(16)),
+                hex.join(" "),
+                ascii
+            );
+        }
+        out
+    }
+    /// Chainable checks on the current state, e.g. `cpu.expect().ax(5).zf(false)`
+    pub fn expect(&self) -> Expect<'_, T, M> {
+        Expect { cpu: self }
+    }
+}
+
+/// Expected register, flag and memory values, each check panicking on a mismatch
+pub struct Expect<'a, T: CodeSource, M> {
+    cpu: &'a Cpu<T, M>,
+}
+
+impl<T: CodeSource, M: AsRef<[u8]> + AsMut<[u8]>> Expect<'_, T, M> {
+    #[track_caller]
+    pub fn register(self, reg: Register, expected: u16) -> Self {
+        let actual = self.cpu.read_register(&reg);
+        assert_eq!(
+            actual, expected,
+            "{} is {:#06x}, expected {:#06x}",
+            reg, actual, expected
+        );
+        self
+    }
+    #[track_caller]
+    pub fn ax(self, expected: u16) -> Self {
+        self.register(Register::AX, expected)
+    }
+    #[track_caller]
+    pub fn bx(self, expected: u16) -> Self {
+        self.register(Register::BX, expected)
+    }
+    #[track_caller]
+    pub fn cx(self, expected: u16) -> Self {
+        self.register(Register::CX, expected)
+    }
+    #[track_caller]
+    pub fn dx(self, expected: u16) -> Self {
+        self.register(Register::DX, expected)
+    }
+    #[track_caller]
+    pub fn sp(self, expected: u16) -> Self {
+        self.register(Register::SP, expected)
+    }
+    #[track_caller]
+    pub fn bp(self, expected: u16) -> Self {
+        self.register(Register::BP, expected)
+    }
+    #[track_caller]
+    pub fn si(self, expected: u16) -> Self {
+        self.register(Register::SI, expected)
+    }
+    #[track_caller]
+    pub fn di(self, expected: u16) -> Self {
+        self.register(Register::DI, expected)
+    }
+    #[track_caller]
+    fn flag(self, name: &str, actual: bool, expected: bool) -> Self {
+        assert_eq!(
+            actual, expected,
+            "{} is {}, expected {}",
+            name, actual, expected
+        );
+        self
+    }
+    #[track_caller]
+    pub fn cf(self, expected: bool) -> Self {
+        let actual = self.cpu.cf;
+        self.flag("cf", actual, expected)
+    }
+    #[track_caller]
+    pub fn zf(self, expected: bool) -> Self {
+        let actual = self.cpu.zf;
+        self.flag("zf", actual, expected)
+    }
+    #[track_caller]
+    pub fn sf(self, expected: bool) -> Self {
+        let actual = self.cpu.sf;
+        self.flag("sf", actual, expected)
+    }
+    #[track_caller]
+    pub fn pf(self, expected: bool) -> Self {
+        let actual = self.cpu.pf;
+        self.flag("pf", actual, expected)
+    }
+    #[track_caller]
+    pub fn af(self, expected: bool) -> Self {
+        let actual = self.cpu.af;
+        self.flag("af", actual, expected)
+    }
+    #[track_caller]
+    pub fn of(self, expected: bool) -> Self {
+        let actual = self.cpu.of;
+        self.flag("of", actual, expected)
+    }
+    /// Bytes starting at physical `address`, wrapping at the end of memory
+    #[track_caller]
+    pub fn mem(self, address: u32, expected: &[u8]) -> Self {
+        let actual: Vec<u8> = (0..expected.len())
+            .map(|offset| self.cpu.memory()[(address as usize + offset) % MEMORY_SIZE])
+            .collect();
+        assert_eq!(actual, expected, "memory at {:#07x}", address);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run `code` to its end on a fresh CPU
+    fn run(code: &[u8]) -> Cpu<Cursor<Vec<u8>>> {
+        let mut cpu = Cpu::from_bytes(code);
+        cpu.run().unwrap();
+        cpu
+    }
+
+    #[test]
+    fn parity_is_even_set_bits_of_every_byte() {
+        for byte in 0..=255u8 {
+            let mut set = 0;
+            for bit in 0..8 {
+                set += (byte >> bit) & 1;
+            }
+            assert_eq!(parity(byte), set % 2 == 0, "parity of {:#04x}", byte);
+        }
+    }
+
+    #[test]
+    fn parity_only_looks_at_the_low_byte() {
+        // add ax, 0 with AX = 0x0103: three bits set in the word but two in the low byte
+        let cpu = run(&[0xB8, 0x03, 0x01, 0x05, 0x00, 0x00]);
+        cpu.expect().pf(true);
+        // AX = 0x0101: two bits set in the word but one in the low byte
+        let cpu = run(&[0xB8, 0x01, 0x01, 0x05, 0x00, 0x00]);
+        cpu.expect().pf(false);
+    }
+
+    #[test]
+    fn word_at_offset_ffff_wraps_within_its_segment() {
+        let cpu = run(&[
+            0xBB, 0x00, 0x01, // mov bx, 0x100
+            0x8E, 0xDB, // mov ds, bx
+            0xB8, 0x34, 0x12, // mov ax, 0x1234
+            0xA3, 0xFF, 0xFF, // mov [0xffff], ax
+            0x8B, 0x16, 0xFF, 0xFF, // mov dx, [0xffff]
+        ]);
+        cpu.expect()
+            .mem(0x10FFF, &[0x34])
+            .mem(0x1000, &[0x12])
+            .dx(0x1234);
+        assert_eq!(cpu.memory()[0x11000], 0);
+    }
+
+    #[test]
+    fn inc_dec_leave_carry_alone_and_flag_signed_overflow() {
+        // stc; mov ax, 0x7fff; inc ax
+        let cpu = run(&[0xF9, 0xB8, 0xFF, 0x7F, 0x40]);
+        cpu.expect().ax(0x8000).cf(true).of(true).sf(true).zf(false);
+        // clc; mov ax, 0x8000; dec ax
+        let cpu = run(&[0xF8, 0xB8, 0x00, 0x80, 0x48]);
+        cpu.expect().ax(0x7FFF).cf(false).of(true).sf(false);
+        // stc; mov ax, 0xffff; inc ax wraps to zero without touching CF
+        let cpu = run(&[0xF9, 0xB8, 0xFF, 0xFF, 0x40]);
+        cpu.expect().ax(0).cf(true).of(false).zf(true).pf(true);
+    }
+
+    /// `mov ax, index; bound ax, [0x200]; mov bx, 1; hlt` with the bounds 0..=9, and
+    /// an INT 5 handler at 0x40 that clamps AX to 0 before returning to the `bound`
+    #[cfg(feature = "target_186")]
+    fn run_bound(index: u16, install_handler: bool) -> (Cpu<Cursor<Vec<u8>>>, RunOutcome) {
+        let [low, high] = index.to_le_bytes();
+        let mut code = vec![
+            0xB8, low, high, 0x62, 0x06, 0x00, 0x02, 0xBB, 0x01, 0x00, 0xF4,
+        ];
+        code.resize(0x40, 0x90);
+        code.extend([0xB8, 0x00, 0x00, 0xCF]);
+        let vector: &[u8] = if install_handler {
+            &[0x40, 0, 0, 0]
+        } else {
+            &[0; 4]
+        };
+        let mut cpu = Cpu::load_code_and_data(&code, &[(0x200, &[0, 0, 9, 0]), (0x14, vector)]);
+        let outcome = cpu.run().unwrap();
+        (cpu, outcome)
+    }
+
+    #[cfg(feature = "target_186")]
+    #[test]
+    fn bound_only_interrupts_out_of_range() {
+        let (cpu, outcome) = run_bound(9, true);
+        assert_eq!(outcome.reason, StopReason::Halted);
+        cpu.expect().ax(9).bx(1).sp(0);
+        let (cpu, outcome) = run_bound(10, true);
+        assert_eq!(outcome.reason, StopReason::Halted);
+        cpu.expect().ax(0).bx(1).sp(0);
+
+        let mut cpu = Cpu::load_code_and_data(
+            &[0xB8, 0xFF, 0xFF, 0x62, 0x06, 0x00, 0x02],
+            &[(0x200, &[0, 0, 9, 0])],
+        );
+        assert!(matches!(
+            cpu.run(),
+            Err(CpuError::UnhandledInterrupt { vector: 5, ip: 3 })
+        ));
+    }
+
+    #[test]
+    fn segment_moves_copy_whole_words() {
+        let cpu = run(&[
+            0xB8, 0x34, 0x12, // mov ax, 0x1234
+            0x8E, 0xC0, // mov es, ax
+            0xB8, 0x20, 0x00, // mov ax, 0x20
+            0x8E, 0xD8, // mov ds, ax
+            0xBB, 0x10, 0x00, // mov bx, 0x10
+            0x8C, 0x1F, // mov [bx], ds
+            0x8C, 0x47, 0x02, // mov [bx + 2], es
+        ]);
+        cpu.expect()
+            .register(Register::ES, 0x1234)
+            .register(Register::DS, 0x20)
+            .mem(0x210, &[0x20, 0x00, 0x34, 0x12]);
+    }
+
+    #[test]
+    fn pop_sp_keeps_the_popped_value() {
+        // mov sp, 0x100; mov ax, 0x1234; push ax; pop sp
+        let cpu = run(&[0xBC, 0x00, 0x01, 0xB8, 0x34, 0x12, 0x50, 0x5C]);
+        cpu.expect().sp(0x1234);
+    }
+
+    #[test]
+    fn pop_ss_shadows_only_the_next_instruction() {
+        // mov sp, 0x100; mov ax, 0x50; push ax; push ax; pop ss; pop ss; nop; nop
+        let mut cpu = Cpu::from_bytes(&[
+            0xBC, 0x00, 0x01, 0xB8, 0x50, 0x00, 0x50, 0x50, 0x17, 0x17, 0x90, 0x90,
+        ]);
+        for _ in 0..4 {
+            cpu.step().unwrap();
+            assert!(!cpu.interrupts_inhibited());
+        }
+        cpu.step().unwrap();
+        assert!(cpu.interrupts_inhibited());
+        cpu.expect().register(Register::SS, 0x50);
+        // A second SS load right after the first doesn't extend the shadow
+        cpu.step().unwrap();
+        assert!(!cpu.interrupts_inhibited());
+        cpu.step().unwrap();
+        assert!(!cpu.interrupts_inhibited());
+    }
+
+    #[test]
+    fn salc_sets_al_from_carry() {
+        // mov ax, 0x1234; stc; salc
+        let cpu = run(&[0xB8, 0x34, 0x12, 0xF9, 0xD6]);
+        cpu.expect().ax(0x12FF).cf(true);
+        // mov ax, 0x1234; clc; salc
+        let cpu = run(&[0xB8, 0x34, 0x12, 0xF8, 0xD6]);
+        cpu.expect().ax(0x1200).cf(false);
+    }
+
+    #[test]
+    fn far_indirect_transfers_load_cs_and_ip_from_memory() {
+        // 0x1000:0x0000
+        let pointer: &[u8] = &[0x00, 0x00, 0x00, 0x10];
+        // call far [0x50]; hlt, returned to from mov ax, 5; retf
+        let main: &[u8] = &[0xFF, 0x1E, 0x50, 0x00, 0xF4];
+        let far: &[u8] = &[0xB8, 0x05, 0x00, 0xCB];
+        let mut cpu = Cpu::load_segments(
+            &[(0x0005, pointer), (0x0100, main), (0x1000, far)],
+            0x0100,
+            0,
+        );
+        assert_eq!(cpu.run_with_limit(10).unwrap().reason, StopReason::Halted);
+        cpu.expect().ax(5).sp(0).register(Register::CS, 0x0100);
+        assert_eq!(cpu.ip(), 5);
+
+        // jmp far [0x50] never comes back
+        let main: &[u8] = &[0xFF, 0x2E, 0x50, 0x00, 0xF4];
+        let far: &[u8] = &[0xB8, 0x07, 0x00, 0xF4];
+        let mut cpu = Cpu::load_segments(
+            &[(0x0005, pointer), (0x0100, main), (0x1000, far)],
+            0x0100,
+            0,
+        );
+        assert_eq!(cpu.run_with_limit(10).unwrap().reason, StopReason::Halted);
+        cpu.expect().ax(7).sp(0).register(Register::CS, 0x1000);
+        assert_eq!(cpu.ip(), 4);
+    }
+
+    #[test]
+    fn code_in_one_segment_calls_into_another() {
+        let main: &[u8] = &[
+            0xB8, 0x34, 0x12, // mov ax, 0x1234
+            0x50, // push ax
+            0x9A, 0x00, 0x00, 0x00, 0x10, // call 0x1000:0x0000
+            0xF4, // hlt
+        ];
+        let far: &[u8] = &[
+            0x89, 0xE5, // mov bp, sp
+            0x8B, 0x5E, 0x04, // mov bx, [bp + 4]
+            0xCA, 0x02, 0x00, // retf 2
+        ];
+        let mut cpu = Cpu::load_segments(&[(0x0100, main), (0x1000, far)], 0x0100, 0);
+        assert_eq!(cpu.memory()[0x1000], 0xB8);
+        assert_eq!(cpu.memory()[0x10000], 0x89);
+        assert_eq!(cpu.run_with_limit(10).unwrap().reason, StopReason::Halted);
+        cpu.expect().bx(0x1234).sp(0).register(Register::CS, 0x0100);
+        assert_eq!(cpu.ip(), 10);
+    }
+
+    #[test]
+    fn immediates_stored_at_computed_addresses_read_back() {
+        let cpu = run(&[
+            0xBB, 0x10, 0x00, // mov bx, 0x10
+            0xBE, 0x04, 0x00, // mov si, 4
+            0xC7, 0x40, 0x02, 0x34, 0x12, // mov word [bx + si + 2], 0x1234
+            0xC6, 0x00, 0x7F, // mov byte [bx + si], 0x7f
+            0x8B, 0x40, 0x02, // mov ax, [bx + si + 2]
+            0x8A, 0x08, // mov cl, [bx + si]
+            0x01, 0x40, 0x02, // add [bx + si + 2], ax
+        ]);
+        cpu.expect()
+            .ax(0x1234)
+            .cx(0x7F)
+            .mem(0x14, &[0x7F, 0x00, 0x68, 0x24]);
+    }
+
+    #[test]
+    fn push_and_pop_use_a_downward_little_endian_stack() {
+        let mut cpu = Cpu::from_bytes(&[
+            0xBC, 0x00, 0x01, // mov sp, 0x100
+            0xB8, 0x34, 0x12, // mov ax, 0x1234
+            0x50, // push ax
+            0xB8, 0x00, 0x00, // mov ax, 0
+            0x5B, // pop bx
+            0x53, // push bx
+            0x8F, 0x06, 0x40, 0x00, // pop word [0x40]
+            0xFF, 0x36, 0x40, 0x00, // push word [0x40]
+            0x59, // pop cx
+        ]);
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        cpu.expect().sp(0xFE).mem(0xFE, &[0x34, 0x12]);
+        cpu.run().unwrap();
+        cpu.expect()
+            .ax(0)
+            .bx(0x1234)
+            .cx(0x1234)
+            .sp(0x100)
+            .mem(0x40, &[0x34, 0x12]);
+    }
+
+    #[test]
+    fn runs_over_borrowed_memory() {
+        let mut memory = vec![0; MEMORY_SIZE];
+        memory[0x20] = 0x05;
+        // mov al, [0x20]; mov byte [0x10], 0x42
+        let code = Cursor::new(vec![0xA0, 0x20, 0x00, 0xC6, 0x06, 0x10, 0x00, 0x42]);
+        let mut cpu = Cpu::with_memory(code, &mut memory[..]).unwrap();
+        cpu.step().unwrap();
+        cpu.expect().ax(0x05);
+        assert_eq!(cpu.memory()[0x10], 0);
+        cpu.step().unwrap();
+        assert_eq!(cpu.memory()[0x10], 0x42);
+        drop(cpu);
+        assert_eq!(memory[0x10], 0x42);
+
+        let mut small = [0u8; 16];
+        assert!(matches!(
+            Cpu::with_memory(Cursor::new(vec![]), &mut small[..]),
+            Err(LoadError::MemoryTooSmall {
+                actual: 16,
+                required: MEMORY_SIZE
+            })
+        ));
+    }
+
+    #[test]
+    fn routine_called_twice_returns_each_time() {
+        let mut cpu = Cpu::from_bytes(&[
+            0xBC, 0x00, 0x01, // mov sp, 0x100
+            0xE8, 0x04, 0x00, // call 0x0a
+            0xE8, 0x01, 0x00, // call 0x0a
+            0xF4, // hlt
+            0x40, // 0x0a: inc ax
+            0xC3, // ret
+        ]);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.ip(), 0x0A);
+        cpu.expect().sp(0xFE).mem(0xFE, &[0x06, 0x00]);
+        let outcome = cpu.run().unwrap();
+        assert_eq!(outcome.reason, StopReason::Halted);
+        assert_eq!(outcome.ip, 0x0A);
+        cpu.expect().ax(2).sp(0x100);
+    }
+
+    #[test]
+    fn inc_dec_set_af_on_a_carry_out_of_the_low_digit() {
+        // mov al, 0x0f; inc al
+        run(&[0xB0, 0x0F, 0xFE, 0xC0]).expect().ax(0x10).af(true);
+        // mov al, 0x0e; inc al
+        run(&[0xB0, 0x0E, 0xFE, 0xC0]).expect().ax(0x0F).af(false);
+        // mov al, 0x10; dec al
+        run(&[0xB0, 0x10, 0xFE, 0xC8]).expect().ax(0x0F).af(true);
+        // mov ax, 0x00ff; inc ax carries out of bit 3 of the low byte too
+        run(&[0xB8, 0xFF, 0x00, 0x40]).expect().ax(0x100).af(true);
+    }
+
+    #[test]
+    fn ip_advances_by_the_bytes_each_instruction_takes() {
+        let mut cpu = Cpu::from_bytes(&[
+            0xB8, 0x01, 0x00, // mov ax, 1
+            0x90, // nop
+            0x8B, 0x87, 0x34, 0x12, // mov ax, [bx + 4660]
+            0xEB, 0xF6, // jmp 0
+        ]);
+        for expected in [3, 4, 8, 0, 3] {
+            cpu.step().unwrap();
+            assert_eq!(cpu.ip(), expected);
+        }
+        assert_eq!(cpu.last_instruction().unwrap().0, 0);
+        assert!(cpu.registers_line().contains("IP=0003"));
+    }
+
+    #[test]
+    fn segment_registers_print_their_own_values() {
+        let mut cpu = Cpu::from_bytes(&[]);
+        cpu.set_register(Register::DI, 0x0007);
+        cpu.set_register(Register::SS, 0x0800);
+        cpu.set_register(Register::DS, 0x0900);
+        cpu.set_register(Register::ES, 0x0A00);
+        let mut out = Vec::new();
+        cpu.write_registers(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[7], "di: 0x07 (7)");
+        assert_eq!(lines[8], "ss: 0x800 (2048)");
+        assert_eq!(lines[9], "ds: 0x900 (2304)");
+        assert_eq!(lines[10], "es: 0xa00 (2560)");
+        assert_eq!(lines[11], "cs: 0x00 (0)");
+        assert_eq!(lines[12], "ip: 0x00 (0)");
+    }
+
+    #[test]
+    fn carry_is_unsigned_and_overflow_signed() {
+        // mov ax, 0xffff; add ax, 1
+        run(&[0xB8, 0xFF, 0xFF, 0x05, 0x01, 0x00])
+            .expect()
+            .ax(0)
+            .cf(true)
+            .of(false)
+            .zf(true);
+        // mov ax, 0x7fff; add ax, 1
+        run(&[0xB8, 0xFF, 0x7F, 0x05, 0x01, 0x00])
+            .expect()
+            .ax(0x8000)
+            .cf(false)
+            .of(true);
+        // mov ax, 0; sub ax, 1 borrows; cmp sets CF the same way without storing
+        run(&[0xB8, 0x00, 0x00, 0x2D, 0x01, 0x00])
+            .expect()
+            .ax(0xFFFF)
+            .cf(true)
+            .of(false);
+        run(&[0xB8, 0x00, 0x00, 0x3D, 0x01, 0x00])
+            .expect()
+            .ax(0)
+            .cf(true);
+        // stc
+        assert_eq!(run(&[0xF9]).flags().to_string(), "C");
+    }
+
+    #[test]
+    fn registers_line_keeps_every_flag_in_place() {
+        // mov ax, 5; add ax, -5; sti
+        let cpu = run(&[0xB8, 0x05, 0x00, 0x05, 0xFB, 0xFF, 0xFB]);
+        assert_eq!(
+            cpu.registers_line(),
+            "AX=0000 BX=0000 CX=0000 DX=0000 SP=0000 BP=0000 SI=0000 DI=0000 \
+             SS=0000 DS=0000 ES=0000 CS=0000 IP=0007 FLAGS=CPAZ-I--"
+        );
+        // std; mov bx, 0x8000; or bx, bx
+        let cpu = run(&[0xFD, 0xBB, 0x00, 0x80, 0x09, 0xDB]);
+        assert!(cpu.registers_line().contains("BX=8000 "));
+        assert!(cpu.registers_line().ends_with("IP=0006 FLAGS=-P--S-D-"));
+    }
+
+    #[test]
+    fn af_reports_a_carry_or_borrow_across_the_low_digit() {
+        // mov al, 0; add al, 0x0f; add al, 1
+        run(&[0xB0, 0x00, 0x04, 0x0F, 0x04, 0x01])
+            .expect()
+            .ax(0x10)
+            .af(true);
+        // mov al, 0; add al, 0x0e; add al, 1
+        run(&[0xB0, 0x00, 0x04, 0x0E, 0x04, 0x01])
+            .expect()
+            .ax(0x0F)
+            .af(false);
+        // mov al, 0x10; sub al, 1
+        run(&[0xB0, 0x10, 0x2C, 0x01]).expect().ax(0x0F).af(true);
+        // mov al, 0x10; cmp al, 1
+        run(&[0xB0, 0x10, 0x3C, 0x01]).expect().ax(0x10).af(true);
+        // mov al, 0x1f; cmp al, 1
+        run(&[0xB0, 0x1F, 0x3C, 0x01]).expect().af(false);
+    }
+
+    #[test]
+    fn run_program_applies_setup_and_snapshots_the_end() {
+        // add ax, bx; mov [0x10], ax
+        let result = run_program(&[0x01, 0xD8, 0xA3, 0x10, 0x00], |cpu| {
+            cpu.set_register(Register::AX, 2);
+            cpu.set_register(Register::BX, 3);
+        });
+        let outcome = result.outcome.unwrap();
+        assert_eq!(outcome.reason, StopReason::EndOfProgram);
+        assert_eq!(outcome.instructions, 2);
+        assert_eq!(result.state.registers[0], 5);
+        assert_eq!(result.ip, 5);
+
+        // Errors are reported in the outcome rather than by panicking
+        let result = run_program(&[0xCD, 0x21], |_| {});
+        assert_eq!(
+            result.outcome,
+            Err(CpuError::UnhandledInterrupt {
+                vector: 0x21,
+                ip: 0
+            })
+        );
+        assert_eq!(result.ip, 2);
+    }
+
+    #[test]
+    fn daa_adjusts_packed_bcd_sums() {
+        // mov al, 0x1a; daa
+        run(&[0xB0, 0x1A, 0x27])
+            .expect()
+            .ax(0x20)
+            .af(true)
+            .cf(false)
+            .zf(false);
+        // mov al, 0x38; add al, 0x45; daa
+        run(&[0xB0, 0x38, 0x04, 0x45, 0x27])
+            .expect()
+            .ax(0x83)
+            .cf(false)
+            .sf(true);
+        // mov al, 0x99; add al, 1; daa carries out of the second digit
+        run(&[0xB0, 0x99, 0x04, 0x01, 0x27])
+            .expect()
+            .ax(0x00)
+            .af(true)
+            .cf(true)
+            .zf(true)
+            .pf(true);
+        // mov al, 0x09; add al, 0x09 sets AF without leaving a digit above 9
+        run(&[0xB0, 0x09, 0x04, 0x09, 0x27])
+            .expect()
+            .ax(0x18)
+            .af(true);
+    }
+
+    #[test]
+    fn aaa_adjusts_unpacked_bcd_sums() {
+        // mov ax, 0x000f; aaa
+        run(&[0xB8, 0x0F, 0x00, 0x37])
+            .expect()
+            .ax(0x0105)
+            .af(true)
+            .cf(true);
+        // stc; mov ax, 0x0235; aaa only masks AL and clears AF and CF
+        run(&[0xF9, 0xB8, 0x35, 0x02, 0x37])
+            .expect()
+            .ax(0x0205)
+            .af(false)
+            .cf(false);
+        // mov ax, 0x0008; add al, 0x09; aaa adjusts on AF with a digit of 1
+        run(&[0xB8, 0x08, 0x00, 0x04, 0x09, 0x37])
+            .expect()
+            .ax(0x0107)
+            .cf(true);
+    }
+
+    #[test]
+    fn inc_dec_respect_the_operand_width() {
+        // mov ax, 0; dec ax
+        run(&[0xB8, 0x00, 0x00, 0x48])
+            .expect()
+            .ax(0xFFFF)
+            .sf(true)
+            .cf(false);
+        // mov ax, 0x7fff; inc ax
+        run(&[0xB8, 0xFF, 0x7F, 0x40])
+            .expect()
+            .ax(0x8000)
+            .of(true)
+            .cf(false);
+        // mov bx, 0x10; mov word [bx], 0x00ff; inc byte [bx]; dec word [bx + 2]
+        run(&[
+            0xBB, 0x10, 0x00, 0xC7, 0x07, 0xFF, 0x00, 0xFE, 0x07, 0xFF, 0x4F, 0x02,
+        ])
+        .expect()
+        .mem(0x10, &[0x00, 0x00, 0xFF, 0xFF])
+        .sf(true);
+        // mov ax, 0x12ff; inc al leaves AH alone
+        run(&[0xB8, 0xFF, 0x12, 0xFE, 0xC0])
+            .expect()
+            .ax(0x1200)
+            .zf(true);
+    }
+
+    #[test]
+    fn adc_chains_a_multiword_addition() {
+        // si:dx:ax = 0x0000ffffffff, di:cx:bx = 1
+        let cpu = run(&[
+            0xB8, 0xFF, 0xFF, // mov ax, 0xffff
+            0xBA, 0xFF, 0xFF, // mov dx, 0xffff
+            0xBB, 0x01, 0x00, // mov bx, 1
+            0x01, 0xD8, // add ax, bx
+            0x11, 0xCA, // adc dx, cx
+            0x11, 0xFE, // adc si, di
+        ]);
+        cpu.expect().ax(0).dx(0).si(1).cf(false).zf(false);
+        // stc; mov al, 0x7f; adc al, 0 overflows only through the carry
+        run(&[0xF9, 0xB0, 0x7F, 0x14, 0x00])
+            .expect()
+            .ax(0x80)
+            .of(true)
+            .cf(false)
+            .af(true);
+        // stc; mov ax, 0xffff; adc ax, 0
+        run(&[0xF9, 0xB8, 0xFF, 0xFF, 0x15, 0x00, 0x00])
+            .expect()
+            .ax(0)
+            .cf(true)
+            .zf(true);
+    }
+
+    #[test]
+    fn sbb_borrows_across_dx_ax() {
+        // dx:ax = 0x00010000 - cx:bx = 0x00000001
+        let cpu = run(&[
+            0xBA, 0x01, 0x00, // mov dx, 1
+            0xBB, 0x01, 0x00, // mov bx, 1
+            0x29, 0xD8, // sub ax, bx
+            0x19, 0xCA, // sbb dx, cx
+        ]);
+        cpu.expect().ax(0xFFFF).dx(0).cf(false).zf(true);
+        // dx:ax = 0 - 1 borrows out of the top word too
+        run(&[0xBB, 0x01, 0x00, 0x29, 0xD8, 0x19, 0xCA])
+            .expect()
+            .ax(0xFFFF)
+            .dx(0xFFFF)
+            .cf(true)
+            .sf(true);
+        // stc; mov al, 0x80; sbb al, 0 overflows only through the borrow
+        run(&[0xF9, 0xB0, 0x80, 0x1C, 0x00])
+            .expect()
+            .ax(0x7F)
+            .of(true)
+            .cf(false)
+            .af(true);
+    }
+
+    #[test]
+    fn logical_operations_clear_cf_and_of() {
+        // mov ax, 0xf0f0; mov bx, 0x0ff0; stc; <op> ax, bx
+        let with_bx = |op: u8| run(&[0xB8, 0xF0, 0xF0, 0xBB, 0xF0, 0x0F, 0xF9, op, 0xD8]);
+        with_bx(0x21)
+            .expect()
+            .ax(0x00F0)
+            .cf(false)
+            .of(false)
+            .sf(false)
+            .pf(true);
+        with_bx(0x09).expect().ax(0xFFF0).cf(false).sf(true);
+        with_bx(0x31)
+            .expect()
+            .ax(0xFF00)
+            .cf(false)
+            .sf(true)
+            .pf(true);
+        with_bx(0x85)
+            .expect()
+            .ax(0xF0F0)
+            .bx(0x0FF0)
+            .cf(false)
+            .zf(false);
+
+        // mov ax, 0xf0f0; stc; <op> ax, imm16
+        let with_immediate = |op: u8, imm: u16| {
+            let [low, high] = imm.to_le_bytes();
+            run(&[0xB8, 0xF0, 0xF0, 0xF9, op, low, high])
+        };
+        with_immediate(0x25, 0x0F0F)
+            .expect()
+            .ax(0)
+            .zf(true)
+            .cf(false);
+        with_immediate(0x0D, 0x0001)
+            .expect()
+            .ax(0xF0F1)
+            .pf(false)
+            .cf(false);
+        with_immediate(0x35, 0xF0F0)
+            .expect()
+            .ax(0)
+            .zf(true)
+            .cf(false);
+        with_immediate(0xA9, 0x8000)
+            .expect()
+            .ax(0xF0F0)
+            .sf(true)
+            .cf(false);
+        // mov bx, 2; test bx, 1 through the 0xF7 group
+        run(&[0xBB, 0x02, 0x00, 0xF7, 0xC3, 0x01, 0x00])
+            .expect()
+            .bx(2)
+            .zf(true);
+    }
+
+    #[test]
+    fn not_complements_and_neg_negates() {
+        // mov ax, 1; neg ax
+        run(&[0xB8, 0x01, 0x00, 0xF7, 0xD8])
+            .expect()
+            .ax(0xFFFF)
+            .cf(true)
+            .sf(true)
+            .af(true);
+        // neg ax of 0 leaves CF clear
+        run(&[0xF7, 0xD8]).expect().ax(0).cf(false).zf(true);
+        // mov ax, 0x8000; neg ax
+        run(&[0xB8, 0x00, 0x80, 0xF7, 0xD8])
+            .expect()
+            .ax(0x8000)
+            .of(true)
+            .cf(true);
+        // mov ax, 0x00ff; stc; not ax keeps the flags
+        run(&[0xB8, 0xFF, 0x00, 0xF9, 0xF7, 0xD0])
+            .expect()
+            .ax(0xFF00)
+            .cf(true)
+            .sf(false);
+        // mov bx, 0x10; mov word [bx], 0x0102; neg byte [bx]; not byte [bx + 1]
+        run(&[
+            0xBB, 0x10, 0x00, 0xC7, 0x07, 0x02, 0x01, 0xF6, 0x1F, 0xF6, 0x57, 0x01,
+        ])
+        .expect()
+        .mem(0x10, &[0xFE, 0xFE]);
+    }
+
+    #[test]
+    fn mul_and_imul_set_cf_and_of_when_the_upper_half_matters() {
+        // mov al, 3; mov bl, 4; mul bl
+        run(&[0xB0, 0x03, 0xB3, 0x04, 0xF6, 0xE3])
+            .expect()
+            .ax(12)
+            .cf(false)
+            .of(false);
+        // mov al, 0x10; mov bl, 0x10; mul bl
+        run(&[0xB0, 0x10, 0xB3, 0x10, 0xF6, 0xE3])
+            .expect()
+            .ax(0x0100)
+            .cf(true)
+            .of(true);
+        // mov ax, 0x1234; mov bx, 0x100; mul bx
+        run(&[0xB8, 0x34, 0x12, 0xBB, 0x00, 0x01, 0xF7, 0xE3])
+            .expect()
+            .dx(0x0012)
+            .ax(0x3400)
+            .cf(true);
+        // mov al, -2; mov bl, 3; imul bl sign-extends into AH without overflow
+        run(&[0xB0, 0xFE, 0xB3, 0x03, 0xF6, 0xEB])
+            .expect()
+            .ax(0xFFFA)
+            .cf(false)
+            .of(false);
+        // mov ax, -1; mov bx, 0x8000; imul bx gives +32768, which needs DX
+        run(&[0xB8, 0xFF, 0xFF, 0xBB, 0x00, 0x80, 0xF7, 0xEB])
+            .expect()
+            .dx(0)
+            .ax(0x8000)
+            .cf(true)
+            .of(true);
+    }
+
+    #[test]
+    fn div_and_idiv_split_quotient_and_remainder() {
+        // mov ax, 100; mov bl, 10; div bl
+        run(&[0xB8, 0x64, 0x00, 0xB3, 0x0A, 0xF6, 0xF3])
+            .expect()
+            .ax(0x000A);
+        // mov ax, 103; mov bl, 10; div bl
+        run(&[0xB8, 0x67, 0x00, 0xB3, 0x0A, 0xF6, 0xF3])
+            .expect()
+            .ax(0x030A);
+        // mov dx, 1; mov ax, 5; mov bx, 0x10; div bx
+        run(&[
+            0xBA, 0x01, 0x00, 0xB8, 0x05, 0x00, 0xBB, 0x10, 0x00, 0xF7, 0xF3,
+        ])
+        .expect()
+        .ax(0x1000)
+        .dx(5);
+        // mov ax, -7; mov bl, 2; idiv bl truncates toward zero
+        run(&[0xB8, 0xF9, 0xFF, 0xB3, 0x02, 0xF6, 0xFB])
+            .expect()
+            .ax(0xFFFD);
+    }
+
+    #[test]
+    fn divide_errors_raise_interrupt_0() {
+        // mov ax, 1; div bl with BL = 0
+        let mut cpu = Cpu::from_bytes(&[0xB8, 0x01, 0x00, 0xF6, 0xF3]);
+        assert_eq!(
+            cpu.run(),
+            Err(CpuError::UnhandledInterrupt { vector: 0, ip: 3 })
+        );
+
+        // mov ax, 0x1000; mov bl, 1; div bl; hlt, with a quotient too big for AL and
+        // a handler at 0x40 doing mov cx, 0xdead; iret
+        let mut code = vec![0xB8, 0x00, 0x10, 0xB3, 0x01, 0xF6, 0xF3, 0xF4];
+        code.resize(0x40, 0x90);
+        code.extend([0xB9, 0xAD, 0xDE, 0xCF]);
+        let mut cpu = Cpu::load_code_and_data(&code, &[(0, &[0x40, 0, 0, 0])]);
+        assert_eq!(cpu.run().unwrap().reason, StopReason::Halted);
+        cpu.expect().cx(0xDEAD).ax(0x1000).sp(0);
+    }
+
+    #[test]
+    fn shifts_and_rotates_move_the_last_bit_into_cf() {
+        // mov ax, 0x8001; shl ax, 1
+        run(&[0xB8, 0x01, 0x80, 0xD1, 0xE0])
+            .expect()
+            .ax(0x0002)
+            .cf(true)
+            .of(true)
+            .zf(false);
+        // mov al, 0x81; shr al, 1
+        run(&[0xB0, 0x81, 0xD0, 0xE8])
+            .expect()
+            .ax(0x40)
+            .cf(true)
+            .of(true);
+        // mov al, 0x80; mov cl, 3; sar al, cl keeps the sign
+        run(&[0xB0, 0x80, 0xB1, 0x03, 0xD2, 0xF8])
+            .expect()
+            .ax(0x00F0)
+            .cf(false)
+            .sf(true);
+        // mov ax, 0x8001; rol ax, 1 wraps the top bit around
+        run(&[0xB8, 0x01, 0x80, 0xD1, 0xC0])
+            .expect()
+            .ax(0x0003)
+            .cf(true);
+        // stc; mov al, 0; rcl al, 1 rotates the carry in
+        run(&[0xF9, 0xB0, 0x00, 0xD0, 0xD0])
+            .expect()
+            .ax(0x01)
+            .cf(false);
+        // clc; mov al, 1; rcr al, 1 rotates the low bit out into the carry
+        run(&[0xF8, 0xB0, 0x01, 0xD0, 0xD8])
+            .expect()
+            .ax(0x00)
+            .cf(true);
+    }
+
+    #[test]
+    fn unconditional_jumps_skip_what_they_jump_over() {
+        // mov ax, 1; jmp short +3; mov ax, 0xdead; inc ax
+        run(&[0xB8, 0x01, 0x00, 0xEB, 0x03, 0xB8, 0xAD, 0xDE, 0x40])
+            .expect()
+            .ax(2);
+        // mov ax, 1; jmp near +3; mov ax, 0xdead; inc ax
+        run(&[0xB8, 0x01, 0x00, 0xE9, 0x03, 0x00, 0xB8, 0xAD, 0xDE, 0x40])
+            .expect()
+            .ax(2);
+    }
+
+    /// Whether the jump `opcode` is taken after `setup`, by having it skip over
+    /// `mov bx, 1`
+    fn jump_taken(setup: &[u8], opcode: u8) -> bool {
+        let code = [setup, &[opcode, 0x03, 0xBB, 0x01, 0x00]].concat();
+        run(&code).register(Register::BX) == 0
+    }
+
+    #[test]
+    fn conditional_jumps_test_their_flags() {
+        // mov al, a; cmp al, b
+        let cmp = |a: u8, b: u8| [0xB0, a, 0x3C, b];
+        for (opcode, a, b, taken) in [
+            (0x70, 0x80, 1, true), // jo
+            (0x70, 1, 2, false),
+            (0x71, 1, 2, true), // jno
+            (0x71, 0x80, 1, false),
+            (0x72, 1, 2, true), // jb
+            (0x72, 2, 1, false),
+            (0x73, 2, 1, true), // jnb
+            (0x73, 1, 2, false),
+            (0x74, 1, 1, true), // je
+            (0x74, 1, 2, false),
+            (0x75, 1, 2, true), // jne
+            (0x75, 1, 1, false),
+            (0x76, 1, 1, true), // jbe
+            (0x76, 1, 2, true),
+            (0x76, 2, 1, false),
+            (0x77, 2, 1, true), // ja
+            (0x77, 0xFF, 1, true),
+            (0x77, 1, 1, false),
+            (0x78, 1, 2, true), // js
+            (0x78, 2, 1, false),
+            (0x79, 2, 1, true), // jns
+            (0x79, 1, 2, false),
+            (0x7A, 1, 1, true), // jp
+            (0x7A, 2, 1, false),
+            (0x7B, 2, 1, true), // jnp
+            (0x7B, 1, 1, false),
+            (0x7C, 0x80, 1, true), // jl
+            (0x7C, 1, 2, true),
+            (0x7C, 2, 1, false),
+            (0x7C, 1, 1, false),
+            (0x7D, 2, 1, true), // jnl
+            (0x7D, 1, 1, true),
+            (0x7D, 0x80, 1, false),
+            (0x7E, 1, 1, true), // jle
+            (0x7E, 0x80, 1, true),
+            (0x7E, 2, 1, false),
+            (0x7F, 2, 1, true), // jnle
+            (0x7F, 1, 1, false),
+            (0x7F, 0xFF, 1, false),
+        ] {
+            assert_eq!(
+                jump_taken(&cmp(a, b), opcode),
+                taken,
+                "{:#04x} after cmp {:#04x}, {:#04x}",
+                opcode,
+                a,
+                b
+            );
+        }
+    }
+
+    #[test]
+    fn loop_jumps_test_cx_after_decrementing() {
+        // mov al, a; cmp al, b; mov cx, count
+        let setup = |a: u8, b: u8, count: u8| [0xB0, a, 0x3C, b, 0xB9, count, 0x00];
+        assert!(jump_taken(&setup(1, 1, 2), 0xE2)); // loop
+        assert!(!jump_taken(&setup(1, 1, 1), 0xE2));
+        assert!(jump_taken(&setup(1, 1, 2), 0xE1)); // loopz
+        assert!(!jump_taken(&setup(1, 2, 2), 0xE1));
+        assert!(!jump_taken(&setup(1, 1, 1), 0xE1));
+        assert!(jump_taken(&setup(1, 2, 2), 0xE0)); // loopnz
+        assert!(!jump_taken(&setup(1, 1, 2), 0xE0));
+        assert!(jump_taken(&setup(1, 1, 0), 0xE3)); // jcxz
+        assert!(!jump_taken(&setup(1, 1, 1), 0xE3));
+        // jcxz leaves CX alone, loop decrements it
+        run(&[0xB9, 0x01, 0x00, 0xE3, 0x00]).expect().cx(1);
+        run(&[0xB9, 0x01, 0x00, 0xE2, 0x00]).expect().cx(0);
+    }
+
+    #[test]
+    fn add_sets_of_only_for_signed_overflow() {
+        // mov ax, 0x7fff; add ax, 1
+        run(&[0xB8, 0xFF, 0x7F, 0x05, 0x01, 0x00])
+            .expect()
+            .of(true)
+            .cf(false);
+        // mov ax, 0xffff; add ax, 1
+        run(&[0xB8, 0xFF, 0xFF, 0x05, 0x01, 0x00])
+            .expect()
+            .of(false)
+            .cf(true);
+        // mov al, 0x80; add al, 0x80 overflows at the byte's sign bit
+        run(&[0xB0, 0x80, 0x04, 0x80])
+            .expect()
+            .ax(0)
+            .of(true)
+            .cf(true);
+        // mov al, 0xff; add al, 0x7f mixes signs, so it never overflows
+        run(&[0xB0, 0xFF, 0x04, 0x7F])
+            .expect()
+            .ax(0x7E)
+            .of(false)
+            .cf(true);
+        // mov bx, 0x10; mov word [bx], 0x7fff; mov ax, 1; add [bx], ax reads the old value
+        run(&[
+            0xBB, 0x10, 0x00, 0xC7, 0x07, 0xFF, 0x7F, 0xB8, 0x01, 0x00, 0x01, 0x07,
+        ])
+        .expect()
+        .mem(0x10, &[0x00, 0x80])
+        .of(true);
+    }
+
+    #[test]
+    fn byte_arithmetic_keeps_the_other_half_of_the_register() {
+        // mov ax, 0x12ff; add al, 1
+        run(&[0xB8, 0xFF, 0x12, 0x04, 0x01])
+            .expect()
+            .ax(0x1200)
+            .cf(true)
+            .zf(true);
+        // mov ax, 0x1234; add ah, 0xf0
+        run(&[0xB8, 0x34, 0x12, 0x80, 0xC4, 0xF0])
+            .expect()
+            .ax(0x0234)
+            .cf(true);
+        // mov ax, 0x1200; sub al, 1
+        run(&[0xB8, 0x00, 0x12, 0x2C, 0x01])
+            .expect()
+            .ax(0x12FF)
+            .cf(true)
+            .sf(true);
+        // mov ax, 0x1234; cmp ah, 0x12 stores nothing
+        run(&[0xB8, 0x34, 0x12, 0x80, 0xFC, 0x12])
+            .expect()
+            .ax(0x1234)
+            .zf(true);
+        // mov ax, 0x007f; add al, 1 takes SF from bit 7
+        run(&[0xB8, 0x7F, 0x00, 0x04, 0x01])
+            .expect()
+            .ax(0x0080)
+            .sf(true)
+            .of(true);
+    }
+
+    #[test]
+    fn truncated_program_stops_with_a_decode_error() {
+        // mov ax, 1; mov bx, <missing high byte>
+        let mut cpu = Cpu::from_bytes(&[0xB8, 0x01, 0x00, 0xBB, 0x02]);
+        assert_eq!(
+            cpu.run(),
+            Err(CpuError::Decode {
+                error: DecodeError::UnexpectedEof,
+                ip: 3
+            })
+        );
+        cpu.expect().ax(1);
+    }
+
+    #[test]
+    fn byte_registers_compose_their_word_register() {
+        let mut cpu = Cpu::from_bytes(&[]);
+        cpu.set_register(Register::AH, 0x12);
+        cpu.set_register(Register::AL, 0x34);
+        assert_eq!(cpu.register(Register::AX), 0x1234);
+        assert_eq!(cpu.register(Register::AH), 0x12);
+        assert_eq!(cpu.register(Register::AL), 0x34);
+        // Only the low byte of the value lands in a byte register
+        cpu.set_register(Register::AL, 0xABCD);
+        assert_eq!(cpu.register(Register::AX), 0x12CD);
+        cpu.set_register(Register::BH, 0xFF);
+        assert_eq!(cpu.register(Register::BX), 0xFF00);
+        assert_eq!(cpu.register(Register::BL), 0);
+        cpu.set_register(Register::SI, 0xBEEF);
+        cpu.expect().ax(0x12CD).bx(0xFF00).si(0xBEEF);
+    }
+
+    #[test]
+    fn step_executes_one_instruction_at_a_time() {
+        // mov ax, 1; mov bx, 2
+        let mut cpu = Cpu::from_bytes(&[0xB8, 0x01, 0x00, 0xBB, 0x02, 0x00]);
+        let first = cpu.step().unwrap().unwrap().to_string();
+        assert_eq!(first, "mov ax, 1");
+        cpu.expect().ax(1).bx(0);
+        let second = cpu.step().unwrap().unwrap().to_string();
+        assert_eq!(second, "mov bx, 2");
+        cpu.expect().ax(1).bx(2);
+        assert!(cpu.step().unwrap().is_none());
+        assert_eq!(cpu.ip(), 6);
+    }
+
+    #[test]
+    fn flags_report_the_exact_cmp_outcome() {
+        // mov al, 1; cmp al, 2
+        let cpu = run(&[0xB0, 0x01, 0x3C, 0x02]);
+        assert_eq!(
+            cpu.flags(),
+            Flags {
+                cf: true,
+                sf: true,
+                pf: true,
+                af: true,
+                ..Flags::default()
+            }
+        );
+        assert_eq!(cpu.flags().to_string(), "CPAS");
+        // ... then std; sti
+        let cpu = run(&[0xB0, 0x01, 0x3C, 0x02, 0xFD, 0xFB]);
+        assert_eq!(cpu.flags().to_string(), "CPASID");
+        let mut out = Vec::new();
+        cpu.write_registers(&mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().ends_with("flags: CPASID\n"));
+        assert!(cpu.registers_line().ends_with("FLAGS=CPA-SID-"));
+    }
+
+    #[test]
+    fn hlt_stops_before_trailing_garbage() {
+        // mov ax, 1; hlt; then an unknown opcode and a truncated mov
+        let mut cpu = Cpu::from_bytes(&[0xB8, 0x01, 0x00, 0xF4, 0x0F, 0xB8]);
+        let outcome = cpu.run().unwrap();
+        assert_eq!(outcome.reason, StopReason::Halted);
+        assert_eq!(outcome.instructions, 2);
+        assert_eq!(outcome.ip, 4);
+        assert!(cpu.step().unwrap().is_none());
+        assert_eq!(cpu.last_instruction().unwrap().1.to_string(), "hlt");
+        cpu.expect().ax(1);
+    }
+
+    #[test]
+    fn xchg_swaps_registers_and_memory_without_touching_flags() {
+        // mov ax, 1; mov bx, 2; stc; xchg ax, bx
+        run(&[0xB8, 0x01, 0x00, 0xBB, 0x02, 0x00, 0xF9, 0x93])
+            .expect()
+            .ax(2)
+            .bx(1)
+            .cf(true);
+        // mov ax, 0x1234; mov bx, 0x10; mov word [bx], 0x5678; xchg ax, [bx]
+        run(&[
+            0xB8, 0x34, 0x12, 0xBB, 0x10, 0x00, 0xC7, 0x07, 0x78, 0x56, 0x87, 0x07,
+        ])
+        .expect()
+        .ax(0x5678)
+        .mem(0x10, &[0x34, 0x12]);
+        // mov ax, 0x1234; mov bx, 0x5678; xchg al, bl
+        run(&[0xB8, 0x34, 0x12, 0xBB, 0x78, 0x56, 0x86, 0xC3])
+            .expect()
+            .ax(0x1278)
+            .bx(0x5634);
+    }
+
+    #[test]
+    fn lea_loads_the_address_without_reading_memory() {
+        // mov si, 0x10; mov word [si + 4], 0xBEEF; cmp si, si; lea bx, [si + 4]
+        let cpu = run(&[
+            0xBE, 0x10, 0x00, 0xC7, 0x44, 0x04, 0xEF, 0xBE, 0x39, 0xF6, 0x8D, 0x5C, 0x04,
+        ]);
+        cpu.expect()
+            .bx(0x14)
+            .si(0x10)
+            .zf(true)
+            .mem(0x14, &[0xEF, 0xBE]);
+    }
+
+    #[test]
+    fn segment_override_picks_the_segment_of_the_access() {
+        let cpu = run(&[
+            0xB8, 0x00, 0x01, // mov ax, 0x100
+            0x8E, 0xC0, // mov es, ax
+            0xBB, 0x04, 0x00, // mov bx, 4
+            0x26, 0xC7, 0x07, 0x34, 0x12, // mov word es:[bx], 0x1234
+            0x26, 0x8B, 0x0F, // mov cx, es:[bx]
+            0x8B, 0x17, // mov dx, [bx]
+        ]);
+        cpu.expect()
+            .mem(0x1004, &[0x34, 0x12])
+            .mem(0x0004, &[0x00, 0x00])
+            .cx(0x1234)
+            .dx(0);
+    }
+
+    #[test]
+    fn movsb_copies_a_buffer_and_advances_si_and_di() {
+        let mut cpu = Cpu::from_bytes(&[
+            0xBE, 0x00, 0x01, // mov si, 0x100
+            0xBF, 0x00, 0x02, // mov di, 0x200
+            0xA4, 0xA4, 0xA4, // movsb; movsb; movsb
+        ]);
+        cpu.write_mem(0x100, b"abc");
+        cpu.run().unwrap();
+        cpu.expect().mem(0x200, b"abc").si(0x103).di(0x203);
+    }
+
+    #[test]
+    fn stos_stores_al_or_ax_and_steps_di_by_the_operand_size() {
+        let cpu = run(&[
+            0xBF, 0x00, 0x02, // mov di, 0x200
+            0xB8, 0x34, 0x12, // mov ax, 0x1234
+            0xAA, // stosb
+            0xAB, // stosw
+        ]);
+        cpu.expect().mem(0x200, &[0x34, 0x34, 0x12]).di(0x203);
+    }
+
+    #[test]
+    fn rep_movsw_copies_cx_words() {
+        let mut cpu = Cpu::from_bytes(&[
+            0xBE, 0x00, 0x01, // mov si, 0x100
+            0xBF, 0x00, 0x02, // mov di, 0x200
+            0xB9, 0x04, 0x00, // mov cx, 4
+            0xF3, 0xA5, // rep movsw
+        ]);
+        cpu.write_mem(0x100, &[1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        cpu.run().unwrap();
+        cpu.expect()
+            .mem(0x200, &[1, 2, 3, 4, 5, 6, 7, 8, 0])
+            .cx(0)
+            .si(0x108)
+            .di(0x208);
+    }
+
+    #[test]
+    fn rep_with_cx_zero_does_nothing() {
+        // mov di, 0x200; mov al, 0xFF; rep stosb
+        run(&[0xBF, 0x00, 0x02, 0xB0, 0xFF, 0xF3, 0xAA])
+            .expect()
+            .mem(0x200, &[0])
+            .cx(0)
+            .di(0x200);
+    }
+
+    #[test]
+    fn repe_and_repne_stop_early_on_zf() {
+        // repe cmpsb stops after the first mismatch, at the third byte
+        let mut cpu = Cpu::from_bytes(&[
+            0xBE, 0x00, 0x01, // mov si, 0x100
+            0xBF, 0x00, 0x02, // mov di, 0x200
+            0xB9, 0x05, 0x00, // mov cx, 5
+            0xF3, 0xA6, // repe cmpsb
+        ]);
+        cpu.write_mem(0x100, b"abcde");
+        cpu.write_mem(0x200, b"abXde");
+        cpu.run().unwrap();
+        cpu.expect().cx(2).si(0x103).di(0x203).zf(false);
+
+        // repne scasb stops on the first match, here the second byte
+        let mut cpu = Cpu::from_bytes(&[
+            0xBF, 0x00, 0x02, // mov di, 0x200
+            0xB0, b'b', // mov al, 'b'
+            0xB9, 0x05, 0x00, // mov cx, 5
+            0xF2, 0xAE, // repne scasb
+        ]);
+        cpu.write_mem(0x200, b"abcde");
+        cpu.run().unwrap();
+        cpu.expect().cx(3).di(0x202).zf(true);
+    }
+
+    #[test]
+    fn std_makes_stosb_walk_backward_and_cld_forward_again() {
+        let cpu = run(&[
+            0xFD, // std
+            0xBF, 0x02, 0x02, // mov di, 0x202
+            0xB0, 0x07, // mov al, 7
+            0xAA, 0xAA, 0xAA, // stosb; stosb; stosb
+        ]);
+        assert!(cpu.df);
+        cpu.expect().mem(0x1FF, &[0, 7, 7, 7, 0]).di(0x1FF);
+
+        // std; cld; mov di, 0x200; stosb
+        let cpu = run(&[0xFD, 0xFC, 0xBF, 0x00, 0x02, 0xAA]);
+        assert!(!cpu.df);
+        cpu.expect().di(0x201);
+    }
+
+    #[test]
+    fn carry_and_interrupt_flag_instructions() {
+        // stc; cmc
+        run(&[0xF9, 0xF5]).expect().cf(false);
+        // stc; cmc; cmc
+        run(&[0xF9, 0xF5, 0xF5]).expect().cf(true);
+        // stc; clc
+        run(&[0xF9, 0xF8]).expect().cf(false);
+        // clc
+        run(&[0xF8]).expect().cf(false);
+        // sti
+        assert!(run(&[0xFB]).if_);
+        // sti; cli
+        assert!(!run(&[0xFB, 0xFA]).if_);
+    }
+
+    #[test]
+    fn popf_restores_the_flags_pushf_saved() {
+        let cpu = run(&[
+            0xBC, 0x00, 0x01, // mov sp, 0x100
+            0x31, 0xC0, // xor ax, ax
+            0xF9, // stc
+            0xFD, // std
+            0x9C, // pushf
+            0xF8, // clc
+            0xFC, // cld
+            0x0D, 0x01, 0x00, // or ax, 1
+            0x9D, // popf
+        ]);
+        cpu.expect()
+            .sp(0x100)
+            .mem(0xFE, &[0x47, 0xF4])
+            .cf(true)
+            .zf(true)
+            .pf(true)
+            .sf(false);
+        assert!(cpu.df);
+    }
+
+    #[test]
+    fn lahf_and_sahf_move_the_low_flags_byte_through_ah() {
+        // mov ah, 0xFF; sahf
+        run(&[0xB4, 0xFF, 0x9E])
+            .expect()
+            .cf(true)
+            .pf(true)
+            .af(true)
+            .zf(true)
+            .sf(true)
+            .of(false);
+        // mov al, 0x80; or al, al; stc; lahf
+        run(&[0xB0, 0x80, 0x08, 0xC0, 0xF9, 0x9F])
+            .expect()
+            .ax(0x8380);
+    }
+
+    #[test]
+    fn flags_word_round_trips_through_the_bit_layout() {
+        let flags = Flags {
+            cf: true,
+            zf: true,
+            of: true,
+            df: true,
+            ..Flags::default()
+        };
+        assert_eq!(flags.to_word(), 0xF002 | 0x0001 | 0x0040 | 0x0800 | 0x0400);
+        assert_eq!(Flags::from_word(flags.to_word()), flags);
+
+        let mut cpu = Cpu::from_bytes(&[]);
+        cpu.set_flags_word(0x0AD5);
+        assert_eq!(cpu.flags_word(), 0xFAD7);
+        assert_eq!(
+            cpu.flags(),
+            Flags {
+                cf: true,
+                pf: true,
+                af: true,
+                zf: true,
+                sf: true,
+                if_: true,
+                of: true,
+                df: false,
+            }
+        );
+        cpu.set_flags_word(0);
+        assert_eq!(cpu.flags(), Flags::default());
+        assert_eq!(cpu.flags_word(), 0xF002);
+    }
+
+    #[test]
+    fn cbw_and_cwd_sign_extend_without_touching_flags() {
+        // mov al, 0x80; cbw
+        run(&[0xB0, 0x80, 0x98]).expect().ax(0xFF80);
+        // mov ax, 0xFF7F; cbw
+        run(&[0xB8, 0x7F, 0xFF, 0x98]).expect().ax(0x007F);
+        // mov ax, 0x8000; cwd
+        run(&[0xB8, 0x00, 0x80, 0x99])
+            .expect()
+            .ax(0x8000)
+            .dx(0xFFFF);
+        // mov dx, 0x1234; mov ax, 0x7FFF; stc; cwd
+        run(&[0xBA, 0x34, 0x12, 0xB8, 0xFF, 0x7F, 0xF9, 0x99])
+            .expect()
+            .ax(0x7FFF)
+            .dx(0)
+            .cf(true);
+    }
+
+    #[test]
+    fn in_and_out_go_through_the_port_bus() {
+        use std::{cell::RefCell, rc::Rc};
+
+        /// Records every write and reads back the last value written to a port
+        #[derive(Default)]
+        struct Recorder(Rc<RefCell<Vec<(u16, bool, u16)>>>);
+
+        impl PortBus for Recorder {
+            fn read(&mut self, port: u16, _word: bool) -> u16 {
+                let writes = self.0.borrow();
+                let last = writes.iter().rev().find(|(written, _, _)| *written == port);
+                last.map_or(0xFFFF, |(_, _, value)| *value)
+            }
+            fn write(&mut self, port: u16, word: bool, value: u16) {
+                self.0.borrow_mut().push((port, word, value));
+            }
+        }
+
+        let mut cpu = Cpu::from_bytes(&[
+            0xB8, 0x34, 0x12, // mov ax, 0x1234
+            0xE6, 0x60, // out 96, al
+            0xBA, 0xF8, 0x03, // mov dx, 0x3F8
+            0xEF, // out dx, ax
+            0xB8, 0x00, 0x00, // mov ax, 0
+            0xE4, 0x60, // in al, 96
+            0x89, 0xC3, // mov bx, ax
+            0xED, // in ax, dx
+            0xE4, 0x61, // in al, 97
+        ]);
+        let writes = Rc::default();
+        cpu.set_port_bus(Recorder(Rc::clone(&writes)));
+        cpu.run().unwrap();
+        assert_eq!(
+            *writes.borrow(),
+            [(0x60, false, 0x34), (0x3F8, true, 0x1234)]
+        );
+        cpu.expect().bx(0x34).ax(0x12FF);
+
+        // Without a bus, in reads 0: mov ax, 0xFFFF; in ax, 96
+        run(&[0xB8, 0xFF, 0xFF, 0xE5, 0x60]).expect().ax(0);
+    }
+
+    #[test]
+    fn int_saves_flags_cs_and_ip_and_jumps_through_the_vector_table() {
+        // mov sp, 0x100; sti; stc; int 0x21; hlt, with the handler at 0x40 halting
+        let mut code = vec![0xBC, 0x00, 0x01, 0xFB, 0xF9, 0xCD, 0x21, 0xF4];
+        code.resize(0x40, 0x90);
+        code.push(0xF4);
+        let mut cpu = Cpu::load_code_and_data(&code, &[(0x21 * 4, &[0x40, 0, 0, 0])]);
+        assert_eq!(cpu.run().unwrap().reason, StopReason::Halted);
+        assert_eq!(cpu.ip(), 0x41);
+        assert!(!cpu.if_);
+        // IP, CS and then the flags word with CF and IF set
+        cpu.expect()
+            .sp(0xFA)
+            .mem(0xFA, &[0x07, 0x00, 0x00, 0x00, 0x03, 0xF2])
+            .cf(true);
+    }
+
+    #[test]
+    fn int3_and_into_use_vectors_3_and_4() {
+        // int3; mov bx, 1; hlt, with the handler at 0x40 doing mov cx, 3; iret
+        let mut code = vec![0xCC, 0xBB, 0x01, 0x00, 0xF4];
+        code.resize(0x40, 0x90);
+        code.extend([0xB9, 0x03, 0x00, 0xCF]);
+        let mut cpu = Cpu::load_code_and_data(&code, &[(3 * 4, &[0x40, 0, 0, 0])]);
+        cpu.run().unwrap();
+        cpu.expect().cx(3).bx(1).sp(0);
+
+        // mov al, 0x7f; add al, 1; into; hlt, with the handler at 0x40 doing
+        // mov cx, 4; iret
+        let mut code = vec![0xB0, 0x7F, 0x04, 0x01, 0xCE, 0xF4];
+        code.resize(0x40, 0x90);
+        code.extend([0xB9, 0x04, 0x00, 0xCF]);
+        let mut cpu = Cpu::load_code_and_data(&code, &[(4 * 4, &[0x40, 0, 0, 0])]);
+        assert_eq!(cpu.run().unwrap().reason, StopReason::Halted);
+        cpu.expect().cx(4).of(true);
+
+        // into does nothing while OF is clear, even with no vector installed
+        let mut cpu = Cpu::from_bytes(&[0xB0, 0x01, 0x04, 0x01, 0xCE]);
+        cpu.run().unwrap();
+        cpu.expect().ax(2).sp(0);
+    }
+
+    #[test]
+    fn hexdump_shows_a_string_extended_by_a_stosb_loop() {
+        let mut cpu = Cpu::from_bytes(&[
+            0xBF, 0x0D, 0x01, // mov di, 0x10D
+            0xB0, b'!', // mov al, '!'
+            0xB9, 0x05, 0x00, // mov cx, 5
+            0xAA, // stosb
+            0xE2, 0xFD, // loop $-1
+        ]);
+        cpu.write_mem(0x100, b"Hello, world");
+        cpu.run().unwrap();
+        assert_eq!(cpu.read_mem(0x10C, 6), b"\0!!!!!");
+        assert_eq!(
+            cpu.hexdump(0x100, 20),
+            format!(
+                "0100  48 65 6C 6C 6F 2C 20 77 6F 72 6C 64 00 21 21 21  Hello, world.!!!\n\
+                 0110  {:<49}!!..\n",
+                "21 21 00 00"
+            )
+        );
+    }
+
+    #[test]
+    fn seek_errors_in_the_instruction_stream_are_decode_errors() {
+        /// Reads fine but can't move backwards, like a pipe
+        struct Unseekable(Cursor<Vec<u8>>);
+
+        impl io::Read for Unseekable {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.0.read(buf)
+            }
+        }
+
+        impl CodeSource for Unseekable {
+            fn position(&mut self) -> io::Result<u64> {
+                Ok(self.0.position())
+            }
+            fn seek_to(&mut self, _position: u64) -> io::Result<()> {
+                Err(io::ErrorKind::Unsupported.into())
+            }
+        }
+
+        // nop; jmp $-1
+        let mut cpu = Cpu::new(Unseekable(Cursor::new(vec![0x90, 0xEB, 0xFD])));
+        assert_eq!(
+            cpu.run(),
+            Err(CpuError::Decode {
+                error: DecodeError::Io(io::ErrorKind::Unsupported),
+                ip: 0
+            })
+        );
+    }
+
+    #[test]
+    fn storing_into_an_immediate_is_an_error_not_a_panic() {
+        let five = Immediate {
+            data: 5,
+            w: Some(1),
+        };
+        let mut cpu = Cpu::from_bytes(&[]);
+        assert_eq!(
+            cpu.execute_instruction(Instruction::mov(five.clone(), Register::AX)),
+            Err(CpuError::Unimplemented {
+                opcode: 0,
+                mnemonic: "mov",
+                ip: 0
+            })
+        );
+        assert_eq!(
+            cpu.execute_instruction(Instruction::Inc(five.into(), 16, None)),
+            Err(CpuError::Unimplemented {
+                opcode: 0,
+                mnemonic: "inc",
+                ip: 0
+            })
+        );
+    }
+
+    #[test]
+    fn jumps_into_the_middle_of_an_instruction_decode_from_the_target_byte() {
+        // mov ax, 0x04EB; jmp 1, landing on the immediate's EB 04, a jmp $+6 to mov bx, 1
+        let mut cpu =
+            Cpu::from_bytes(&[0xB8, 0xEB, 0x04, 0xEB, 0xFC, 0x90, 0x90, 0xBB, 0x01, 0x00]);
+        let mut executed = Vec::new();
+        while let Some(instruction) = cpu.step().unwrap() {
+            executed.push(instruction.to_string());
+        }
+        assert_eq!(
+            executed,
+            ["mov ax, 1259", "jmp $-2", "jmp $+6", "mov bx, 1"]
+        );
+        cpu.expect().ax(0x04EB).bx(1);
+    }
+
+    #[test]
+    fn polling_the_bios_tick_count_terminates() {
+        let mut cpu = Cpu::from_bytes(&[
+            0x8B, 0x1E, 0x6C, 0x04, // mov bx, [0x46c]
+            0x39, 0x1E, 0x6C, 0x04, // cmp [0x46c], bx
+            0x74, 0xFA, // je $-4
+        ]);
+        cpu.set_timer_interval(Some(5));
+        let outcome = cpu.run_with_limit(100).unwrap();
+        assert_eq!(outcome.reason, StopReason::EndOfProgram);
+        cpu.expect().mem(0x46C, &[1, 0, 0, 0]).bx(0);
+
+        // Without an interval the count never moves
+        let mut cpu =
+            Cpu::from_bytes(&[0x8B, 0x1E, 0x6C, 0x04, 0x39, 0x1E, 0x6C, 0x04, 0x74, 0xFA]);
+        let outcome = cpu.run_with_limit(100).unwrap();
+        assert_eq!(outcome.reason, StopReason::StepLimit);
+    }
+
+    #[test]
+    fn timer_interrupts_wake_hlt_while_if_is_set() {
+        // sti; hlt; hlt; mov bx, cx; cli; hlt, with an INT 8 handler at 0x40 doing
+        // inc cx; iret
+        let mut code = vec![0xFB, 0xF4, 0xF4, 0x89, 0xCB, 0xFA, 0xF4];
+        code.resize(0x40, 0x90);
+        code.extend([0x41, 0xCF]);
+        let mut cpu = Cpu::load_code_and_data(&code, &[(8 * 4, &[0x40, 0, 0, 0])]);
+        cpu.set_timer_interval(Some(3));
+        cpu.set_timer_interrupt(true);
+        let outcome = cpu.run_with_limit(100).unwrap();
+        assert_eq!(outcome.reason, StopReason::Halted);
+        assert_eq!(outcome.ip, 7);
+        cpu.expect().bx(2).sp(0);
+
+        // A tick while IF is clear waits for sti: mov ax, 1 three times; mov bx, cx;
+        // sti; mov dx, cx; cli; hlt, with the handler at 0x40 doing inc cx; iret. The
+        // tick after mov bx, cx is taken after sti, the next one after mov dx, cx.
+        let mut code = vec![
+            0xB8, 0x01, 0x00, 0xB8, 0x01, 0x00, 0xB8, 0x01, 0x00, 0x89, 0xCB, 0xFB, 0x89, 0xCA,
+            0xFA, 0xF4,
+        ];
+        code.resize(0x40, 0x90);
+        code.extend([0x41, 0xCF]);
+        let mut cpu = Cpu::load_code_and_data(&code, &[(8 * 4, &[0x40, 0, 0, 0])]);
+        cpu.set_timer_interval(Some(4));
+        cpu.set_timer_interrupt(true);
+        cpu.run_with_limit(100).unwrap();
+        cpu.expect().bx(0).dx(1).cx(2);
+    }
+
+    #[test]
+    fn run_quiet_and_run_verbose_put_the_installed_tracer_back() {
+        use std::{cell::Cell, rc::Rc};
+
+        struct Count(Rc<Cell<usize>>);
+
+        impl Tracer for Count {
+            fn executed(&mut self, _state: &CpuState) {
+                self.0.set(self.0.get() + 1);
+            }
+        }
+
+        // nop; nop; hlt
+        let mut cpu = Cpu::from_bytes(&[0x90, 0x90, 0xF4]);
+        let count = Rc::new(Cell::new(0));
+        cpu.set_tracer(Count(Rc::clone(&count)));
+        cpu.step().unwrap();
+        assert_eq!(cpu.run_quiet().unwrap().reason, StopReason::Halted);
+        assert_eq!(count.get(), 1);
+        assert_eq!(Rc::strong_count(&count), 2);
+        // Already halted, so the listing has nothing to print
+        assert_eq!(cpu.run_verbose().unwrap().instructions, 0);
+        assert_eq!(Rc::strong_count(&count), 2);
+    }
+
+    #[test]
+    fn programs_larger_than_a_segment_are_rejected() {
+        let too_large = vec![0x90; MAX_PROGRAM_SIZE as usize + 1];
+        assert!(matches!(
+            Cpu::load(Cursor::new(too_large)),
+            Err(LoadError::ProgramTooLarge {
+                actual: 0x10001,
+                max: MAX_PROGRAM_SIZE,
+            })
+        ));
+        assert!(Cpu::load(Cursor::new(vec![0x90; MAX_PROGRAM_SIZE as usize])).is_ok());
+
+        // Only the bytes from the current position on are the program
+        let mut skipped = Cursor::new(vec![0x90; MAX_PROGRAM_SIZE as usize + 4]);
+        skipped.set_position(4);
+        assert!(Cpu::load(skipped).is_ok());
+    }
+
+    #[test]
+    fn flags_and_registers_clear_independently() {
+        // mov ax, 5; stc; std; sti
+        let mut cpu = run(&[0xB8, 0x05, 0x00, 0xF9, 0xFD, 0xFB]);
+        cpu.clear_flags();
+        assert_eq!(cpu.flags(), Flags::default());
+        assert_eq!(cpu.register(Register::AX), 5);
+
+        let mut cpu = run(&[0xB8, 0x05, 0x00, 0xF9, 0xFD, 0xFB]);
+        let flags = cpu.flags();
+        cpu.clear_registers();
+        assert_eq!(cpu.register(Register::AX), 0);
+        assert_eq!(cpu.flags(), flags);
+        assert!(flags.cf && flags.df && flags.if_);
+    }
+
+    #[test]
+    fn odd_word_accesses_are_reported_when_asked() {
+        use std::{cell::RefCell, rc::Rc};
+
+        /// Logs `(ip, address, write)` of each misaligned access
+        struct Misaligned(Rc<RefCell<Vec<(u16, u32, bool)>>>);
+
+        impl Tracer for Misaligned {
+            fn event(&mut self, event: &Event) {
+                if let Event::MisalignedAccess { ip, address, write } = event {
+                    self.0.borrow_mut().push((*ip, *address, *write));
+                }
+            }
+        }
+
+        let code = [
+            0xBB, 0x01, 0x00, // mov bx, 1
+            0x89, 0x07, // mov [bx], ax
+            0x88, 0x07, // mov [bx], al
+            0x8B, 0x4F, 0x01, // mov cx, [bx + 1]
+            0x8B, 0x57, 0x02, // mov dx, [bx + 2]
+        ];
+        let log = Rc::default();
+        let mut cpu = Cpu::from_bytes(&code);
+        cpu.set_tracer(Misaligned(Rc::clone(&log)));
+        cpu.run().unwrap();
+        assert!(log.borrow().is_empty());
+
+        let mut cpu = Cpu::from_bytes(&code);
+        cpu.set_tracer(Misaligned(Rc::clone(&log)));
+        cpu.set_report_misaligned(true);
+        cpu.run().unwrap();
+        assert_eq!(*log.borrow(), [(0x0003, 1, true), (0x000A, 3, false)]);
+    }
+
+    #[test]
+    fn memory_api_wraps_within_segment_zero() {
+        let mut cpu = Cpu::from_bytes(&[0xF4]);
+        cpu.write_mem(0xFFFE, &[1, 2, 3, 4]);
+        assert_eq!(cpu.read_mem(0xFFFE, 4), [1, 2, 3, 4]);
+        assert_eq!(cpu.read_mem(0x0000, 2), [3, 4]);
+        assert_eq!(cpu.memory()[0x10000], 0);
+        assert_eq!(
+            cpu.hexdump(0xFFFE, 4),
+            format!("FFFE  {:<49}....\n", "01 02 03 04")
+        );
     }
 }
@@ -1,164 +1,624 @@
-use std::io::{BufRead, Seek, SeekFrom};
+use std::{
+    fmt::Display,
+    io::{self, BufRead, Cursor, Read, Seek},
+};
 
-use crate::instruction::{Immediate, Instruction, JumpType, Location, Memory, Register};
+use crate::instruction::{
+    Immediate, Instruction, JumpType, Location, Memory, Port, Register, Repeat, ShiftCount,
+    ShiftType, StringType,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Operand/address-size override prefix (0x66/0x67), which only exists on the 386 and later
+    UnsupportedPrefix(u8),
+    /// The opcode only accepts a memory operand but mod selected a register
+    RegisterOperand(u8),
+    /// No instruction, built in or custom, starts with this byte
+    UnknownOpcode(u8),
+    /// The input ended before an instruction
+    UnexpectedEof,
+    /// The input ended right after this prefix, with no instruction for it to apply to
+    DanglingPrefix(u8),
+    /// Finding or moving to a position in the instruction stream failed
+    Io(io::ErrorKind),
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::UnsupportedPrefix(byte) => write!(
+                f,
+                "unsupported prefix {:#04x}, this looks like 32-bit code",
+                byte
+            ),
+            DecodeError::RegisterOperand(opcode) => {
+                write!(f, "opcode {:#04x} requires a memory operand", opcode)
+            }
+            DecodeError::UnknownOpcode(opcode) => write!(f, "unknown opcode {:#04x}", opcode),
+            DecodeError::UnexpectedEof => write!(f, "unexpected end of input"),
+            DecodeError::DanglingPrefix(prefix) => {
+                write!(f, "prefix {:#04x} at end of input", prefix)
+            }
+            DecodeError::Io(kind) => write!(f, "can't seek in the instruction stream: {}", kind),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<io::Error> for DecodeError {
+    fn from(error: io::Error) -> Self {
+        DecodeError::Io(error.kind())
+    }
+}
+
+/// Where the decoder reads instruction bytes from. Execution moves around the
+/// source for branches, so it must be able to revisit earlier bytes.
+pub trait CodeSource: Read {
+    /// Offset of the next byte to be read
+    fn position(&mut self) -> io::Result<u64>;
+    fn seek_to(&mut self, position: u64) -> io::Result<()>;
+}
+
+impl<T: BufRead + Seek> CodeSource for T {
+    fn position(&mut self) -> io::Result<u64> {
+        self.stream_position()
+    }
+    fn seek_to(&mut self, position: u64) -> io::Result<()> {
+        self.seek(io::SeekFrom::Start(position)).map(|_| ())
+    }
+}
+
+/// Adapts a forward-only stream (a pipe, a socket) into a `CodeSource` by
+/// keeping every byte read so far, so backward branches can be replayed
+pub struct Buffered<R> {
+    inner: R,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl<R: Read> Buffered<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buffer: Vec::new(),
+            position: 0,
+        }
+    }
+    fn fill_to(&mut self, len: usize) -> io::Result<()> {
+        if self.buffer.len() < len {
+            let missing = (len - self.buffer.len()) as u64;
+            (&mut self.inner)
+                .take(missing)
+                .read_to_end(&mut self.buffer)?;
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Buffered<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.fill_to(self.position + buf.len())?;
+        let available = &self.buffer[self.position.min(self.buffer.len())..];
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+impl<R: Read> CodeSource for Buffered<R> {
+    fn position(&mut self) -> io::Result<u64> {
+        Ok(self.position as u64)
+    }
+    fn seek_to(&mut self, position: u64) -> io::Result<()> {
+        self.position = position as usize;
+        Ok(())
+    }
+}
+
+/// Decodes opcodes the built-in decoder doesn't know, e.g. undocumented ones.
+///
+/// It is called with the opcode byte already consumed and may read the operand bytes
+/// with `get_byte` and `load_two`, which then count towards `instruction_bytes`. It must
+/// not call `next_op` or move the codec, and should only consume bytes when it returns
+/// an instruction, since the codec isn't rewound before reporting `UnknownOpcode`.
+pub type CustomDecoder<T> = fn(u8, &mut Codec<T>) -> Option<Instruction>;
 
 /// Logic for decoding 8086 instructions into assembly
 /// User Manual: https://edge.edx.org/c4x/BITSPilani/EEE231/asset/8086_family_Users_Manual_1_.pdf
-
 pub struct Codec<T> {
     source: T,
+    custom: Option<CustomDecoder<T>>,
+    /// Segment override prefix of the instruction being decoded
+    segment: Option<Register>,
+    /// Repeat prefix of the instruction being decoded, ignored unless it is a string instruction
+    repeat: Option<Repeat>,
+    /// Offset and bytes of the most recently decoded instruction
+    start: u64,
+    bytes: Vec<u8>,
+    opcode: u8,
 }
 
-impl<T: BufRead + Seek> Codec<T> {
+impl<T: CodeSource> Codec<T> {
     pub fn new(source: T) -> Self {
-        Self { source }
+        Self {
+            source,
+            custom: None,
+            segment: None,
+            repeat: None,
+            start: 0,
+            bytes: Vec::new(),
+            opcode: 0,
+        }
+    }
+
+    pub fn set_custom_decoder(&mut self, decode: CustomDecoder<T>) {
+        self.custom = Some(decode);
     }
 
     pub fn get_byte(&mut self) -> Option<u8> {
         let mut buf = [0; 1];
         self.source.read_exact(&mut buf).ok()?;
+        self.bytes.push(buf[0]);
         Some(buf[0])
     }
     pub fn load_two(&mut self) -> Option<(u8, u8)> {
         let mut buf = [0; 2];
         self.source.read_exact(&mut buf).ok()?;
+        self.bytes.extend_from_slice(&buf);
         Some((buf[0], buf[1]))
     }
 
-    pub fn decode_all(self) -> Vec<Instruction> {
+    pub fn decode_all(self) -> Result<Vec<Instruction>, DecodeError> {
         self.into_iter().collect()
     }
 
-    pub fn jump(&mut self, bytes: i8) {
-        let pos = SeekFrom::Current(bytes as i64);
-        self.source.seek(pos).unwrap();
+    /// Decode everything, pairing each instruction with its address in an image loaded
+    /// at `base`, e.g. to edit and `reassemble` it
+    pub fn decode_program(mut self, base: u16) -> Result<Vec<(u16, Instruction)>, DecodeError> {
+        let mut program = Vec::new();
+        while let Some(instruction) = self.next_op()? {
+            program.push((base.wrapping_add(self.start as u16), instruction));
+        }
+        Ok(program)
+    }
+
+    /// Offset of the most recently decoded instruction
+    pub fn instruction_start(&self) -> u64 {
+        self.start
     }
 
-    pub fn next_op(&mut self) -> Option<Instruction> {
-        let b1 = self.get_byte()?;
-        // User Manual page 161
-        match b1 {
-            0b01110100 => return Some(Instruction::Jump(JumpType::Je, self.get_byte()? as i8)),
-            0b01111100 => return Some(Instruction::Jump(JumpType::Jl, self.get_byte()? as i8)),
-            0b01111110 => return Some(Instruction::Jump(JumpType::Jle, self.get_byte()? as i8)),
-            0b01110010 => return Some(Instruction::Jump(JumpType::Jb, self.get_byte()? as i8)),
-            0b01110110 => return Some(Instruction::Jump(JumpType::Jbe, self.get_byte()? as i8)),
-            0b01111010 => return Some(Instruction::Jump(JumpType::Jp, self.get_byte()? as i8)),
-            0b01110000 => return Some(Instruction::Jump(JumpType::Jo, self.get_byte()? as i8)),
-            0b01111000 => return Some(Instruction::Jump(JumpType::Js, self.get_byte()? as i8)),
-            0b01110101 => return Some(Instruction::Jump(JumpType::Jne, self.get_byte()? as i8)),
-            0b01111101 => return Some(Instruction::Jump(JumpType::Jnl, self.get_byte()? as i8)),
-            0b01111111 => return Some(Instruction::Jump(JumpType::Jnle, self.get_byte()? as i8)),
-            0b01110011 => return Some(Instruction::Jump(JumpType::Jnb, self.get_byte()? as i8)),
-            0b01110111 => return Some(Instruction::Jump(JumpType::Jnbe, self.get_byte()? as i8)),
-            0b01111011 => return Some(Instruction::Jump(JumpType::Jnp, self.get_byte()? as i8)),
-            0b01110001 => return Some(Instruction::Jump(JumpType::Jno, self.get_byte()? as i8)),
-            0b01111001 => return Some(Instruction::Jump(JumpType::Jns, self.get_byte()? as i8)),
-            0b11100010 => return Some(Instruction::Jump(JumpType::Loop, self.get_byte()? as i8)),
-            0b11100001 => {
-                return Some(Instruction::Jump(
-                    JumpType::Jnloopzs,
-                    self.get_byte()? as i8,
-                ))
+    /// Encoded bytes of the most recently decoded instruction
+    pub fn instruction_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// First byte of the most recently decoded instruction
+    pub fn opcode(&self) -> u8 {
+        self.opcode
+    }
+
+    pub fn position(&mut self) -> Result<u64, DecodeError> {
+        Ok(self.source.position()?)
+    }
+
+    pub fn jump_to(&mut self, position: u64) -> Result<(), DecodeError> {
+        Ok(self.source.seek_to(position)?)
+    }
+
+    pub fn jump(&mut self, bytes: i16) -> Result<(), DecodeError> {
+        let pos = self.position()?.wrapping_add_signed(bytes as i64);
+        self.jump_to(pos)
+    }
+
+    pub fn next_op(&mut self) -> Result<Option<Instruction>, DecodeError> {
+        self.start = self.position()?;
+        self.bytes.clear();
+        self.segment = None;
+        self.repeat = None;
+        let Some(mut b1) = self.get_byte() else {
+            return Ok(None);
+        };
+        loop {
+            if b1 & 0b11100111 == 0b00100110 {
+                self.segment = Some(Register::segment((b1 >> 3) & 0b11));
+            } else if b1 & 0b11111110 == 0b11110010 {
+                self.repeat = Some(if b1 & 1 == 1 {
+                    Repeat::Rep
+                } else {
+                    Repeat::Repne
+                });
+            } else {
+                break;
             }
-            0b11100000 => return Some(Instruction::Jump(JumpType::Loopnz, self.get_byte()? as i8)),
-            0b11100011 => return Some(Instruction::Jump(JumpType::Jcxz, self.get_byte()? as i8)),
-            0b00110111 => return Some(Instruction::Aaa),
-            0b00100111 => return Some(Instruction::Daa),
-            _ => {}
+            let Some(byte) = self.get_byte() else {
+                return Err(DecodeError::DanglingPrefix(b1));
+            };
+            b1 = byte;
         }
-
+        self.opcode = b1;
+        self.decode_opcode(b1).map(Some)
+    }
+    fn decode_opcode(&mut self, b1: u8) -> Result<Instruction, DecodeError> {
+        // User Manual page 161
+        let instruction = match b1 {
+            0b01110100 => self.decode_jump(JumpType::Je)?,
+            0b01111100 => self.decode_jump(JumpType::Jl)?,
+            0b01111110 => self.decode_jump(JumpType::Jle)?,
+            0b01110010 => self.decode_jump(JumpType::Jb)?,
+            0b01110110 => self.decode_jump(JumpType::Jbe)?,
+            0b01111010 => self.decode_jump(JumpType::Jp)?,
+            0b01110000 => self.decode_jump(JumpType::Jo)?,
+            0b01111000 => self.decode_jump(JumpType::Js)?,
+            0b01110101 => self.decode_jump(JumpType::Jne)?,
+            0b01111101 => self.decode_jump(JumpType::Jnl)?,
+            0b01111111 => self.decode_jump(JumpType::Jnle)?,
+            0b01110011 => self.decode_jump(JumpType::Jnb)?,
+            0b01110111 => self.decode_jump(JumpType::Jnbe)?,
+            0b01111011 => self.decode_jump(JumpType::Jnp)?,
+            0b01110001 => self.decode_jump(JumpType::Jno)?,
+            0b01111001 => self.decode_jump(JumpType::Jns)?,
+            0b11100010 => self.decode_jump(JumpType::Loop)?,
+            0b11100001 => self.decode_jump(JumpType::Jnloopzs)?,
+            0b11100000 => self.decode_jump(JumpType::Loopnz)?,
+            0b11100011 => self.decode_jump(JumpType::Jcxz)?,
+            0b11101011 => self.decode_jump(JumpType::Jmp)?,
+            0b11101001 => Instruction::JumpNear(self.next_word()?),
+            0b11101000 => Instruction::Call(self.next_word()?),
+            0b11000011 => Instruction::Ret(None),
+            0b11000010 => Instruction::Ret(Some(self.next_word()? as u16)),
+            0b11001011 => Instruction::RetFar(None),
+            0b11001010 => Instruction::RetFar(Some(self.next_word()? as u16)),
+            0b10011010 | 0b11101010 => {
+                let offset = self.next_word()? as u16;
+                let segment = self.next_word()? as u16;
+                if b1 == 0b10011010 {
+                    Instruction::CallFarDirect(segment, offset)
+                } else {
+                    Instruction::JumpFarDirect(segment, offset)
+                }
+            }
+            0b11110100 => Instruction::Hlt,
+            0b11111100 => Instruction::Cld,
+            0b11111101 => Instruction::Std,
+            0b11111000 => Instruction::Clc,
+            0b11111001 => Instruction::Stc,
+            0b11110101 => Instruction::Cmc,
+            0b11111010 => Instruction::Cli,
+            0b11111011 => Instruction::Sti,
+            0b10011100 => Instruction::Pushf,
+            0b10011101 => Instruction::Popf,
+            0b10011111 => Instruction::Lahf,
+            0b10011110 => Instruction::Sahf,
+            0b10011000 => Instruction::Cbw,
+            0b10011001 => Instruction::Cwd,
+            0b11100100 | 0b11100101 => {
+                Instruction::In(8 << (b1 & 1), Port::Immediate(self.next_byte()?))
+            }
+            0b11101100 | 0b11101101 => Instruction::In(8 << (b1 & 1), Port::Dx),
+            0b11100110 | 0b11100111 => {
+                Instruction::Out(Port::Immediate(self.next_byte()?), 8 << (b1 & 1))
+            }
+            0b11101110 | 0b11101111 => Instruction::Out(Port::Dx, 8 << (b1 & 1)),
+            0b11001101 => Instruction::Int(self.next_byte()?),
+            0b11001100 => Instruction::Int3,
+            0b11001110 => Instruction::Into,
+            0b11001111 => Instruction::Iret,
+            0b10100100 | 0b10100101 => self.decode_string(StringType::Movs, b1),
+            0b10100110 | 0b10100111 => self.decode_string(StringType::Cmps, b1),
+            0b10101010 | 0b10101011 => self.decode_string(StringType::Stos, b1),
+            0b10101110 | 0b10101111 => self.decode_string(StringType::Scas, b1),
+            0b00110111 => Instruction::Aaa,
+            0b00100111 => Instruction::Daa,
+            0b11010110 => Instruction::Salc,
+            0b10000100 | 0b10000101 => {
+                let (src, dest) = self.decode_register_to_memory_locations(b1)?;
+                Instruction::Test(src, dest)
+            }
+            0b10001101 => self.decode_lea(b1)?,
+            0b10000110 | 0b10000111 => {
+                let (src, dest) = self.decode_register_to_memory_locations(b1)?;
+                Instruction::Xchg(src, dest)
+            }
+            0b10010000..=0b10010111 => Instruction::Xchg(
+                Location::Register(Register::new(b1 & 0b111, 1)),
+                Location::Register(Register::AX),
+            ),
+            0b10101000 | 0b10101001 => {
+                let w = b1 & 1;
+                let immediate = Location::Immediate(self.get_immediate_data(w)?);
+                let reg = Location::Register(if w == 1 { Register::AX } else { Register::AL });
+                Instruction::Test(immediate, reg)
+            }
+            0b11010000..=0b11010011 => self.decode_shift(b1)?,
+            0b11110110 | 0b11110111 => self.decode_group3(b1)?,
+            0b11111110 => self.decode_group4(b1)?,
+            0b11111111 => self.decode_group5(b1)?,
+            0b10001111 => self.decode_pop_memory(b1)?,
+            0b00000110 | 0b00001110 | 0b00010110 | 0b00011110 => {
+                let segment = Register::segment((b1 >> 3) & 0b11);
+                Instruction::Push(Location::Register(segment))
+            }
+            0b00000111 | 0b00010111 | 0b00011111 => {
+                let segment = Register::segment((b1 >> 3) & 0b11);
+                Instruction::Pop(Location::Register(segment))
+            }
+            0x66 | 0x67 => return Err(DecodeError::UnsupportedPrefix(b1)),
+            #[cfg(feature = "target_186")]
+            0b01100010 => self.decode_bound(b1)?,
+            _ => self.decode_by_prefix(b1)?,
+        };
+        Ok(instruction)
+    }
+    fn decode_by_prefix(&mut self, b1: u8) -> Result<Instruction, DecodeError> {
         let prefix = b1 >> 4;
 
-        let instruction = match prefix {
-            0b1011 => self.decode_immediate_to_register(b1),
+        Ok(match prefix {
+            0b1011 => self.decode_immediate_to_register(b1)?,
             0b1000 => {
                 if b1 >> 2 == 0b100000 {
-                    self.decode_arithmetic_immediate_to_register_memory(b1)
+                    self.decode_arithmetic_immediate_to_register_memory(b1)?
+                } else if b1 & 0b11111101 == 0b10001100 {
+                    self.decode_segment_move(b1)?
                 } else {
-                    self.decode_register_to_memory(b1)
+                    self.decode_register_to_memory(b1)?
                 }
             }
-            0b1100 => self.decode_immediate_to_register_memory(b1),
-            0b1010 => self.decode_accumulator(b1),
-            0b0000 | 0b0010 | 0b0011 => {
+            // Only some opcodes of these rows are built in, the rest may be custom
+            0b1100 if b1 & 0b11111110 == 0b11000110 => {
+                self.decode_immediate_to_register_memory(b1)?
+            }
+            0b1010 if b1 & 0b11111100 == 0b10100000 => self.decode_accumulator(b1)?,
+            // Low bits 110 and 111 are the segment and BCD opcodes matched above
+            0b0000..=0b0011 if b1 & 0b110 != 0b110 => {
                 if (b1 >> 2) & 1 == 1 {
-                    self.decode_arithmetic_immediate_to_accumulator(b1)
+                    self.decode_arithmetic_immediate_to_accumulator(b1)?
                 } else {
-                    self.decode_arithmetic_register_memory(b1)
+                    self.decode_arithmetic_register_memory(b1)?
                 }
             }
             0b0100 => {
                 if (b1 >> 3) & 1 == 1 {
-                    Instruction::Dec(Location::Register(Register::new(b1 & 0b111, 1)), None)
+                    Instruction::Dec(Location::Register(Register::new(b1 & 0b111, 1)), 16, None)
                 } else {
-                    Instruction::Inc(Location::Register(Register::new(b1 & 0b111, 1)), None)
+                    Instruction::Inc(Location::Register(Register::new(b1 & 0b111, 1)), 16, None)
                 }
             }
-            _ => unreachable!(),
+            0b0101 => {
+                let reg = Location::Register(Register::new(b1 & 0b111, 1));
+                if (b1 >> 3) & 1 == 1 {
+                    Instruction::Pop(reg)
+                } else {
+                    Instruction::Push(reg)
+                }
+            }
+            _ => match self.custom {
+                Some(decode) => decode(b1, self).ok_or(DecodeError::UnknownOpcode(b1))?,
+                None => return Err(DecodeError::UnknownOpcode(b1)),
+            },
+        })
+    }
+    /// Like `get_byte`, but running out of input inside an instruction is an error
+    fn next_byte(&mut self) -> Result<u8, DecodeError> {
+        self.get_byte().ok_or(DecodeError::UnexpectedEof)
+    }
+    /// A little-endian word, see `next_byte`
+    fn next_word(&mut self) -> Result<i16, DecodeError> {
+        let (low, high) = self.load_two().ok_or(DecodeError::UnexpectedEof)?;
+        Ok(i16::from_le_bytes([low, high]))
+    }
+    fn decode_jump(&mut self, ty: JumpType) -> Result<Instruction, DecodeError> {
+        Ok(Instruction::Jump(ty, self.next_byte()? as i8))
+    }
+    #[cfg(feature = "target_186")]
+    fn decode_bound(&mut self, b1: u8) -> Result<Instruction, DecodeError> {
+        let b2 = self.next_byte()?;
+        let md = b2 >> 6;
+        if md == 0b11 {
+            return Err(DecodeError::RegisterOperand(b1));
+        }
+        let reg = Register::new((b2 >> 3) & 0b111, 1);
+        Ok(Instruction::Bound(
+            reg,
+            self.get_memory_location(b2 & 0b111, md)?,
+        ))
+    }
+    fn decode_string(&mut self, ty: StringType, b1: u8) -> Instruction {
+        let width = 8 << (b1 & 1);
+        Instruction::StringOp(ty, width, self.segment.clone(), self.repeat.take())
+    }
+    /// 0x8D, always a 16-bit register and a memory operand
+    fn decode_lea(&mut self, b1: u8) -> Result<Instruction, DecodeError> {
+        let b2 = self.next_byte()?;
+        let md = b2 >> 6;
+        if md == 0b11 {
+            return Err(DecodeError::RegisterOperand(b1));
+        }
+        let dest = Register::new((b2 >> 3) & 0b111, 1);
+        Ok(Instruction::Lea(
+            dest,
+            self.get_memory_location(b2 & 0b111, md)?,
+        ))
+    }
+    /// 0xD0-0xD3, shifting by 1 or by CL as the reg field selects
+    fn decode_shift(&mut self, b1: u8) -> Result<Instruction, DecodeError> {
+        let b2 = self.next_byte()?;
+        let w = b1 & 1;
+        let md = b2 >> 6;
+        let rm = b2 & 0b111;
+        let ty = match (b2 >> 3) & 0b111 {
+            0b000 => ShiftType::Rol,
+            0b001 => ShiftType::Ror,
+            0b010 => ShiftType::Rcl,
+            0b011 => ShiftType::Rcr,
+            0b100 => ShiftType::Shl,
+            0b101 => ShiftType::Shr,
+            0b111 => ShiftType::Sar,
+            _ => return Err(DecodeError::UnknownOpcode(b1)),
         };
-
-        Some(instruction)
+        let dest = if md == 0b11 {
+            Location::Register(Register::new(rm, w))
+        } else {
+            Location::Memory(self.get_memory_location(rm, md)?)
+        };
+        let count = if (b1 >> 1) & 1 == 1 {
+            ShiftCount::Cl
+        } else {
+            ShiftCount::One
+        };
+        let width = if w == 1 { 16 } else { 8 };
+        Ok(Instruction::Shift(ty, dest, width, count))
     }
-    fn generate_displacement_value(&mut self, w: u8) -> i16 {
-        match w {
-            1 => {
-                let bytes = self.load_two().unwrap();
-                ((bytes.1 as i16) << 8) + bytes.0 as i16
+    /// The 0xF6/0xF7 group, selected by the reg field
+    fn decode_group3(&mut self, b1: u8) -> Result<Instruction, DecodeError> {
+        let b2 = self.next_byte()?;
+        let w = b1 & 1;
+        let width = if w == 1 { 16 } else { 8 };
+        let md = b2 >> 6;
+        let rm = b2 & 0b111;
+        let operand = if md == 0b11 {
+            Location::Register(Register::new(rm, w))
+        } else {
+            Location::Memory(self.get_memory_location(rm, md)?)
+        };
+        Ok(match (b2 >> 3) & 0b111 {
+            // 001 is an undocumented alias of 000
+            0b000 | 0b001 => {
+                let mut data = self.get_immediate_data(w)?;
+                if md != 0b11 {
+                    data.set_w(Some(w));
+                }
+                Instruction::Test(Location::Immediate(data), operand)
             }
-            0 => self.get_byte().unwrap() as i8 as i16,
-            _ => unreachable!(),
+            0b010 => Instruction::Not(operand, width),
+            0b011 => Instruction::Neg(operand, width),
+            0b100 => Instruction::Mul(operand, width),
+            0b101 => Instruction::Imul(operand, width),
+            0b110 => Instruction::Div(operand, width),
+            _ => Instruction::Idiv(operand, width),
+        })
+    }
+    /// 0xFE, whose only operations are `inc r/m8` and `dec r/m8`
+    fn decode_group4(&mut self, b1: u8) -> Result<Instruction, DecodeError> {
+        let b2 = self.next_byte()?;
+        let md = b2 >> 6;
+        let rm = b2 & 0b111;
+        let operand = if md == 0b11 {
+            Location::Register(Register::new(rm, 0))
+        } else {
+            Location::Memory(self.get_memory_location(rm, md)?)
+        };
+        match (b2 >> 3) & 0b111 {
+            0b000 => Ok(Instruction::Inc(operand, 8, None)),
+            0b001 => Ok(Instruction::Dec(operand, 8, None)),
+            _ => Err(DecodeError::UnknownOpcode(b1)),
         }
     }
-    fn decode_accumulator(&mut self, b1: u8) -> Instruction {
+    /// The 0xFF group, selected by the reg field
+    fn decode_group5(&mut self, b1: u8) -> Result<Instruction, DecodeError> {
+        let b2 = self.next_byte()?;
+        let md = b2 >> 6;
+        let op = (b2 >> 3) & 0b111;
+        if op == 0b000 {
+            return Ok(Instruction::Inc(self.get_word_operand(b2)?, 16, None));
+        }
+        if op == 0b001 {
+            return Ok(Instruction::Dec(self.get_word_operand(b2)?, 16, None));
+        }
+        if op == 0b110 {
+            return Ok(Instruction::Push(self.get_word_operand(b2)?));
+        }
+        if !matches!(op, 0b011 | 0b101) {
+            return Err(DecodeError::UnknownOpcode(b1));
+        }
+        if md == 0b11 {
+            return Err(DecodeError::RegisterOperand(b1));
+        }
+        let pointer = self.get_memory_location(b2 & 0b111, md)?;
+        Ok(if op == 0b011 {
+            Instruction::CallFar(pointer)
+        } else {
+            Instruction::JumpFar(pointer)
+        })
+    }
+    /// 0x8F, whose only operation is `pop r/m16`
+    fn decode_pop_memory(&mut self, b1: u8) -> Result<Instruction, DecodeError> {
+        let b2 = self.next_byte()?;
+        if (b2 >> 3) & 0b111 != 0b000 {
+            return Err(DecodeError::UnknownOpcode(b1));
+        }
+        Ok(Instruction::Pop(self.get_word_operand(b2)?))
+    }
+    /// The r/m operand of `b2` for an instruction that only operates on words
+    fn get_word_operand(&mut self, b2: u8) -> Result<Location, DecodeError> {
+        let md = b2 >> 6;
+        let rm = b2 & 0b111;
+        Ok(if md == 0b11 {
+            Location::Register(Register::new(rm, 1))
+        } else {
+            Location::Memory(self.get_memory_location(rm, md)?)
+        })
+    }
+    fn decode_accumulator(&mut self, b1: u8) -> Result<Instruction, DecodeError> {
         let opcode = b1 >> 1;
         let w = b1 & 1;
-        let displacement = self.generate_displacement_value(w);
-        let memory = Location::Memory(Memory::new(None, None, displacement));
+        // The address is a full word even when moving a byte
+        let displacement = self.next_word()?;
+        let memory = Location::Memory(Memory::with_segment(
+            None,
+            None,
+            displacement,
+            self.segment.clone(),
+        ));
         let reg = Location::Register(if w == 1 { Register::AX } else { Register::AL });
 
-        if opcode == 0b1010000 {
+        Ok(if opcode == 0b1010000 {
             Instruction::Mov(memory, reg)
         } else {
             Instruction::Mov(reg, memory)
-        }
+        })
     }
-    fn get_immediate_data(&mut self, w: u8) -> Immediate {
+    fn get_immediate_data(&mut self, w: u8) -> Result<Immediate, DecodeError> {
         let data = if w == 1 {
-            let bytes = self.load_two().unwrap();
-            ((bytes.1 as i16) << 8) + bytes.0 as i16
+            self.next_word()?
         } else {
-            self.get_byte().unwrap() as i8 as i16
+            self.next_byte()? as i8 as i16
         };
-        Immediate::new(data, None)
+        Ok(Immediate::new(data, None))
     }
-    fn decode_immediate_to_register(&mut self, b1: u8) -> Instruction {
+    fn decode_immediate_to_register(&mut self, b1: u8) -> Result<Instruction, DecodeError> {
         let w = (b1 >> 3) & 1;
         let reg = Register::new(b1 & 0b111, w);
-        let immediate = self.get_immediate_data(w);
-        Instruction::Mov(Location::Immediate(immediate), Location::Register(reg))
+        let immediate = self.get_immediate_data(w)?;
+        Ok(Instruction::Mov(
+            Location::Immediate(immediate),
+            Location::Register(reg),
+        ))
     }
 
-    fn decode_immediate_to_register_memory(&mut self, b1: u8) -> Instruction {
+    fn decode_immediate_to_register_memory(&mut self, b1: u8) -> Result<Instruction, DecodeError> {
         let w = b1 & 1;
 
-        let b2 = self.get_byte().unwrap();
+        let b2 = self.next_byte()?;
         let md = b2 >> 6;
         let rm = b2 & 0b111;
 
-        let memory = self.get_memory_location(rm, md);
-        let mut immediate = self.get_immediate_data(w);
+        let dest = if md == 0b11 {
+            Location::Register(Register::new(rm, w))
+        } else {
+            Location::Memory(self.get_memory_location(rm, md)?)
+        };
+        let mut immediate = self.get_immediate_data(w)?;
         immediate.set_w(Some(w));
-        Instruction::Mov(Location::Immediate(immediate), Location::Memory(memory))
+        Ok(Instruction::Mov(Location::Immediate(immediate), dest))
     }
 
-    fn get_memory_location(&mut self, rm: u8, md: u8) -> Memory {
+    fn get_memory_location(&mut self, rm: u8, md: u8) -> Result<Memory, DecodeError> {
         let displacement = match (md, rm) {
-            (0b10, _) | (0b00, 0b110) => {
-                let bytes = self.load_two().unwrap();
-                ((bytes.1 as i16) << 8) + bytes.0 as i16
-            }
-            (0b01, _) => self.get_byte().unwrap() as i8 as i16,
+            (0b10, _) | (0b00, 0b110) => self.next_word()?,
+            (0b01, _) => self.next_byte()? as i8 as i16,
             _ => 0i16,
         };
 
@@ -171,15 +631,21 @@ impl<T: BufRead + Seek> Codec<T> {
             (0b100, _) => (Some(Register::SI), None),
             (0b101, _) => (Some(Register::DI), None),
             (0b110, _) => (Some(Register::BP), None),
-            (0b111, _) => (Some(Register::BX), None),
-
-            _ => unreachable!(),
+            (_, _) => (Some(Register::BX), None),
         };
-        Memory::new(right_reg1, right_reg2, displacement)
+        Ok(Memory::with_segment(
+            right_reg1,
+            right_reg2,
+            displacement,
+            self.segment.clone(),
+        ))
     }
 
-    fn decode_register_to_memory_locations(&mut self, b1: u8) -> (Location, Location) {
-        let b2 = self.get_byte().unwrap();
+    fn decode_register_to_memory_locations(
+        &mut self,
+        b1: u8,
+    ) -> Result<(Location, Location), DecodeError> {
+        let b2 = self.next_byte()?;
 
         let d = (b1 & 0b10) >> 1;
         let w = b1 & 0b1;
@@ -187,7 +653,7 @@ impl<T: BufRead + Seek> Codec<T> {
         let reg = (b2 >> 3) & 0b111;
         let rm = b2 & 0b111; // r/m
 
-        match (md, w) {
+        Ok(match (md, w) {
             (0b11, w) => {
                 let r1 = Register::new(reg, w);
                 let r2 = Register::new(rm, w);
@@ -197,30 +663,59 @@ impl<T: BufRead + Seek> Codec<T> {
             (md, w) => {
                 let r1 = Location::Register(Register::new(reg, w));
 
-                let r2 = Location::Memory(self.get_memory_location(rm, md));
-                let (src, dest) = if d == 1 { (r2, r1) } else { (r1, r2) };
-                (src, dest)
+                let r2 = Location::Memory(self.get_memory_location(rm, md)?);
+                if d == 1 {
+                    (r2, r1)
+                } else {
+                    (r1, r2)
+                }
             }
-        }
+        })
+    }
+    /// `mov` to or from a segment register, the other operand is always a word
+    fn decode_segment_move(&mut self, b1: u8) -> Result<Instruction, DecodeError> {
+        let b2 = self.next_byte()?;
+        let segment = Location::Register(Register::segment((b2 >> 3) & 0b11));
+        let other = self.get_word_operand(b2)?;
+        Ok(if (b1 >> 1) & 1 == 1 {
+            Instruction::Mov(other, segment)
+        } else {
+            Instruction::Mov(segment, other)
+        })
+    }
+    fn decode_register_to_memory(&mut self, b1: u8) -> Result<Instruction, DecodeError> {
+        let (l1, l2) = self.decode_register_to_memory_locations(b1)?;
+        Ok(Instruction::Mov(l1, l2))
     }
-    fn decode_register_to_memory(&mut self, b1: u8) -> Instruction {
-        let (l1, l2) = self.decode_register_to_memory_locations(b1);
-        Instruction::Mov(l1, l2)
-    }
-    fn decode_arithmetic_register_memory(&mut self, b1: u8) -> Instruction {
-        let (l1, l2) = self.decode_register_to_memory_locations(b1);
-        let arithmetic_opcode = (b1 >> 3) & 0b111;
-        match arithmetic_opcode {
-            0b000 => Instruction::Add(l1, l2),
-            0b101 => Instruction::Sub(l1, l2),
-            0b111 => Instruction::Cmp(l1, l2),
-            _ => unreachable!(),
+    /// The ALU operation selected by 3 bits of the opcode or reg field
+    fn arithmetic(op: u8, src: Location, dest: Location) -> Instruction {
+        match op & 0b111 {
+            0b000 => Instruction::Add(src, dest),
+            0b001 => Instruction::Or(src, dest),
+            0b010 => Instruction::Adc(src, dest),
+            0b011 => Instruction::Sbb(src, dest),
+            0b100 => Instruction::And(src, dest),
+            0b101 => Instruction::Sub(src, dest),
+            0b110 => Instruction::Xor(src, dest),
+            _ => Instruction::Cmp(src, dest),
         }
     }
-    fn decode_arithmetic_immediate_to_register_memory(&mut self, b1: u8) -> Instruction {
-        let w = if b1 & 0b11 == 0b01 { 1 } else { 0 };
+    fn decode_arithmetic_register_memory(&mut self, b1: u8) -> Result<Instruction, DecodeError> {
+        let (l1, l2) = self.decode_register_to_memory_locations(b1)?;
+        Ok(Self::arithmetic(b1 >> 3, l1, l2))
+    }
+    fn decode_arithmetic_immediate_to_register_memory(
+        &mut self,
+        b1: u8,
+    ) -> Result<Instruction, DecodeError> {
+        // Width of the immediate data, the operand's own width is the low bit
+        let w = match b1 & 0b11 {
+            0b01 => 1,
+            // 0x80, its undocumented alias 0x82, and 0x83 whose byte is sign-extended
+            _ => 0,
+        };
 
-        let b2 = self.get_byte().unwrap();
+        let b2 = self.next_byte()?;
 
         let md = b2 >> 6;
         let rm = b2 & 0b111;
@@ -231,43 +726,31 @@ impl<T: BufRead + Seek> Codec<T> {
 
                 Location::Register(r2)
             }
-            md => Location::Memory(self.get_memory_location(rm, md)),
+            md => Location::Memory(self.get_memory_location(rm, md)?),
         };
 
-        let mut data = self.get_immediate_data(w);
+        let mut data = self.get_immediate_data(w)?;
         if md != 0b11 {
             data.set_w(Some(b1 & 1));
         }
         let immediate = Location::Immediate(data);
 
-        let arithmetic_opcode = (b2 >> 3) & 0b111;
-
-        match arithmetic_opcode {
-            0b000 => Instruction::Add(immediate, memory),
-            0b101 => Instruction::Sub(immediate, memory),
-            0b111 => Instruction::Cmp(immediate, memory),
-            _ => unreachable!(),
-        }
+        Ok(Self::arithmetic(b2 >> 3, immediate, memory))
     }
 
-    fn decode_arithmetic_immediate_to_accumulator(&mut self, b1: u8) -> Instruction {
+    fn decode_arithmetic_immediate_to_accumulator(
+        &mut self,
+        b1: u8,
+    ) -> Result<Instruction, DecodeError> {
         let w = b1 & 1;
-        let displacement = self.generate_displacement_value(w);
-        let memory = Location::Memory(Memory::new(None, None, displacement));
+        let immediate = Location::Immediate(self.get_immediate_data(w)?);
         let reg = Location::Register(if w == 1 { Register::AX } else { Register::AL });
-        let arithmetic_opcode = (b1 >> 3) & 0b111;
-
-        match arithmetic_opcode {
-            0b000 => Instruction::Add(memory, reg),
-            0b101 => Instruction::Sub(memory, reg),
-            0b111 => Instruction::Cmp(memory, reg),
-            _ => unreachable!(),
-        }
+        Ok(Self::arithmetic(b1 >> 3, immediate, reg))
     }
 }
 
-impl<T: BufRead + Seek> IntoIterator for Codec<T> {
-    type Item = Instruction;
+impl<T: CodeSource> IntoIterator for Codec<T> {
+    type Item = Result<Instruction, DecodeError>;
 
     type IntoIter = InstructionIterator<T>;
 
@@ -280,10 +763,326 @@ pub struct InstructionIterator<T> {
     codec: Codec<T>,
 }
 
-impl<T: BufRead + Seek> Iterator for InstructionIterator<T> {
-    type Item = Instruction;
+impl<T: CodeSource> Iterator for InstructionIterator<T> {
+    type Item = Result<Instruction, DecodeError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.codec.next_op()
+        self.codec.next_op().transpose()
+    }
+}
+
+/// Decode the instruction at the start of `bytes` with its length, or `None` for empty input
+pub fn decode_one(bytes: &[u8]) -> Result<Option<(Instruction, usize)>, DecodeError> {
+    let mut codec = Codec::new(Cursor::new(bytes));
+    let instruction = codec.next_op()?;
+    Ok(instruction.map(|instruction| (instruction, codec.bytes.len())))
+}
+
+/// Decode the instruction at the start of `bytes`, returning it with the bytes after it
+pub fn decode(bytes: &[u8]) -> Result<(Instruction, &[u8]), DecodeError> {
+    let (instruction, len) = decode_one(bytes)?.ok_or(DecodeError::UnexpectedEof)?;
+    Ok((instruction, &bytes[len..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The first instruction of `bytes`, as disassembled
+    fn text(bytes: &[u8]) -> String {
+        decode(bytes).unwrap().0.to_string()
+    }
+
+    #[test]
+    fn segment_moves_use_a_word_operand() {
+        assert_eq!(text(&[0x8E, 0xC0]), "mov es, ax");
+        assert_eq!(text(&[0x8C, 0x1F]), "mov [bx], ds");
+        assert_eq!(text(&[0x8C, 0xD8]), "mov ax, ds");
+        assert!(matches!(
+            decode(&[0x8E, 0xC0]).unwrap().0,
+            Instruction::Mov(
+                Location::Register(Register::AX),
+                Location::Register(Register::ES)
+            )
+        ));
+    }
+
+    #[test]
+    fn salc_is_built_in() {
+        assert_eq!(text(&[0xD6]), "salc");
+    }
+
+    #[test]
+    fn multiply_and_divide_show_only_the_explicit_operand() {
+        assert_eq!(text(&[0xF7, 0xE3]), "mul bx");
+        assert_eq!(text(&[0xF6, 0xF9]), "idiv cl");
+        assert_eq!(text(&[0xF6, 0x27]), "mul byte [bx]");
+        assert_eq!(text(&[0xF7, 0x30]), "div word [bx + si]");
+        assert_eq!(text(&[0xF7, 0x2F]), "imul word [bx]");
+    }
+
+    #[test]
+    fn opcode_82_is_an_alias_of_80() {
+        for operands in [&[0xC0, 0x05][..], &[0x2F, 0xFF], &[0x3E, 0x34, 0x12, 0x07]] {
+            let alias = [&[0x82], operands].concat();
+            let original = [&[0x80], operands].concat();
+            assert_eq!(text(&alias), text(&original));
+            assert_eq!(decode_one(&alias).unwrap().unwrap().1, alias.len());
+        }
+        assert_eq!(text(&[0x82, 0x2F, 0xFF]), "sub [bx], byte -1");
+    }
+
+    #[test]
+    fn ff_group_far_transfers_take_a_memory_pointer() {
+        assert_eq!(text(&[0xFF, 0x1E, 0x50, 0x00]), "call far [80]");
+        assert_eq!(text(&[0xFF, 0x2F]), "jmp far [bx]");
+        assert!(matches!(
+            decode(&[0xFF, 0xDE]),
+            Err(DecodeError::RegisterOperand(0xFF))
+        ));
+        assert!(matches!(
+            decode(&[0xFF, 0xEB]),
+            Err(DecodeError::RegisterOperand(0xFF))
+        ));
+    }
+
+    #[test]
+    fn prefix_at_end_of_input_is_an_error() {
+        assert!(matches!(
+            decode_one(&[0x26]),
+            Err(DecodeError::DanglingPrefix(0x26))
+        ));
+        assert!(matches!(
+            decode_one(&[0x2E, 0xF3]),
+            Err(DecodeError::DanglingPrefix(0xF3))
+        ));
+        let mut codec = Codec::new(Cursor::new(&[0x90, 0x26][..]));
+        assert!(codec.next_op().unwrap().is_some());
+        assert!(matches!(
+            codec.next_op(),
+            Err(DecodeError::DanglingPrefix(0x26))
+        ));
+    }
+
+    #[test]
+    fn adc_decodes_in_both_arithmetic_forms() {
+        assert_eq!(text(&[0x11, 0xCA]), "adc dx, cx");
+        assert_eq!(text(&[0x83, 0xD0, 0x01]), "adc ax, 1");
+        assert_eq!(text(&[0x14, 0x05]), "adc al, 5");
+    }
+
+    #[test]
+    fn sbb_decodes_in_both_arithmetic_forms() {
+        assert_eq!(text(&[0x19, 0xCA]), "sbb dx, cx");
+        assert_eq!(text(&[0x80, 0x1F, 0x02]), "sbb [bx], byte 2");
+        assert_eq!(text(&[0x1D, 0x34, 0x12]), "sbb ax, 4660");
+    }
+
+    #[test]
+    fn logical_operations_decode() {
+        assert_eq!(text(&[0x21, 0xD8]), "and ax, bx");
+        assert_eq!(text(&[0x09, 0xD8]), "or ax, bx");
+        assert_eq!(text(&[0x31, 0xD8]), "xor ax, bx");
+        assert_eq!(text(&[0x85, 0xD8]), "test ax, bx");
+        assert_eq!(text(&[0x24, 0x0F]), "and al, 15");
+        assert_eq!(text(&[0x80, 0xCB, 0x01]), "or bl, 1");
+        assert_eq!(text(&[0xA9, 0x00, 0x80]), "test ax, -32768");
+        assert_eq!(text(&[0xF6, 0x07, 0x01]), "test [bx], byte 1");
+    }
+
+    #[test]
+    fn not_and_neg_decode_with_their_width() {
+        assert_eq!(text(&[0xF7, 0xD0]), "not ax");
+        assert_eq!(text(&[0xF6, 0xD8]), "neg al");
+        assert_eq!(text(&[0xF6, 0x1F]), "neg byte [bx]");
+        assert_eq!(text(&[0xF7, 0x57, 0x02]), "not word [bx + 2]");
+    }
+
+    #[test]
+    fn shift_group_decodes_both_counts() {
+        assert_eq!(text(&[0xD1, 0xE0]), "shl ax, 1");
+        assert_eq!(text(&[0xD2, 0xF8]), "sar al, cl");
+        assert_eq!(text(&[0xD0, 0xD0]), "rcl al, 1");
+        assert_eq!(text(&[0xD3, 0x0F]), "ror word [bx], cl");
+    }
+
+    #[test]
+    fn short_and_near_jmp_keep_their_displacement_size() {
+        assert!(matches!(
+            decode(&[0xEB, 0x03]).unwrap().0,
+            Instruction::Jump(JumpType::Jmp, 3)
+        ));
+        assert!(matches!(
+            decode(&[0xE9, 0x03, 0x00]).unwrap().0,
+            Instruction::JumpNear(3)
+        ));
+        assert_eq!(text(&[0xEB, 0x03]), "jmp $+5");
+        assert_eq!(text(&[0xE9, 0xFD, 0xFF]), "jmp $+0");
+    }
+
+    #[test]
+    fn truncated_or_unknown_instructions_are_errors() {
+        assert!(matches!(
+            decode(&[0xB8, 0x34]),
+            Err(DecodeError::UnexpectedEof)
+        ));
+        assert!(matches!(
+            decode(&[0x8B, 0x87, 0x34]),
+            Err(DecodeError::UnexpectedEof)
+        ));
+        assert!(matches!(
+            decode(&[0xC4, 0x00]),
+            Err(DecodeError::UnknownOpcode(0xC4))
+        ));
+        assert!(matches!(decode_one(&[]), Ok(None)));
+    }
+
+    #[test]
+    fn only_built_in_opcodes_of_the_a_and_c_rows_decode() {
+        assert_eq!(text(&[0xC2, 0x02, 0x00]), "ret 2");
+        assert_eq!(text(&[0xC6, 0xC0, 0x05]), "mov al, byte 5");
+        assert_eq!(text(&[0xA1, 0x10, 0x00]), "mov ax, [16]");
+        for opcode in [0xAC, 0xAD, 0xC4, 0xC5] {
+            assert!(matches!(
+                decode(&[opcode, 0x00, 0x00]),
+                Err(DecodeError::UnknownOpcode(byte)) if byte == opcode
+            ));
+        }
+    }
+
+    #[test]
+    fn lea_takes_a_memory_operand() {
+        assert_eq!(text(&[0x8D, 0x5C, 0x04]), "lea bx, [si + 4]");
+        assert_eq!(text(&[0x8D, 0x06, 0x34, 0x12]), "lea ax, [4660]");
+    }
+
+    #[test]
+    fn segment_override_attaches_to_the_memory_operand() {
+        let (instruction, rest) = decode(&[0x26, 0x8B, 0x07]).unwrap();
+        assert_eq!(instruction.to_string(), "mov ax, es:[bx]");
+        assert!(rest.is_empty());
+        match instruction {
+            Instruction::Mov(Location::Memory(memory), _) => {
+                assert_eq!(memory.segment(), Register::ES);
+                assert_eq!(memory.default_segment(), Register::DS);
+            }
+            other => panic!("expected a mov from memory, got {}", other),
+        }
+        assert_eq!(text(&[0x8B, 0x46, 0x02]), "mov ax, [bp + 2]");
+        assert_eq!(text(&[0x3E, 0x8B, 0x46, 0x02]), "mov ax, ds:[bp + 2]");
+    }
+
+    #[test]
+    fn movs_and_stos_come_in_byte_and_word_forms() {
+        assert_eq!(text(&[0xA4]), "movsb");
+        assert_eq!(text(&[0xA5]), "movsw");
+        assert_eq!(text(&[0xAA]), "stosb");
+        assert_eq!(text(&[0xAB]), "stosw");
+    }
+
+    #[test]
+    fn repeat_prefixes_attach_to_the_string_instruction() {
+        let (instruction, rest) = decode(&[0xF3, 0xA5, 0x90]).unwrap();
+        assert_eq!(instruction.to_string(), "rep movsw");
+        assert_eq!(rest, [0x90]);
+        assert_eq!(text(&[0xF3, 0xA6]), "repe cmpsb");
+        assert_eq!(text(&[0xF2, 0xAE]), "repne scasb");
+    }
+
+    #[test]
+    fn flag_instructions_are_single_bytes() {
+        for (byte, mnemonic) in [
+            (0xF5, "cmc"),
+            (0xF8, "clc"),
+            (0xF9, "stc"),
+            (0xFA, "cli"),
+            (0xFB, "sti"),
+            (0xFC, "cld"),
+            (0xFD, "std"),
+        ] {
+            let bytes = [byte, 0x90];
+            let (instruction, rest) = decode(&bytes).unwrap();
+            assert_eq!(instruction.to_string(), mnemonic);
+            assert_eq!(rest, [0x90]);
+        }
+    }
+
+    #[test]
+    fn cbw_and_cwd_are_single_bytes() {
+        assert_eq!(text(&[0x98]), "cbw");
+        assert_eq!(text(&[0x99]), "cwd");
+    }
+
+    #[test]
+    fn in_and_out_take_an_immediate_port_or_dx() {
+        assert_eq!(text(&[0xE4, 0x60]), "in al, 96");
+        assert_eq!(text(&[0xED]), "in ax, dx");
+        assert_eq!(text(&[0xE7, 0x60]), "out 96, ax");
+        assert_eq!(text(&[0xEE]), "out dx, al");
+    }
+
+    #[test]
+    fn software_interrupts() {
+        assert_eq!(text(&[0xCD, 0x21]), "int 33");
+        assert_eq!(text(&[0xCC]), "int3");
+        assert_eq!(text(&[0xCE]), "into");
+        assert_eq!(text(&[0xCF]), "iret");
+    }
+
+    #[test]
+    fn operand_and_address_size_prefixes_are_386_only() {
+        for prefix in [0x66, 0x67] {
+            assert!(matches!(
+                decode_one(&[prefix, 0x89, 0xC0]),
+                Err(DecodeError::UnsupportedPrefix(byte)) if byte == prefix
+            ));
+        }
+        assert_eq!(
+            DecodeError::UnsupportedPrefix(0x66).to_string(),
+            "unsupported prefix 0x66, this looks like 32-bit code"
+        );
+    }
+
+    #[test]
+    fn buffered_replays_bytes_of_a_forward_only_stream() {
+        /// Only `Read`, like a pipe
+        struct Pipe<'a>(&'a [u8]);
+
+        impl Read for Pipe<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.0.read(buf)
+            }
+        }
+
+        // inc ax; jmp $-1
+        let mut codec = Codec::new(Buffered::new(Pipe(&[0x40, 0xEB, 0xFD])));
+        assert_eq!(codec.next_op().unwrap().unwrap().to_string(), "inc ax");
+        assert_eq!(codec.next_op().unwrap().unwrap().to_string(), "jmp $-1");
+        codec.jump(-3).unwrap();
+        assert_eq!(codec.position().unwrap(), 0);
+        assert_eq!(codec.next_op().unwrap().unwrap().to_string(), "inc ax");
+        assert_eq!(codec.instruction_start(), 0);
+    }
+
+    #[test]
+    fn custom_decoder_handles_unknown_opcodes() {
+        /// `0F nn` as `int nn`
+        fn decode_0f(opcode: u8, codec: &mut Codec<Cursor<&[u8]>>) -> Option<Instruction> {
+            if opcode != 0x0F {
+                return None;
+            }
+            codec.get_byte().map(Instruction::Int)
+        }
+
+        let mut codec = Codec::new(Cursor::new(&[0x0F, 0x21, 0x40, 0xF1][..]));
+        codec.set_custom_decoder(decode_0f);
+        assert_eq!(codec.next_op().unwrap().unwrap().to_string(), "int 33");
+        assert_eq!(codec.instruction_bytes(), [0x0F, 0x21]);
+        // Built-in opcodes never reach the custom decoder
+        assert_eq!(codec.next_op().unwrap().unwrap().to_string(), "inc ax");
+        assert!(matches!(
+            codec.next_op(),
+            Err(DecodeError::UnknownOpcode(0xF1))
+        ));
     }
 }
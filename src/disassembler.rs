@@ -0,0 +1,589 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+    io::Cursor,
+};
+
+use crate::{
+    decoder::{decode_one, Codec, DecodeError},
+    encoder::{encode, encoded_len},
+    instruction::{Instruction, JumpType, Location, Register},
+};
+
+/// How control can leave an instruction
+struct Flow {
+    falls_through: bool,
+    target: Option<u16>,
+    /// The target comes from a register or memory and can't be followed statically
+    indirect: bool,
+}
+
+fn flow(instruction: &Instruction, next: u16) -> Flow {
+    match instruction {
+        Instruction::Jump(JumpType::Jmp, disp) => Flow {
+            falls_through: false,
+            target: Some(next.wrapping_add(*disp as i16 as u16)),
+            indirect: false,
+        },
+        Instruction::JumpNear(disp) => Flow {
+            falls_through: false,
+            target: Some(next.wrapping_add(*disp as u16)),
+            indirect: false,
+        },
+        Instruction::Jump(_, disp) => Flow {
+            falls_through: true,
+            target: Some(next.wrapping_add(*disp as i16 as u16)),
+            indirect: false,
+        },
+        Instruction::JumpFar(_) => Flow {
+            falls_through: false,
+            target: None,
+            indirect: true,
+        },
+        Instruction::Call(disp) => Flow {
+            falls_through: true,
+            target: Some(next.wrapping_add(*disp as u16)),
+            indirect: false,
+        },
+        // Where it returns to was pushed by the call, which is followed anyway
+        Instruction::Ret(_) | Instruction::RetFar(_) | Instruction::Iret => Flow {
+            falls_through: false,
+            target: None,
+            indirect: false,
+        },
+        // Another segment's offsets say nothing about this image
+        Instruction::JumpFarDirect(_, _) => Flow {
+            falls_through: false,
+            target: None,
+            indirect: false,
+        },
+        Instruction::CallFarDirect(_, _) => Flow {
+            falls_through: true,
+            target: None,
+            indirect: false,
+        },
+        Instruction::CallFar(_) => Flow {
+            falls_through: true,
+            target: None,
+            indirect: true,
+        },
+        _ => Flow {
+            falls_through: true,
+            target: None,
+            indirect: false,
+        },
+    }
+}
+
+/// Decode the instruction at `address` of an image loaded at `base`,
+/// returning it with its length in bytes
+fn decode_at(bytes: &[u8], address: u16, base: u16) -> Option<(Instruction, u16)> {
+    let index = address.checked_sub(base)? as usize;
+    let (instruction, len) = decode_one(bytes.get(index..)?).ok()??;
+    Some((instruction, len as u16))
+}
+
+#[derive(Debug, Default)]
+pub struct Disassembly {
+    pub instructions: BTreeMap<u16, Instruction>,
+    /// For each decoded address, the entry points it is reachable from
+    pub reachable_from: BTreeMap<u16, Vec<u16>>,
+    /// Addresses of indirect jumps and calls, whose targets can be fed back in as entries
+    pub unresolved: Vec<u16>,
+    /// Instructions starting inside another decoded instruction, where code overlaps itself
+    pub overlapping: Vec<u16>,
+    /// Set when `MAX_VISITS` was reached before every path was followed
+    pub truncated: bool,
+}
+
+/// Upper bound on the instructions visited by one disassembly, counting every entry
+/// an instruction is reached from, so adversarial images can't make it run for long
+pub const MAX_VISITS: usize = 1 << 20;
+
+/// Decode only the code reachable from `entry` by following branches, so inline
+/// data is never mistaken for instructions. `bytes` is an image loaded at `base`
+/// and the result is keyed by address.
+pub fn disassemble_recursive(bytes: &[u8], entry: u16, base: u16) -> BTreeMap<u16, Instruction> {
+    disassemble_entries(bytes, &[entry], base).instructions
+}
+
+/// Recursive descent from several entry points, e.g. exports or interrupt vector targets.
+/// Each address is decoded at most once and followed at most once per entry, so
+/// loops and self-calls terminate.
+pub fn disassemble_entries(bytes: &[u8], entries: &[u16], base: u16) -> Disassembly {
+    let mut disassembly = Disassembly::default();
+    let mut lengths = BTreeMap::new();
+    let mut undecodable = BTreeSet::new();
+    let mut visits = 0;
+
+    'entries: for &entry in entries {
+        let mut pending = vec![entry];
+        while let Some(address) = pending.pop() {
+            if undecodable.contains(&address)
+                || disassembly
+                    .reachable_from
+                    .get(&address)
+                    .is_some_and(|entries| entries.contains(&entry))
+            {
+                continue;
+            }
+            if visits == MAX_VISITS {
+                disassembly.truncated = true;
+                break 'entries;
+            }
+            visits += 1;
+            let len = match lengths.get(&address) {
+                Some(&len) => len,
+                None => {
+                    let Some((instruction, len)) = decode_at(bytes, address, base) else {
+                        undecodable.insert(address);
+                        continue;
+                    };
+                    disassembly.instructions.insert(address, instruction);
+                    lengths.insert(address, len);
+                    len
+                }
+            };
+            let flow = flow(
+                &disassembly.instructions[&address],
+                address.wrapping_add(len),
+            );
+            if flow.falls_through {
+                pending.push(address.wrapping_add(len));
+            }
+            if let Some(target) = flow.target {
+                pending.push(target);
+            }
+            if flow.indirect && !disassembly.unresolved.contains(&address) {
+                disassembly.unresolved.push(address);
+            }
+            disassembly
+                .reachable_from
+                .entry(address)
+                .or_default()
+                .push(entry);
+        }
+    }
+    for (&address, &len) in &lengths {
+        let end = address.saturating_add(len);
+        for (&inner, _) in lengths.range(address.saturating_add(1)..end) {
+            if !disassembly.overlapping.contains(&inner) {
+                disassembly.overlapping.push(inner);
+            }
+        }
+    }
+    disassembly.overlapping.sort();
+    disassembly
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XrefKind {
+    /// A jump or call transfers control to the address
+    Code,
+    /// A memory operand's displacement is the address, directly or as a table base
+    Data,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Xref {
+    /// Address of the referencing instruction
+    pub from: u16,
+    pub kind: XrefKind,
+}
+
+/// Every instruction of a decoded program referring to `target`, in program order
+pub fn xrefs_to(instructions: &[(u16, Instruction)], target: u16) -> Vec<Xref> {
+    let mut xrefs = Vec::new();
+    for (address, instruction) in instructions {
+        if let Ok(len) = encoded_len(instruction) {
+            let next = address.wrapping_add(len as u16);
+            if flow(instruction, next).target == Some(target) {
+                xrefs.push(Xref {
+                    from: *address,
+                    kind: XrefKind::Code,
+                });
+            }
+        }
+        if instruction.memory_operands().iter().any(|memory| {
+            // `[bx]` is a displacement of 0 but says nothing about address 0
+            let direct = memory.reg1.is_none() && memory.reg2.is_none();
+            // `[bp + 4]` is a stack slot, not a table at 4
+            let on_stack = memory.reg1 == Some(Register::BP) || memory.reg2 == Some(Register::BP);
+            memory.displacement as u16 == target && (direct || target != 0) && !on_stack
+        }) {
+            xrefs.push(Xref {
+                from: *address,
+                kind: XrefKind::Data,
+            });
+        }
+    }
+    xrefs
+}
+
+/// Plain-language meaning of common hand-written idioms, e.g. `sub ax, ax` clearing `ax`
+/// or `test ax, ax` only setting the flags from `ax`
+pub fn idiom(instruction: &Instruction) -> Option<String> {
+    match instruction {
+        Instruction::Sub(Location::Register(src), Location::Register(dest))
+        | Instruction::Xor(Location::Register(src), Location::Register(dest))
+            if src == dest =>
+        {
+            Some(format!("{} = 0", dest))
+        }
+        Instruction::Or(Location::Register(src), Location::Register(dest))
+        | Instruction::Test(Location::Register(src), Location::Register(dest))
+            if src == dest =>
+        {
+            Some(format!("flags from {}", dest))
+        }
+        _ => None,
+    }
+}
+
+/// Format an instruction, appending a trailing `;` comment when it is a recognized idiom
+pub fn annotate(instruction: &Instruction) -> String {
+    match idiom(instruction) {
+        Some(comment) => format!("{} ; {}", instruction, comment),
+        None => instruction.to_string(),
+    }
+}
+
+/// How listings show where a relative jump goes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum JumpTargets {
+    /// Relative to the jump's own address, e.g. `je $+5`
+    #[default]
+    Relative,
+    /// The address the jump lands on, e.g. `je 0x0105`
+    Absolute,
+}
+
+/// How listings render addresses and operands
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DisplayOptions {
+    /// Show addresses as `CS:IP` in this code segment, like DEBUG.COM, instead of flat offsets
+    pub code_segment: Option<u16>,
+    pub jump_targets: JumpTargets,
+    /// Follow recognized idioms with a `;` comment, see `annotate`
+    pub annotate: bool,
+}
+
+impl DisplayOptions {
+    pub fn format_address(&self, ip: u16) -> String {
+        match self.code_segment {
+            Some(cs) => format!("{:04X}:{:04X}", cs, ip),
+            None => format!("{:04X}", ip),
+        }
+    }
+    /// The instruction at `ip`, `len` bytes long, with its jump target per `jump_targets`
+    pub fn format_instruction(&self, ip: u16, len: u16, instruction: &Instruction) -> String {
+        let next = ip.wrapping_add(len);
+        let target = match instruction {
+            Instruction::Jump(_, _) | Instruction::JumpNear(_) | Instruction::Call(_) => {
+                flow(instruction, next).target
+            }
+            _ => None,
+        };
+        match (self.jump_targets, target) {
+            (JumpTargets::Absolute, Some(target)) => {
+                format!("{} {:#06x}", instruction.mnemonic(), target)
+            }
+            _ if self.annotate => annotate(instruction),
+            _ => instruction.to_string(),
+        }
+    }
+    /// One listing line: address, encoded bytes and the instruction
+    pub fn format_line(&self, ip: u16, bytes: &[u8], instruction: &Instruction) -> String {
+        let instruction = self.format_instruction(ip, bytes.len() as u16, instruction);
+        let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        format!(
+            "{}  {:<18}{}",
+            self.format_address(ip),
+            bytes.join(" "),
+            instruction
+        )
+    }
+}
+
+/// NASM source for a whole image, one instruction per line, that assembles back to
+/// the same bytes. The image is decoded first so every jump target that starts an
+/// instruction gets one label, numbered in address order, e.g. `label_0:` and
+/// `loop label_0`. Other targets stay relative, e.g. `jmp $+5`. An instruction
+/// not in the encoding NASM would pick, like `8B 06 10 00` for the `A1 10 00` of
+/// `mov ax, [16]`, is written as `db` bytes with the instruction in a comment.
+pub fn disassemble(bytes: &[u8]) -> Result<String, DecodeError> {
+    write_source(bytes, false)
+}
+
+/// `disassemble`, with recognized idioms followed by a `;` comment that NASM ignores
+pub fn disassemble_annotated(bytes: &[u8]) -> Result<String, DecodeError> {
+    write_source(bytes, true)
+}
+
+fn write_source(bytes: &[u8], annotated: bool) -> Result<String, DecodeError> {
+    let mut program = Vec::new();
+    let mut codec = Codec::new(Cursor::new(bytes));
+    while let Some(instruction) = codec.next_op()? {
+        let ip = codec.instruction_start() as u16;
+        let next = ip.wrapping_add(codec.instruction_bytes().len() as u16);
+        program.push((ip, next, instruction));
+    }
+    // A jump may also land right after the last instruction
+    let mut starts: BTreeSet<u16> = program.iter().map(|(ip, _, _)| *ip).collect();
+    starts.insert(bytes.len() as u16);
+    let targets: BTreeSet<u16> = program
+        .iter()
+        .filter_map(|(_, next, instruction)| flow(instruction, *next).target)
+        .filter(|target| starts.contains(target))
+        .collect();
+    let labels: BTreeMap<u16, usize> = targets
+        .into_iter()
+        .enumerate()
+        .map(|(label, target)| (target, label))
+        .collect();
+
+    let mut out = String::from("bits 16\n\n");
+    for (ip, next, instruction) in &program {
+        if let Some(label) = labels.get(ip) {
+            writeln!(out, "label_{}:", label).unwrap();
+        }
+        match flow(instruction, *next)
+            .target
+            .and_then(|target| labels.get(&target))
+        {
+            // NASM would pick the short form for a near jump to a close label
+            Some(label) if matches!(instruction, Instruction::JumpNear(_)) => {
+                writeln!(out, "jmp near label_{}", label)
+            }
+            Some(label) => writeln!(out, "{} label_{}", instruction.mnemonic(), label),
+            None if !is_canonical(instruction, &bytes[*ip as usize..*next as usize]) => {
+                let bytes: Vec<String> = bytes[*ip as usize..*next as usize]
+                    .iter()
+                    .map(|byte| format!("{:#04x}", byte))
+                    .collect();
+                writeln!(out, "db {} ; {}", bytes.join(", "), instruction)
+            }
+            None if annotated => writeln!(out, "{}", annotate(instruction)),
+            None => writeln!(out, "{}", instruction),
+        }
+        .unwrap();
+    }
+    if let Some(label) = labels.get(&(bytes.len() as u16)) {
+        writeln!(out, "label_{}:", label).unwrap();
+    }
+    Ok(out)
+}
+
+/// Whether `bytes` are what the encoder, which picks the forms NASM does, gives
+fn is_canonical(instruction: &Instruction, bytes: &[u8]) -> bool {
+    encode(instruction).is_ok_and(|encoded| encoded == bytes)
+}
+
+/// Linear-sweep listing of an image loaded at `base`, without executing it
+pub fn listing(bytes: &[u8], base: u16, options: &DisplayOptions) -> String {
+    let mut out = String::new();
+    let mut codec = Codec::new(Cursor::new(bytes));
+    loop {
+        match codec.next_op() {
+            Ok(Some(instruction)) => {
+                let ip = base.wrapping_add(codec.instruction_start() as u16);
+                let line = options.format_line(ip, codec.instruction_bytes(), &instruction);
+                writeln!(out, "{}", line).unwrap();
+            }
+            Ok(None) => break,
+            Err(e) => {
+                let ip = base.wrapping_add(codec.instruction_start() as u16);
+                writeln!(out, "{}  ; {}", options.format_address(ip), e).unwrap();
+                break;
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::decode;
+
+    #[test]
+    fn jump_targets_are_shown_relative_or_absolute() {
+        let (je, _) = decode(&[0x74, 0x03]).unwrap();
+        assert_eq!(je.to_string(), "je $+5");
+        let relative = DisplayOptions::default();
+        assert_eq!(relative.format_instruction(0x100, 2, &je), "je $+5");
+        let absolute = DisplayOptions {
+            jump_targets: JumpTargets::Absolute,
+            ..DisplayOptions::default()
+        };
+        assert_eq!(absolute.format_instruction(0x100, 2, &je), "je 0x0105");
+        let (call, _) = decode(&[0xE8, 0xFD, 0xFF]).unwrap();
+        assert_eq!(absolute.format_instruction(0x100, 3, &call), "call 0x0100");
+        assert_eq!(
+            absolute.format_line(0x100, &[0x74, 0x03], &je),
+            "0100  74 03             je 0x0105"
+        );
+    }
+
+    #[test]
+    fn self_calling_loop_terminates() {
+        // 0x100: call 0x100; jmp 0x100
+        let code = [0xE8, 0xFD, 0xFF, 0xEB, 0xFB];
+        let instructions = disassemble_recursive(&code, 0x100, 0x100);
+        assert_eq!(
+            instructions.keys().copied().collect::<Vec<_>>(),
+            [0x100, 0x103]
+        );
+
+        // jmp $, given as the entry twice
+        let disassembly = disassemble_entries(&[0xEB, 0xFE], &[0, 0], 0);
+        assert_eq!(disassembly.instructions.len(), 1);
+        assert_eq!(disassembly.reachable_from[&0], [0]);
+        assert!(!disassembly.truncated);
+    }
+
+    #[test]
+    fn disassembles_known_bytes_to_nasm() {
+        let bytes = [
+            0x89, 0xD9, // mov cx, bx
+            0xB0, 0x05, // mov al, 5
+            0x8B, 0x40, 0x02, // mov ax, [bx + si + 2]
+            0x03, 0x06, 0x10, 0x00, // add ax, [16]
+            0xF4, // hlt
+        ];
+        assert_eq!(
+            disassemble(&bytes).unwrap(),
+            "bits 16\n\nmov cx, bx\nmov al, 5\nmov ax, [bx + si + 2]\nadd ax, [16]\nhlt\n"
+        );
+        assert!(matches!(
+            disassemble(&[0x0F]),
+            Err(DecodeError::UnknownOpcode(0x0F))
+        ));
+    }
+
+    #[test]
+    fn backward_loop_jumps_share_one_label() {
+        let bytes = [
+            0xB9, 0x03, 0x00, // mov cx, 3
+            0x40, // inc ax
+            0xE2, 0xFD, // loop 3
+            0x75, 0xFB, // jne 3
+            0xEB, 0x00, // jmp 10
+        ];
+        let source = disassemble(&bytes).unwrap();
+        assert_eq!(
+            source,
+            "bits 16\n\nmov cx, 3\nlabel_0:\ninc ax\nloop label_0\njne label_0\n\
+             jmp label_1\nlabel_1:\n"
+        );
+        assert_eq!(disassemble(&bytes).unwrap(), source);
+    }
+
+    #[test]
+    fn non_canonical_encodings_are_kept_as_bytes() {
+        let bytes = [
+            0x8B, 0x06, 0x10, 0x00, // mov ax, [16], which NASM assembles to A1 10 00
+            0xFF, 0xC0, // inc ax, which NASM assembles to 40
+            0x82, 0xC0, 0x05, // add al, 5, which NASM assembles to 04 05
+            0xA1, 0x10, 0x00, // mov ax, [16]
+            0xE1, 0xFE, // loopz $
+        ];
+        assert_eq!(
+            disassemble(&bytes).unwrap(),
+            "bits 16\n\ndb 0x8b, 0x06, 0x10, 0x00 ; mov ax, [16]\ndb 0xff, 0xc0 ; inc ax\n\
+             db 0x82, 0xc0, 0x05 ; add al, 5\nmov ax, [16]\nlabel_0:\nloopz label_0\n"
+        );
+    }
+
+    #[test]
+    fn jump_into_the_middle_of_an_instruction_is_flagged_as_overlapping() {
+        let code = [
+            0xB8, 0xEB, 0x04, // mov ax, 0x04EB, whose immediate is also jmp $+6
+            0xEB, 0xFC, // jmp 1
+            0x90, 0x90, // skipped
+            0xBB, 0x01, 0x00, // mov bx, 1
+        ];
+        let disassembly = disassemble_entries(&code, &[0], 0);
+        let listing: Vec<String> = disassembly
+            .instructions
+            .iter()
+            .map(|(address, instruction)| format!("{}: {}", address, instruction))
+            .collect();
+        assert_eq!(
+            listing,
+            [
+                "0: mov ax, 1259",
+                "1: jmp $+6",
+                "3: jmp $-2",
+                "7: mov bx, 1"
+            ]
+        );
+        assert_eq!(disassembly.overlapping, [1]);
+    }
+
+    #[test]
+    fn xrefs_find_jumps_and_data_references_to_an_address() {
+        let bytes = [
+            0xA1, 0x10, 0x00, // 0: mov ax, [16]
+            0x8B, 0x47, 0x10, // 3: mov ax, [bx + 16]
+            0x8B, 0x46, 0x10, // 6: mov ax, [bp + 16]
+            0x8B, 0x07, // 9: mov ax, [bx]
+            0x74, 0x03, // 11: je 16
+            0xE8, 0x00, 0x00, // 13: call 16
+            0xF4, // 16: hlt
+        ];
+        let mut codec = Codec::new(Cursor::new(&bytes[..]));
+        let mut instructions = Vec::new();
+        while let Some(instruction) = codec.next_op().unwrap() {
+            instructions.push((codec.instruction_start() as u16, instruction));
+        }
+        let data = |from| Xref {
+            from,
+            kind: XrefKind::Data,
+        };
+        let code = |from| Xref {
+            from,
+            kind: XrefKind::Code,
+        };
+        assert_eq!(
+            xrefs_to(&instructions, 16),
+            [data(0), data(3), code(11), code(13)]
+        );
+        assert_eq!(xrefs_to(&instructions, 0), []);
+    }
+
+    #[test]
+    fn idioms_are_annotated() {
+        let comment = |bytes: &[u8]| idiom(&decode(bytes).unwrap().0);
+        assert_eq!(comment(&[0x31, 0xC0]).as_deref(), Some("ax = 0")); // xor ax, ax
+        assert_eq!(comment(&[0x29, 0xDB]).as_deref(), Some("bx = 0")); // sub bx, bx
+        assert_eq!(comment(&[0x85, 0xC9]).as_deref(), Some("flags from cx")); // test cx, cx
+        assert_eq!(comment(&[0x09, 0xD2]).as_deref(), Some("flags from dx")); // or dx, dx
+        assert_eq!(comment(&[0x31, 0xD8]), None); // xor ax, bx
+        assert_eq!(
+            annotate(&decode(&[0x31, 0xC0]).unwrap().0),
+            "xor ax, ax ; ax = 0"
+        );
+        assert_eq!(
+            disassemble_annotated(&[0x31, 0xC0, 0x40]).unwrap(),
+            "bits 16\n\nxor ax, ax ; ax = 0\ninc ax\n"
+        );
+    }
+
+    #[test]
+    fn listing_shows_cs_ip_addresses_and_decode_errors() {
+        let options = DisplayOptions {
+            code_segment: Some(0x1234),
+            ..DisplayOptions::default()
+        };
+        assert_eq!(options.format_address(0x100), "1234:0100");
+        assert_eq!(DisplayOptions::default().format_address(0x100), "0100");
+        // inc ax; mov al, 5; an unknown opcode
+        assert_eq!(
+            listing(&[0x40, 0xB0, 0x05, 0x0F], 0x100, &options),
+            "1234:0100  40                inc ax\n\
+             1234:0101  B0 05             mov al, 5\n\
+             1234:0103  ; unknown opcode 0x0f\n"
+        );
+    }
+}
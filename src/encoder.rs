@@ -0,0 +1,797 @@
+use std::{collections::HashMap, fmt::Display};
+
+use crate::instruction::{
+    Immediate, Instruction, JumpType, Location, Memory, Port, Register, Repeat, ShiftCount,
+    ShiftType, StringType,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodeError {
+    /// The operands have no 8086 encoding, e.g. memory to memory
+    InvalidOperands(&'static str),
+    /// A memory operand's registers don't form an 8086 addressing mode
+    InvalidAddressing,
+    /// Neither operand says whether the operation is on a byte or a word
+    UnknownOperandSize(&'static str),
+    /// A relocated jump at `address` no longer reaches `target`
+    JumpOutOfRange { address: u16, target: u16 },
+    /// A jump at `address` targets an address no instruction started at
+    UnresolvedTarget { address: u16, target: u16 },
+    /// An immediate doesn't fit the `width`-bit operand it is stored into
+    ImmediateOutOfRange { value: i16, width: u8 },
+}
+
+impl Display for EncodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncodeError::InvalidOperands(mnemonic) => {
+                write!(f, "invalid operands for `{}`", mnemonic)
+            }
+            EncodeError::InvalidAddressing => write!(f, "invalid memory addressing mode"),
+            EncodeError::UnknownOperandSize(mnemonic) => {
+                write!(f, "operand size of `{}` is ambiguous", mnemonic)
+            }
+            EncodeError::JumpOutOfRange { address, target } => {
+                write!(f, "jump at {:#06x} can't reach {:#06x}", address, target)
+            }
+            EncodeError::UnresolvedTarget { address, target } => write!(
+                f,
+                "jump at {:#06x} targets {:#06x}, which isn't an instruction",
+                address, target
+            ),
+            EncodeError::ImmediateOutOfRange { value, width } => {
+                write!(f, "immediate {} doesn't fit in {} bits", value, width)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+/// Where encoded bytes go, so lengths can be worked out without collecting them
+trait Sink {
+    fn push(&mut self, byte: u8);
+    fn extend_from_slice(&mut self, bytes: &[u8]);
+}
+
+impl Sink for Vec<u8> {
+    fn push(&mut self, byte: u8) {
+        Vec::push(self, byte);
+    }
+    fn extend_from_slice(&mut self, bytes: &[u8]) {
+        Vec::extend_from_slice(self, bytes);
+    }
+}
+
+/// Counts the bytes instead of storing them
+struct Count(usize);
+
+impl Sink for Count {
+    fn push(&mut self, _byte: u8) {
+        self.0 += 1;
+    }
+    fn extend_from_slice(&mut self, bytes: &[u8]) {
+        self.0 += bytes.len();
+    }
+}
+
+fn jump_opcode(ty: &JumpType) -> u8 {
+    match ty {
+        JumpType::Je => 0b01110100,
+        JumpType::Jl => 0b01111100,
+        JumpType::Jle => 0b01111110,
+        JumpType::Jb => 0b01110010,
+        JumpType::Jbe => 0b01110110,
+        JumpType::Jp => 0b01111010,
+        JumpType::Jo => 0b01110000,
+        JumpType::Js => 0b01111000,
+        JumpType::Jne => 0b01110101,
+        JumpType::Jnl => 0b01111101,
+        JumpType::Jnle => 0b01111111,
+        JumpType::Jnb => 0b01110011,
+        JumpType::Jnbe => 0b01110111,
+        JumpType::Jnp => 0b01111011,
+        JumpType::Jno => 0b01110001,
+        JumpType::Jns => 0b01111001,
+        JumpType::Loop => 0b11100010,
+        JumpType::Jnloopzs => 0b11100001,
+        JumpType::Loopnz => 0b11100000,
+        JumpType::Jcxz => 0b11100011,
+        JumpType::Jmp => 0b11101011,
+    }
+}
+
+/// The `mod` and `r/m` fields, followed by the displacement bytes
+fn encode_memory(reg: u8, memory: &Memory, out: &mut impl Sink) -> Result<(), EncodeError> {
+    use Register::*;
+    let rm = match (&memory.reg1, &memory.reg2) {
+        (None, None) => {
+            out.push(reg << 3 | 0b110);
+            out.extend_from_slice(&memory.displacement.to_le_bytes());
+            return Ok(());
+        }
+        (Some(BX), Some(SI)) | (Some(SI), Some(BX)) => 0b000,
+        (Some(BX), Some(DI)) | (Some(DI), Some(BX)) => 0b001,
+        (Some(BP), Some(SI)) | (Some(SI), Some(BP)) => 0b010,
+        (Some(BP), Some(DI)) | (Some(DI), Some(BP)) => 0b011,
+        (Some(SI), None) | (None, Some(SI)) => 0b100,
+        (Some(DI), None) | (None, Some(DI)) => 0b101,
+        (Some(BP), None) | (None, Some(BP)) => 0b110,
+        (Some(BX), None) | (None, Some(BX)) => 0b111,
+        _ => return Err(EncodeError::InvalidAddressing),
+    };
+    let displacement = memory.displacement;
+    // [bp] with no displacement is taken by the direct address form
+    if displacement == 0 && rm != 0b110 {
+        out.push(reg << 3 | rm);
+    } else if let Ok(displacement) = i8::try_from(displacement) {
+        out.push(0b01 << 6 | reg << 3 | rm);
+        out.push(displacement as u8);
+    } else {
+        out.push(0b10 << 6 | reg << 3 | rm);
+        out.extend_from_slice(&displacement.to_le_bytes());
+    }
+    Ok(())
+}
+
+/// `mod reg r/m` for a register or memory operand
+fn encode_rm(reg: u8, rm: &Location, out: &mut impl Sink) -> Result<(), EncodeError> {
+    match rm {
+        Location::Register(rm) => {
+            out.push(0b11 << 6 | reg << 3 | rm.code());
+            Ok(())
+        }
+        Location::Memory(memory) => encode_memory(reg, memory, out),
+        Location::Immediate(_) => Err(EncodeError::InvalidOperands("r/m")),
+    }
+}
+
+/// Byte immediates may be written signed or unsigned, so -128 through 255 fit
+fn encode_immediate(
+    immediate: &Immediate,
+    width: u8,
+    out: &mut impl Sink,
+) -> Result<(), EncodeError> {
+    if width == 8 {
+        if !(-128..=255).contains(&immediate.data) {
+            return Err(EncodeError::ImmediateOutOfRange {
+                value: immediate.data,
+                width,
+            });
+        }
+        out.push(immediate.data as u8);
+    } else {
+        out.extend_from_slice(&immediate.data.to_le_bytes());
+    }
+    Ok(())
+}
+
+/// Registers decide the width, then an explicitly sized immediate, as in the executor
+fn operand_width(
+    mnemonic: &'static str,
+    src: &Location,
+    dest: &Location,
+) -> Result<u8, EncodeError> {
+    match (dest, src) {
+        (Location::Register(reg), _) | (_, Location::Register(reg)) => Ok(reg.width()),
+        (_, Location::Immediate(Immediate { w: Some(0), .. })) => Ok(8),
+        (_, Location::Immediate(Immediate { w: Some(_), .. })) => Ok(16),
+        _ => Err(EncodeError::UnknownOperandSize(mnemonic)),
+    }
+}
+
+fn encode_mov(src: &Location, dest: &Location, out: &mut impl Sink) -> Result<(), EncodeError> {
+    match (dest, src) {
+        (Location::Register(segment), other) | (other, Location::Register(segment))
+            if segment.is_segment() =>
+        {
+            if matches!(other, Location::Immediate(_))
+                || matches!(other, Location::Register(reg) if reg.width() != 16 || reg.is_segment())
+            {
+                return Err(EncodeError::InvalidOperands("mov"));
+            }
+            let to_segment = matches!(dest, Location::Register(reg) if reg.is_segment());
+            out.push(if to_segment { 0x8E } else { 0x8C });
+            encode_rm(segment.code(), other, out)
+        }
+        (Location::Register(reg), Location::Immediate(immediate)) => {
+            let w = (reg.width() == 16) as u8;
+            out.push(0b1011 << 4 | w << 3 | reg.code());
+            encode_immediate(immediate, reg.width(), out)
+        }
+        (Location::Memory(memory), Location::Immediate(immediate)) => {
+            let width = operand_width("mov", src, dest)?;
+            out.push(0xC6 | (width == 16) as u8);
+            encode_memory(0, memory, out)?;
+            encode_immediate(immediate, width, out)
+        }
+        // The accumulator has a short form for direct addresses
+        (
+            Location::Register(reg @ (Register::AL | Register::AX)),
+            Location::Memory(
+                memory @ Memory {
+                    reg1: None,
+                    reg2: None,
+                    ..
+                },
+            ),
+        )
+        | (
+            Location::Memory(
+                memory @ Memory {
+                    reg1: None,
+                    reg2: None,
+                    ..
+                },
+            ),
+            Location::Register(reg @ (Register::AL | Register::AX)),
+        ) => {
+            let store = matches!(dest, Location::Memory(_)) as u8;
+            out.push(0b1010000 << 1 | store << 1 | (reg.width() == 16) as u8);
+            out.extend_from_slice(&memory.displacement.to_le_bytes());
+            Ok(())
+        }
+        (_, Location::Register(reg)) => {
+            out.push(0x88 | (reg.width() == 16) as u8);
+            encode_rm(reg.code(), dest, out)
+        }
+        (Location::Register(reg), Location::Memory(_)) => {
+            out.push(0x8A | (reg.width() == 16) as u8);
+            encode_rm(reg.code(), src, out)
+        }
+        _ => Err(EncodeError::InvalidOperands("mov")),
+    }
+}
+
+/// The two-operand ALU group, `op` being the 3-bit operation shared by all its forms
+fn encode_arithmetic(
+    mnemonic: &'static str,
+    op: u8,
+    src: &Location,
+    dest: &Location,
+    out: &mut impl Sink,
+) -> Result<(), EncodeError> {
+    let width = operand_width(mnemonic, src, dest)?;
+    let w = (width == 16) as u8;
+    match (dest, src) {
+        (Location::Register(reg), _) | (_, Location::Register(reg)) if reg.is_segment() => {
+            Err(EncodeError::InvalidOperands(mnemonic))
+        }
+        (Location::Immediate(_), _) => Err(EncodeError::InvalidOperands(mnemonic)),
+        (_, Location::Immediate(immediate)) => {
+            let short = width == 16 && i8::try_from(immediate.data).is_ok();
+            if let (Location::Register(Register::AL | Register::AX), false) = (dest, short) {
+                out.push(op << 3 | 0b100 | w);
+                encode_immediate(immediate, width, out)?;
+            } else if short {
+                out.push(0x83);
+                encode_rm(op, dest, out)?;
+                encode_immediate(immediate, 8, out)?;
+            } else {
+                out.push(0x80 | w);
+                encode_rm(op, dest, out)?;
+                encode_immediate(immediate, width, out)?;
+            }
+            Ok(())
+        }
+        (_, Location::Register(reg)) => {
+            out.push(op << 3 | w);
+            encode_rm(reg.code(), dest, out)
+        }
+        (Location::Register(reg), Location::Memory(_)) => {
+            out.push(op << 3 | 0b10 | w);
+            encode_rm(reg.code(), src, out)
+        }
+        _ => Err(EncodeError::InvalidOperands(mnemonic)),
+    }
+}
+
+/// `test` has its own opcodes and, unlike the arithmetic group, no sign-extended form
+fn encode_test(src: &Location, dest: &Location, out: &mut impl Sink) -> Result<(), EncodeError> {
+    let width = operand_width("test", src, dest)?;
+    let w = (width == 16) as u8;
+    match (dest, src) {
+        (Location::Register(reg), _) | (_, Location::Register(reg)) if reg.is_segment() => {
+            Err(EncodeError::InvalidOperands("test"))
+        }
+        (Location::Register(Register::AL | Register::AX), Location::Immediate(immediate)) => {
+            out.push(0xA8 | w);
+            encode_immediate(immediate, width, out)
+        }
+        (Location::Immediate(_), _) => Err(EncodeError::InvalidOperands("test")),
+        (_, Location::Immediate(immediate)) => {
+            out.push(0xF6 | w);
+            encode_rm(0b000, dest, out)?;
+            encode_immediate(immediate, width, out)
+        }
+        // Both operands are only read, so either may be the r/m one
+        (_, Location::Register(reg)) => {
+            out.push(0x84 | w);
+            encode_rm(reg.code(), dest, out)
+        }
+        (Location::Register(reg), _) => {
+            out.push(0x84 | w);
+            encode_rm(reg.code(), src, out)
+        }
+        _ => Err(EncodeError::InvalidOperands("test")),
+    }
+}
+
+/// Either operand may be the register, and AX with another word register has a
+/// one-byte form, which for `xchg ax, ax` is `nop`
+fn encode_xchg(src: &Location, dest: &Location, out: &mut impl Sink) -> Result<(), EncodeError> {
+    let width = operand_width("xchg", src, dest)?;
+    match (dest, src) {
+        (Location::Register(reg), _) | (_, Location::Register(reg)) if reg.is_segment() => {
+            Err(EncodeError::InvalidOperands("xchg"))
+        }
+        (Location::Register(Register::AX), Location::Register(reg))
+        | (Location::Register(reg), Location::Register(Register::AX))
+            if reg.width() == 16 =>
+        {
+            out.push(0x90 | reg.code());
+            Ok(())
+        }
+        (Location::Register(reg), other @ (Location::Register(_) | Location::Memory(_)))
+        | (other @ Location::Memory(_), Location::Register(reg)) => {
+            out.push(0x86 | (width == 16) as u8);
+            encode_rm(reg.code(), other, out)
+        }
+        _ => Err(EncodeError::InvalidOperands("xchg")),
+    }
+}
+
+fn encode_inc_dec(
+    mnemonic: &'static str,
+    op: u8,
+    dest: &Location,
+    width: u8,
+    amount: Option<u8>,
+    out: &mut impl Sink,
+) -> Result<(), EncodeError> {
+    if amount.is_some_and(|amount| amount != 1) {
+        return Err(EncodeError::InvalidOperands(mnemonic));
+    }
+    match dest {
+        Location::Register(reg) if reg.is_segment() => Err(EncodeError::InvalidOperands(mnemonic)),
+        Location::Register(reg) if reg.width() == 16 => {
+            out.push(0b0100 << 4 | op << 3 | reg.code());
+            Ok(())
+        }
+        Location::Register(reg) => {
+            out.push(0xFE);
+            encode_rm(op, &Location::Register(reg.clone()), out)
+        }
+        Location::Memory(_) => {
+            out.push(0xFE | (width == 16) as u8);
+            encode_rm(op, dest, out)
+        }
+        Location::Immediate(_) => Err(EncodeError::InvalidOperands(mnemonic)),
+    }
+}
+
+fn encode_push_pop(pop: bool, operand: &Location, out: &mut impl Sink) -> Result<(), EncodeError> {
+    let mnemonic = if pop { "pop" } else { "push" };
+    match operand {
+        Location::Register(Register::CS) if pop => Err(EncodeError::InvalidOperands(mnemonic)),
+        Location::Register(reg) if reg.is_segment() => {
+            out.push(reg.code() << 3 | 0b110 | pop as u8);
+            Ok(())
+        }
+        Location::Register(reg) if reg.width() == 16 => {
+            out.push(0b0101 << 4 | (pop as u8) << 3 | reg.code());
+            Ok(())
+        }
+        Location::Memory(memory) if pop => {
+            out.push(0x8F);
+            encode_memory(0b000, memory, out)
+        }
+        Location::Memory(memory) => {
+            out.push(0xFF);
+            encode_memory(0b110, memory, out)
+        }
+        _ => Err(EncodeError::InvalidOperands(mnemonic)),
+    }
+}
+
+/// 0xD0-0xD3, the reg field selecting the shift or rotate and bit 1 a count of CL
+fn encode_shift(
+    ty: &ShiftType,
+    dest: &Location,
+    width: u8,
+    count: &ShiftCount,
+    out: &mut impl Sink,
+) -> Result<(), EncodeError> {
+    let op = match ty {
+        ShiftType::Rol => 0b000,
+        ShiftType::Ror => 0b001,
+        ShiftType::Rcl => 0b010,
+        ShiftType::Rcr => 0b011,
+        ShiftType::Shl => 0b100,
+        ShiftType::Shr => 0b101,
+        ShiftType::Sar => 0b111,
+    };
+    let cl = matches!(count, ShiftCount::Cl) as u8;
+    match dest {
+        Location::Register(reg) if reg.is_segment() || reg.width() != width => {
+            Err(EncodeError::InvalidOperands(ty.mnemonic()))
+        }
+        Location::Immediate(_) => Err(EncodeError::InvalidOperands(ty.mnemonic())),
+        dest => {
+            out.push(0xD0 | cl << 1 | (width == 16) as u8);
+            encode_rm(op, dest, out)
+        }
+    }
+}
+
+/// The single-operand 0xF6/0xF7 group, `op` selecting the operation
+fn encode_group3(
+    mnemonic: &'static str,
+    op: u8,
+    operand: &Location,
+    width: u8,
+    out: &mut impl Sink,
+) -> Result<(), EncodeError> {
+    match operand {
+        Location::Register(reg) if reg.is_segment() || reg.width() != width => {
+            Err(EncodeError::InvalidOperands(mnemonic))
+        }
+        Location::Immediate(_) => Err(EncodeError::InvalidOperands(mnemonic)),
+        operand => {
+            out.push(0xF6 | (width == 16) as u8);
+            encode_rm(op, operand, out)
+        }
+    }
+}
+
+/// `opcode` is the byte form with a fixed port, the DX forms are 8 above it
+fn encode_port(opcode: u8, port: &Port, width: u8, out: &mut impl Sink) {
+    let w = (width == 16) as u8;
+    match port {
+        Port::Immediate(port) => out.extend_from_slice(&[opcode | w, *port]),
+        Port::Dx => out.push((opcode + 8) | w),
+    }
+}
+
+fn segment_prefix(segment: &Register) -> u8 {
+    0b001 << 5 | segment.code() << 3 | 0b110
+}
+
+/// Machine code for a single instruction, picking the shortest encoding
+pub fn encode(instruction: &Instruction) -> Result<Vec<u8>, EncodeError> {
+    let mut out = Vec::new();
+    encode_into(instruction, &mut out)?;
+    Ok(out)
+}
+
+/// Length of `encode`'s output, without building it, e.g. to check whether a
+/// replacement fits in place of an instruction
+pub fn encoded_len(instruction: &Instruction) -> Result<usize, EncodeError> {
+    let mut count = Count(0);
+    encode_into(instruction, &mut count)?;
+    Ok(count.0)
+}
+
+fn encode_into(instruction: &Instruction, out: &mut impl Sink) -> Result<(), EncodeError> {
+    if let Some(segment) = instruction
+        .memory_operands()
+        .iter()
+        .find_map(|memory| memory.segment.as_ref())
+    {
+        out.push(segment_prefix(segment));
+    }
+    match instruction {
+        Instruction::Mov(src, dest) => encode_mov(src, dest, out)?,
+        Instruction::Add(src, dest) => encode_arithmetic("add", 0b000, src, dest, out)?,
+        Instruction::Adc(src, dest) => encode_arithmetic("adc", 0b010, src, dest, out)?,
+        Instruction::Sbb(src, dest) => encode_arithmetic("sbb", 0b011, src, dest, out)?,
+        Instruction::Sub(src, dest) => encode_arithmetic("sub", 0b101, src, dest, out)?,
+        Instruction::Cmp(src, dest) => encode_arithmetic("cmp", 0b111, src, dest, out)?,
+        Instruction::And(src, dest) => encode_arithmetic("and", 0b100, src, dest, out)?,
+        Instruction::Or(src, dest) => encode_arithmetic("or", 0b001, src, dest, out)?,
+        Instruction::Xor(src, dest) => encode_arithmetic("xor", 0b110, src, dest, out)?,
+        Instruction::Test(src, dest) => encode_test(src, dest, out)?,
+        Instruction::Xchg(src, dest) => encode_xchg(src, dest, out)?,
+        Instruction::Jump(ty, disp) => out.extend_from_slice(&[jump_opcode(ty), *disp as u8]),
+        Instruction::JumpNear(disp) => {
+            out.push(0xE9);
+            out.extend_from_slice(&disp.to_le_bytes());
+        }
+        Instruction::Call(disp) => {
+            out.push(0xE8);
+            out.extend_from_slice(&disp.to_le_bytes());
+        }
+        Instruction::Ret(None) => out.push(0xC3),
+        Instruction::Ret(Some(bytes)) => {
+            out.push(0xC2);
+            out.extend_from_slice(&bytes.to_le_bytes());
+        }
+        Instruction::Hlt => out.push(0xF4),
+        Instruction::StringOp(ty, width, segment, repeat) => {
+            match repeat {
+                Some(Repeat::Rep) => out.push(0xF3),
+                Some(Repeat::Repne) => out.push(0xF2),
+                None => {}
+            }
+            if let Some(segment) = segment {
+                out.push(segment_prefix(segment));
+            }
+            let opcode = match ty {
+                StringType::Movs => 0xA4,
+                StringType::Cmps => 0xA6,
+                StringType::Stos => 0xAA,
+                StringType::Scas => 0xAE,
+            };
+            out.push(opcode | (*width == 16) as u8);
+        }
+        Instruction::Cld => out.push(0xFC),
+        Instruction::Std => out.push(0xFD),
+        Instruction::Clc => out.push(0xF8),
+        Instruction::Stc => out.push(0xF9),
+        Instruction::Cmc => out.push(0xF5),
+        Instruction::Cli => out.push(0xFA),
+        Instruction::Sti => out.push(0xFB),
+        Instruction::Pushf => out.push(0x9C),
+        Instruction::Popf => out.push(0x9D),
+        Instruction::Lahf => out.push(0x9F),
+        Instruction::Sahf => out.push(0x9E),
+        Instruction::Cbw => out.push(0x98),
+        Instruction::Cwd => out.push(0x99),
+        Instruction::In(width, port) => encode_port(0xE4, port, *width, out),
+        Instruction::Out(port, width) => encode_port(0xE6, port, *width, out),
+        Instruction::Int(vector) => out.extend_from_slice(&[0xCD, *vector]),
+        Instruction::Int3 => out.push(0xCC),
+        Instruction::Into => out.push(0xCE),
+        Instruction::Iret => out.push(0xCF),
+        Instruction::Daa => out.push(0x27),
+        Instruction::Aaa => out.push(0x37),
+        Instruction::Salc => out.push(0xD6),
+        Instruction::Shift(ty, dest, width, count) => encode_shift(ty, dest, *width, count, out)?,
+        Instruction::Not(dest, width) => encode_group3("not", 0b010, dest, *width, out)?,
+        Instruction::Neg(dest, width) => encode_group3("neg", 0b011, dest, *width, out)?,
+        Instruction::Mul(src, width) => encode_group3("mul", 0b100, src, *width, out)?,
+        Instruction::Imul(src, width) => encode_group3("imul", 0b101, src, *width, out)?,
+        Instruction::Div(src, width) => encode_group3("div", 0b110, src, *width, out)?,
+        Instruction::Idiv(src, width) => encode_group3("idiv", 0b111, src, *width, out)?,
+        Instruction::JumpFar(pointer) => {
+            out.push(0xFF);
+            encode_memory(0b101, pointer, out)?;
+        }
+        Instruction::JumpFarDirect(segment, offset)
+        | Instruction::CallFarDirect(segment, offset) => {
+            out.push(if matches!(instruction, Instruction::JumpFarDirect(_, _)) {
+                0xEA
+            } else {
+                0x9A
+            });
+            out.extend_from_slice(&offset.to_le_bytes());
+            out.extend_from_slice(&segment.to_le_bytes());
+        }
+        Instruction::RetFar(None) => out.push(0xCB),
+        Instruction::RetFar(Some(bytes)) => {
+            out.push(0xCA);
+            out.extend_from_slice(&bytes.to_le_bytes());
+        }
+        Instruction::CallFar(pointer) => {
+            out.push(0xFF);
+            encode_memory(0b011, pointer, out)?;
+        }
+        Instruction::Inc(dest, width, amount) => {
+            encode_inc_dec("inc", 0b000, dest, *width, *amount, out)?
+        }
+        Instruction::Dec(dest, width, amount) => {
+            encode_inc_dec("dec", 0b001, dest, *width, *amount, out)?
+        }
+        Instruction::Bound(index, bounds) => {
+            out.push(0x62);
+            encode_memory(index.code(), bounds, out)?;
+        }
+        Instruction::Lea(dest, address) => {
+            if dest.is_segment() || dest.width() != 16 {
+                return Err(EncodeError::InvalidOperands("lea"));
+            }
+            out.push(0x8D);
+            encode_memory(dest.code(), address, out)?;
+        }
+        Instruction::Push(src) => encode_push_pop(false, src, out)?,
+        Instruction::Pop(dest) => encode_push_pop(true, dest, out)?,
+    }
+    Ok(())
+}
+
+/// Encode a decoded program after it was edited. Every entry keeps the address it was
+/// decoded at, and relative jumps are retargeted to wherever the instruction at their
+/// original target ends up, so instructions may be replaced, inserted or removed.
+/// A jump to an address shared by several entries lands on the first of them.
+pub fn reassemble(program: &[(u16, Instruction)]) -> Result<Vec<u8>, EncodeError> {
+    let Some(&(base, _)) = program.first() else {
+        return Ok(Vec::new());
+    };
+    let encoded = program
+        .iter()
+        .map(|(_, instruction)| encode(instruction))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut relocated = HashMap::new();
+    let mut address = base;
+    for ((original, _), bytes) in program.iter().zip(&encoded) {
+        relocated.entry(*original).or_insert(address);
+        address = address.wrapping_add(bytes.len() as u16);
+    }
+    let (last, last_bytes) = (program[program.len() - 1].0, &encoded[encoded.len() - 1]);
+    relocated
+        .entry(last.wrapping_add(last_bytes.len() as u16))
+        .or_insert(address);
+
+    let mut out = Vec::new();
+    for ((original, instruction), mut bytes) in program.iter().zip(encoded) {
+        let address = base.wrapping_add(out.len() as u16);
+        let disp = match instruction {
+            Instruction::Jump(_, disp) => *disp as i16,
+            Instruction::JumpNear(disp) | Instruction::Call(disp) => *disp,
+            _ => {
+                out.extend_from_slice(&bytes);
+                continue;
+            }
+        };
+        let len = bytes.len() as u16;
+        let target = original.wrapping_add(len).wrapping_add(disp as u16);
+        let Some(&new_target) = relocated.get(&target) else {
+            return Err(EncodeError::UnresolvedTarget {
+                address: *original,
+                target,
+            });
+        };
+        let new_disp = new_target.wrapping_sub(address.wrapping_add(len)) as i16;
+        if let Instruction::Jump(_, _) = instruction {
+            let Ok(new_disp) = i8::try_from(new_disp) else {
+                return Err(EncodeError::JumpOutOfRange {
+                    address: *original,
+                    target,
+                });
+            };
+            bytes[1] = new_disp as u8;
+        } else {
+            bytes[1..3].copy_from_slice(&new_disp.to_le_bytes());
+        }
+        out.extend_from_slice(&bytes);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::{decode, Codec};
+    use std::io::Cursor;
+
+    #[test]
+    fn reassemble_retargets_jumps_around_a_shorter_instruction() {
+        let code = [
+            0xEB, 0x03, // jmp 0x05
+            0xB8, 0x34, 0x12, // mov ax, 0x1234
+            0xE8, 0x02, 0x00, // call 0x0a
+            0xEB, 0xFB, // jmp 0x05
+            0xC3, // ret
+        ];
+        let mut program = Codec::new(Cursor::new(&code[..]))
+            .decode_program(0)
+            .unwrap();
+        assert_eq!(program.len(), 5);
+        assert_eq!(reassemble(&program).unwrap(), code);
+        program[1].1 = decode(&[0x90]).unwrap().0;
+        assert_eq!(
+            reassemble(&program).unwrap(),
+            [0xEB, 0x01, 0x90, 0xE8, 0x02, 0x00, 0xEB, 0xFB, 0xC3]
+        );
+    }
+
+    #[test]
+    fn segment_override_round_trips() {
+        let es_di = Memory::with_segment(Some(Register::DI), None, 0, Some(Register::ES));
+        let instruction = Instruction::mov(es_di, Register::AX);
+        let bytes = encode(&instruction).unwrap();
+        assert_eq!(bytes, [0x26, 0x89, 0x05]);
+        let (decoded, rest) = decode(&bytes).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded.to_string(), "mov es:[di], ax");
+        assert_eq!(decoded.to_string(), instruction.to_string());
+
+        let plain = Instruction::mov(Memory::new(Some(Register::DI), None, 0), Register::AX);
+        assert_eq!(encode(&plain).unwrap(), [0x89, 0x05]);
+    }
+
+    #[test]
+    fn byte_destinations_reject_word_immediates() {
+        let too_big = Instruction::mov(Register::AL, Immediate::new(0x1234, None));
+        assert_eq!(
+            encode(&too_big),
+            Err(EncodeError::ImmediateOutOfRange {
+                value: 0x1234,
+                width: 8
+            })
+        );
+        let add = Instruction::add(Register::BL, Immediate::new(-129, None));
+        assert!(matches!(
+            encode(&add),
+            Err(EncodeError::ImmediateOutOfRange { value: -129, .. })
+        ));
+        // Both signed and unsigned spellings of a byte fit
+        let max = Instruction::mov(Register::AL, Immediate::new(255, None));
+        assert_eq!(encode(&max), Ok(vec![0xB0, 0xFF]));
+        let min = Instruction::mov(Register::AL, Immediate::new(-128, None));
+        assert_eq!(encode(&min), Ok(vec![0xB0, 0x80]));
+        let word = Instruction::mov(Register::AX, Immediate::new(0x1234, None));
+        assert_eq!(encode(&word), Ok(vec![0xB8, 0x34, 0x12]));
+    }
+
+    #[test]
+    fn encoded_len_matches_encode() {
+        let code: &[u8] = &[
+            0x89, 0xD8, // mov ax, bx
+            0xB0, 0x05, // mov al, 5
+            0x8B, 0x40, 0x02, // mov ax, [bx + si + 2]
+            0x8B, 0x87, 0x34, 0x12, // mov ax, [bx + 4660]
+            0x26, 0x89, 0x05, // mov es:[di], ax
+            0x83, 0xC0, 0xFF, // add ax, -1
+            0x81, 0xC3, 0x34, 0x12, // add bx, 4660
+            0x04, 0x07, // add al, 7
+            0xC6, 0x07, 0x01, // mov byte [bx], 1
+            0xFE, 0x07, // inc byte [bx]
+            0x40, // inc ax
+            0x8F, 0x06, 0x40, 0x00, // pop word [64]
+            0xD2, 0xE8, // shr al, cl
+            0x8D, 0x40, 0x02, // lea ax, [bx + si + 2]
+            0xE4, 0x60, // in al, 96
+            0xCD, 0x21, // int 33
+            0xF3, 0xA4, // rep movsb
+            0xA3, 0x10, 0x00, // mov [16], ax
+            0x74, 0x00, // je $+2
+            0xE8, 0x00, 0x00, // call $+3
+            0xC2, 0x02, 0x00, // ret 2
+            0x9A, 0x00, 0x00, 0x00, 0x10, // call 0x1000:0x0000
+            0xF6, 0x27, // mul byte [bx]
+            0x9C, // pushf
+        ];
+        let program = Codec::new(Cursor::new(code)).decode_program(0).unwrap();
+        assert_eq!(program.len(), 24);
+        let mut ends = program.iter().skip(1).map(|(ip, _)| *ip as usize);
+        for (ip, instruction) in &program {
+            let bytes = encode(instruction).unwrap();
+            assert_eq!(encoded_len(instruction), Ok(bytes.len()), "{}", instruction);
+            let end = ends.next().unwrap_or(code.len());
+            assert_eq!(bytes, code[*ip as usize..end], "{}", instruction);
+        }
+    }
+
+    #[test]
+    fn inc_dec_round_trip_with_their_width() {
+        for (bytes, text) in [
+            (&[0xFE, 0xC0][..], "inc al"),
+            (&[0xFF, 0x07], "inc word [bx]"),
+            (&[0xFE, 0x0F], "dec byte [bx]"),
+            (&[0x4B], "dec bx"),
+        ] {
+            let (instruction, _) = decode(bytes).unwrap();
+            assert_eq!(instruction.to_string(), text);
+            assert_eq!(encode(&instruction).unwrap(), bytes);
+        }
+        // The register form of the 0xFF group shrinks to the one-byte encoding
+        let (instruction, _) = decode(&[0xFF, 0xC0]).unwrap();
+        assert_eq!(encode(&instruction).unwrap(), [0x40]);
+    }
+
+    #[test]
+    fn xchg_forms_round_trip() {
+        for (bytes, text) in [
+            (&[0x90][..], "nop"),
+            (&[0x93], "xchg ax, bx"),
+            (&[0x87, 0x07], "xchg ax, [bx]"),
+            (&[0x86, 0xC3], "xchg al, bl"),
+        ] {
+            let (instruction, _) = decode(bytes).unwrap();
+            assert_eq!(instruction.to_string(), text);
+            assert_eq!(encode(&instruction).unwrap(), bytes);
+        }
+    }
+}
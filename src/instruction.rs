@@ -1,8 +1,6 @@
-use std::fmt::Display;
+use std::{fmt::Display, str::FromStr};
 
-static mut LABEL_COUNTER: usize = 0;
-
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Register {
     AL,
     CL,
@@ -23,6 +21,7 @@ pub enum Register {
     SS,
     DS,
     ES,
+    CS,
 }
 
 impl Display for Register {
@@ -47,11 +46,54 @@ impl Display for Register {
             Register::SS => "ss",
             Register::DS => "ds",
             Register::ES => "es",
+            Register::CS => "cs",
         };
         write!(f, "{}", display)
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownRegister(pub String);
+
+impl Display for UnknownRegister {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown register `{}`", self.0)
+    }
+}
+
+impl std::error::Error for UnknownRegister {}
+
+impl FromStr for Register {
+    type Err = UnknownRegister;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let reg = match s {
+            "al" => Register::AL,
+            "cl" => Register::CL,
+            "dl" => Register::DL,
+            "bl" => Register::BL,
+            "ah" => Register::AH,
+            "ch" => Register::CH,
+            "dh" => Register::DH,
+            "bh" => Register::BH,
+            "ax" => Register::AX,
+            "cx" => Register::CX,
+            "dx" => Register::DX,
+            "bx" => Register::BX,
+            "sp" => Register::SP,
+            "bp" => Register::BP,
+            "si" => Register::SI,
+            "di" => Register::DI,
+            "ss" => Register::SS,
+            "ds" => Register::DS,
+            "es" => Register::ES,
+            "cs" => Register::CS,
+            _ => return Err(UnknownRegister(s.to_string())),
+        };
+        Ok(reg)
+    }
+}
+
 impl Register {
     pub fn new(opcode: u8, w: u8) -> Self {
         use Register::*;
@@ -75,27 +117,94 @@ impl Register {
             _ => panic!("Invalid register or w pattern!"),
         }
     }
+    /// Segment register selected by the 2-bit `sr` field
+    pub fn segment(sr: u8) -> Self {
+        match sr {
+            0b00 => Register::ES,
+            0b01 => Register::CS,
+            0b10 => Register::SS,
+            0b11 => Register::DS,
+            _ => panic!("Invalid segment register pattern!"),
+        }
+    }
+    /// The 3-bit `reg` field, or the 2-bit `sr` field for segment registers
+    pub fn code(&self) -> u8 {
+        use Register::*;
+        match self {
+            AL | AX | ES => 0b000,
+            CL | CX | CS => 0b001,
+            DL | DX | SS => 0b010,
+            BL | BX | DS => 0b011,
+            AH | SP => 0b100,
+            CH | BP => 0b101,
+            DH | SI => 0b110,
+            BH | DI => 0b111,
+        }
+    }
+    pub fn is_segment(&self) -> bool {
+        matches!(
+            self,
+            Register::SS | Register::DS | Register::ES | Register::CS
+        )
+    }
+    /// Size of the register in bits, 8 or 16
+    pub fn width(&self) -> u8 {
+        use Register::*;
+        match self {
+            AL | CL | DL | BL | AH | CH | DH | BH => 8,
+            _ => 16,
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Memory {
     pub(crate) reg1: Option<Register>,
     pub(crate) reg2: Option<Register>,
     pub(crate) displacement: i16,
+    /// Segment override prefix, `None` uses the default segment
+    pub(crate) segment: Option<Register>,
 }
 
 impl Memory {
     pub fn new(reg1: Option<Register>, reg2: Option<Register>, displacement: i16) -> Self {
+        Self::with_segment(reg1, reg2, displacement, None)
+    }
+    /// Memory addressed through an explicit segment, e.g. `es:[di]`
+    pub fn with_segment(
+        reg1: Option<Register>,
+        reg2: Option<Register>,
+        displacement: i16,
+        segment: Option<Register>,
+    ) -> Self {
         Self {
             reg1,
             reg2,
             displacement,
+            segment,
+        }
+    }
+    /// SS for BP-based addressing, DS otherwise
+    pub fn default_segment(&self) -> Register {
+        if self.reg1 == Some(Register::BP) || self.reg2 == Some(Register::BP) {
+            Register::SS
+        } else {
+            Register::DS
         }
     }
+    /// The segment actually used: the override if there is one, else the default
+    pub fn segment(&self) -> Register {
+        self.segment
+            .clone()
+            .unwrap_or_else(|| self.default_segment())
+    }
 }
 
 impl Display for Memory {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(segment) = &self.segment {
+            write!(f, "{}:", segment)?;
+        }
         match (self.reg1.as_ref(), self.reg2.as_ref(), self.displacement) {
             (Some(reg1), Some(reg2), 0) => write!(f, "[{} + {}]", reg1, reg2),
             (Some(reg1), Some(reg2), x) => {
@@ -120,7 +229,7 @@ impl Display for Memory {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Immediate {
     pub data: i16,
     pub w: Option<u8>,
@@ -149,7 +258,7 @@ impl Immediate {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Location {
     Register(Register),
     Memory(Memory),
@@ -166,6 +275,24 @@ impl Display for Location {
     }
 }
 
+impl From<Register> for Location {
+    fn from(reg: Register) -> Self {
+        Location::Register(reg)
+    }
+}
+
+impl From<Memory> for Location {
+    fn from(memory: Memory) -> Self {
+        Location::Memory(memory)
+    }
+}
+
+impl From<Immediate> for Location {
+    fn from(immediate: Immediate) -> Self {
+        Location::Immediate(immediate)
+    }
+}
+
 #[derive(Debug)]
 pub enum Instruction {
     Mov(Location, Location),
@@ -174,13 +301,542 @@ pub enum Instruction {
     Sbb(Location, Location),
     Sub(Location, Location),
     Cmp(Location, Location),
+    And(Location, Location),
+    Or(Location, Location),
+    Xor(Location, Location),
+    /// `and` that only sets the flags
+    Test(Location, Location),
+    /// Swap the operands. `xchg ax, ax` is `nop`.
+    Xchg(Location, Location),
+    /// Short jumps, conditional or not, with an 8-bit displacement
     Jump(JumpType, i8),
+    /// Unconditional jump with a 16-bit displacement
+    JumpNear(i16),
     Daa,
     Aaa,
-    Inc(Location, Option<u8>),
-    Dec(Location, Option<u8>),
+    /// Destination, operand width and an optional amount other than one
+    Inc(Location, u8, Option<u8>),
+    Dec(Location, u8, Option<u8>),
+    /// Raise interrupt 5 unless the signed index is within the pair of bounds in memory (186+)
+    Bound(Register, Memory),
+    /// Load the offset of the memory operand into the register, without accessing memory
+    Lea(Register, Memory),
+    Push(Location),
+    Pop(Location),
+    /// Undocumented: set AL to 0xFF if CF is set, else 0x00
+    Salc,
+    /// Shift or rotate an operand of the given width
+    Shift(ShiftType, Location, u8, ShiftCount),
+    /// Complement every bit of, or negate, an operand of the given width, 8 or 16
+    Not(Location, u8),
+    Neg(Location, u8),
+    /// Multiply and divide the accumulator by an operand of the given width, 8 or 16.
+    /// AL/AX, and DX for words, are implicit and not shown.
+    Mul(Location, u8),
+    Imul(Location, u8),
+    Div(Location, u8),
+    Idiv(Location, u8),
+    /// Load IP and then CS from the far pointer in memory
+    JumpFar(Memory),
+    /// Push CS and IP, then load them from the far pointer in memory
+    CallFar(Memory),
+    /// Far jump and call to an immediate segment:offset, stored in that order
+    JumpFarDirect(u16, u16),
+    CallFarDirect(u16, u16),
+    /// Pop IP and CS pushed by a far call, then release this many bytes of arguments
+    RetFar(Option<u16>),
+    /// Push IP and jump by a 16-bit displacement
+    Call(i16),
+    /// Pop IP pushed by a near call, then release this many bytes of arguments
+    Ret(Option<u16>),
+    /// Stop until an interrupt arrives, which ends a run unless the timer raises one
+    Hlt,
+    /// A string instruction on bytes or words, with the segment override of its
+    /// DS:SI source and an optional repeat prefix. ES:DI can't be overridden.
+    StringOp(StringType, u8, Option<Register>, Option<Repeat>),
+    /// Clear or set the direction flag, making string instructions step up or down
+    Cld,
+    Std,
+    /// Clear, set or complement the carry flag
+    Clc,
+    Stc,
+    Cmc,
+    /// Clear or set the interrupt flag, which masks external interrupts
+    Cli,
+    Sti,
+    /// Push or pop the flags register
+    Pushf,
+    Popf,
+    /// Copy SF, ZF, AF, PF and CF to or from AH, in their flags register bits
+    Lahf,
+    Sahf,
+    /// Sign-extend AL into AX, or AX into DX:AX
+    Cbw,
+    Cwd,
+    /// Read the accumulator of the given width, AL or AX, from a port
+    In(u8, Port),
+    /// Write the accumulator of the given width to a port
+    Out(Port, u8),
+    /// Software interrupts: `int n`, the one-byte breakpoint `int3`, and `into`,
+    /// which raises interrupt 4 only if OF is set
+    Int(u8),
+    Int3,
+    Into,
+    /// Pop IP, CS and the flags pushed by an interrupt
+    Iret,
 }
 
+/// Constructors taking operands in assembly order (`dest, src`),
+/// while the variants store them as `(src, dest)`
+impl Instruction {
+    pub fn mov(dest: impl Into<Location>, src: impl Into<Location>) -> Self {
+        Instruction::Mov(src.into(), dest.into())
+    }
+    pub fn add(dest: impl Into<Location>, src: impl Into<Location>) -> Self {
+        Instruction::Add(src.into(), dest.into())
+    }
+    pub fn adc(dest: impl Into<Location>, src: impl Into<Location>) -> Self {
+        Instruction::Adc(src.into(), dest.into())
+    }
+    pub fn sbb(dest: impl Into<Location>, src: impl Into<Location>) -> Self {
+        Instruction::Sbb(src.into(), dest.into())
+    }
+    pub fn sub(dest: impl Into<Location>, src: impl Into<Location>) -> Self {
+        Instruction::Sub(src.into(), dest.into())
+    }
+    pub fn cmp(dest: impl Into<Location>, src: impl Into<Location>) -> Self {
+        Instruction::Cmp(src.into(), dest.into())
+    }
+    pub fn and(dest: impl Into<Location>, src: impl Into<Location>) -> Self {
+        Instruction::And(src.into(), dest.into())
+    }
+    pub fn or(dest: impl Into<Location>, src: impl Into<Location>) -> Self {
+        Instruction::Or(src.into(), dest.into())
+    }
+    pub fn xor(dest: impl Into<Location>, src: impl Into<Location>) -> Self {
+        Instruction::Xor(src.into(), dest.into())
+    }
+    pub fn test(dest: impl Into<Location>, src: impl Into<Location>) -> Self {
+        Instruction::Test(src.into(), dest.into())
+    }
+    pub fn xchg(dest: impl Into<Location>, src: impl Into<Location>) -> Self {
+        Instruction::Xchg(src.into(), dest.into())
+    }
+    /// Fails for byte registers and immediates, the 8086 only pushes words
+    pub fn push(src: impl Into<Location>) -> Result<Self, InvalidStackOperand> {
+        let src = src.into();
+        match &src {
+            Location::Register(reg) if reg.width() == 16 => Ok(Instruction::Push(src)),
+            Location::Memory(_) => Ok(Instruction::Push(src)),
+            _ => Err(InvalidStackOperand {
+                mnemonic: "push",
+                operand: src,
+            }),
+        }
+    }
+    /// Fails for byte registers, immediates and `cs`
+    pub fn pop(dest: impl Into<Location>) -> Result<Self, InvalidStackOperand> {
+        let dest = dest.into();
+        match &dest {
+            Location::Register(Register::CS) => Err(InvalidStackOperand {
+                mnemonic: "pop",
+                operand: dest,
+            }),
+            Location::Register(reg) if reg.width() == 16 => Ok(Instruction::Pop(dest)),
+            Location::Memory(_) => Ok(Instruction::Pop(dest)),
+            _ => Err(InvalidStackOperand {
+                mnemonic: "pop",
+                operand: dest,
+            }),
+        }
+    }
+    /// Plain-English description, e.g. `mov bx, ax` is "Copy AX into BX"
+    pub fn explain(&self) -> String {
+        match self {
+            Instruction::Mov(src, dest) => {
+                format!("Copy {} into {}", describe(src), describe(dest))
+            }
+            Instruction::Add(src, dest) => format!("Add {} to {}", describe(src), describe(dest)),
+            Instruction::Adc(src, dest) => format!(
+                "Add {} and the carry flag to {}",
+                describe(src),
+                describe(dest)
+            ),
+            Instruction::Sub(src, dest) => {
+                format!("Subtract {} from {}", describe(src), describe(dest))
+            }
+            Instruction::Sbb(src, dest) => format!(
+                "Subtract {} and the carry flag from {}",
+                describe(src),
+                describe(dest)
+            ),
+            Instruction::Cmp(src, dest) => format!(
+                "Compare {} with {} by subtracting, setting flags without storing the result",
+                describe(dest),
+                describe(src)
+            ),
+            Instruction::And(src, dest) => format!(
+                "Bitwise AND {} into {}, clearing CF and OF",
+                describe(src),
+                describe(dest)
+            ),
+            Instruction::Or(src, dest) => format!(
+                "Bitwise OR {} into {}, clearing CF and OF",
+                describe(src),
+                describe(dest)
+            ),
+            Instruction::Xor(src, dest) => format!(
+                "Bitwise XOR {} into {}, clearing CF and OF",
+                describe(src),
+                describe(dest)
+            ),
+            Instruction::Test(src, dest) => format!(
+                "AND {} with {}, setting flags without storing the result",
+                describe(dest),
+                describe(src)
+            ),
+            Instruction::Xchg(
+                Location::Register(Register::AX),
+                Location::Register(Register::AX),
+            ) => "Do nothing".to_string(),
+            Instruction::Xchg(src, dest) => {
+                format!("Swap {} and {}", describe(dest), describe(src))
+            }
+            Instruction::Jump(JumpType::Jmp, disp) => {
+                format!("Jump {}", describe_jump(*disp as i16))
+            }
+            Instruction::Jump(ty, disp) => format!(
+                "{} {} {}",
+                ty.action(),
+                describe_jump(*disp as i16),
+                ty.condition()
+            ),
+            Instruction::JumpNear(disp) => format!("Jump {}", describe_jump(*disp)),
+            Instruction::Daa => "Adjust AL to packed BCD after an addition".to_string(),
+            Instruction::Aaa => {
+                "Adjust AL to unpacked BCD after an addition, carrying into AH".to_string()
+            }
+            Instruction::Inc(dest, _, amount) => {
+                format!("Add {} to {}", amount.unwrap_or(1), describe(dest))
+            }
+            Instruction::Dec(dest, _, amount) => {
+                format!("Subtract {} from {}", amount.unwrap_or(1), describe(dest))
+            }
+            Instruction::Bound(index, bounds) => format!(
+                "Raise interrupt 5 unless {} is within the signed bounds at {}",
+                index.to_string().to_uppercase(),
+                bounds
+            ),
+            Instruction::Lea(dest, address) => format!(
+                "Load the address {} into {}",
+                address,
+                dest.to_string().to_uppercase()
+            ),
+            Instruction::Push(src) => format!("Push {} onto the stack", describe(src)),
+            Instruction::Pop(dest) => format!("Pop the top of the stack into {}", describe(dest)),
+            Instruction::Salc => "Set AL to 0xFF if the carry flag is set, otherwise 0".to_string(),
+            Instruction::Shift(ty, dest, _, count) => {
+                let count = match count {
+                    ShiftCount::One => "1",
+                    ShiftCount::Cl => "CL",
+                };
+                let dest = describe(dest);
+                match ty {
+                    ShiftType::Rol => format!("Rotate {} left by {}", dest, count),
+                    ShiftType::Ror => format!("Rotate {} right by {}", dest, count),
+                    ShiftType::Rcl => {
+                        format!("Rotate {} left through the carry flag by {}", dest, count)
+                    }
+                    ShiftType::Rcr => {
+                        format!("Rotate {} right through the carry flag by {}", dest, count)
+                    }
+                    ShiftType::Shl => format!("Shift {} left by {}", dest, count),
+                    ShiftType::Shr => {
+                        format!("Shift {} right by {}, filling with zeros", dest, count)
+                    }
+                    ShiftType::Sar => {
+                        format!("Shift {} right by {}, keeping its sign", dest, count)
+                    }
+                }
+            }
+            Instruction::Not(dest, _) => format!("Complement every bit of {}", describe(dest)),
+            Instruction::Neg(dest, _) => format!("Negate {}", describe(dest)),
+            Instruction::Mul(src, 8) => format!("Multiply AL by {} into AX", describe(src)),
+            Instruction::Mul(src, _) => format!("Multiply AX by {} into DX:AX", describe(src)),
+            Instruction::Imul(src, 8) => {
+                format!("Multiply AL by {} as signed numbers into AX", describe(src))
+            }
+            Instruction::Imul(src, _) => {
+                format!(
+                    "Multiply AX by {} as signed numbers into DX:AX",
+                    describe(src)
+                )
+            }
+            Instruction::Div(src, 8) => format!(
+                "Divide AX by {}, quotient in AL and remainder in AH",
+                describe(src)
+            ),
+            Instruction::Div(src, _) => format!(
+                "Divide DX:AX by {}, quotient in AX and remainder in DX",
+                describe(src)
+            ),
+            Instruction::Idiv(src, 8) => format!(
+                "Divide AX by {} as signed numbers, quotient in AL and remainder in AH",
+                describe(src)
+            ),
+            Instruction::Idiv(src, _) => format!(
+                "Divide DX:AX by {} as signed numbers, quotient in AX and remainder in DX",
+                describe(src)
+            ),
+            Instruction::JumpFar(pointer) => {
+                format!("Jump to the segment:offset pointer at {}", pointer)
+            }
+            Instruction::CallFar(pointer) => format!(
+                "Push CS and IP, then jump to the segment:offset pointer at {}",
+                pointer
+            ),
+            Instruction::JumpFarDirect(segment, offset) => {
+                format!("Jump to {:04X}:{:04X}", segment, offset)
+            }
+            Instruction::CallFarDirect(segment, offset) => format!(
+                "Push CS and IP, then jump to {:04X}:{:04X}",
+                segment, offset
+            ),
+            Instruction::RetFar(None) => "Pop IP and CS to return from a far call".to_string(),
+            Instruction::RetFar(Some(bytes)) => format!(
+                "Pop IP and CS to return from a far call, then discard {} bytes of arguments",
+                bytes
+            ),
+            Instruction::Call(disp) => format!("Push IP and jump {}", describe_jump(*disp)),
+            Instruction::Ret(None) => "Pop IP to return from a near call".to_string(),
+            Instruction::Ret(Some(bytes)) => format!(
+                "Pop IP to return from a near call, then discard {} bytes of arguments",
+                bytes
+            ),
+            Instruction::Hlt => "Halt the processor".to_string(),
+            Instruction::StringOp(ty, width, segment, repeat) => {
+                let size = if *width == 8 { "byte" } else { "word" };
+                let source = segment.as_ref().unwrap_or(&Register::DS);
+                let source = source.to_string().to_uppercase();
+                let accumulator = if *width == 8 { "AL" } else { "AX" };
+                let once = match ty {
+                    StringType::Movs => format!(
+                        "copy the {} at {}:SI to ES:DI, then step SI and DI",
+                        size, source
+                    ),
+                    StringType::Cmps => format!(
+                        "compare the {} at {}:SI with the one at ES:DI, then step SI and DI",
+                        size, source
+                    ),
+                    StringType::Stos => {
+                        format!("store {} at ES:DI, then step DI", accumulator)
+                    }
+                    StringType::Scas => format!(
+                        "compare {} with the {} at ES:DI, then step DI",
+                        accumulator, size
+                    ),
+                };
+                match repeat {
+                    None => once[..1].to_uppercase() + &once[1..],
+                    Some(_) if !ty.compares() => {
+                        format!("Until CX is 0, {} and decrement CX", once)
+                    }
+                    Some(repeat) => format!(
+                        "Until CX is 0 or they are {}, {} and decrement CX",
+                        if matches!(repeat, Repeat::Rep) {
+                            "unequal"
+                        } else {
+                            "equal"
+                        },
+                        once
+                    ),
+                }
+            }
+            Instruction::Cld => {
+                "Clear the direction flag, so string instructions step up".to_string()
+            }
+            Instruction::Std => {
+                "Set the direction flag, so string instructions step down".to_string()
+            }
+            Instruction::Clc => "Clear the carry flag".to_string(),
+            Instruction::Stc => "Set the carry flag".to_string(),
+            Instruction::Cmc => "Complement the carry flag".to_string(),
+            Instruction::Cli => "Disable external interrupts".to_string(),
+            Instruction::Sti => "Enable external interrupts".to_string(),
+            Instruction::Pushf => "Push the flags onto the stack".to_string(),
+            Instruction::Popf => "Pop the flags from the stack".to_string(),
+            Instruction::Lahf => "Load the low byte of the flags into AH".to_string(),
+            Instruction::Sahf => "Store AH into the low byte of the flags".to_string(),
+            Instruction::Cbw => "Sign-extend AL into AX".to_string(),
+            Instruction::Cwd => "Sign-extend AX into DX:AX".to_string(),
+            Instruction::In(width, port) => format!(
+                "Read {} from port {}",
+                if *width == 8 { "AL" } else { "AX" },
+                port.to_string().to_uppercase()
+            ),
+            Instruction::Out(port, width) => format!(
+                "Write {} to port {}",
+                if *width == 8 { "AL" } else { "AX" },
+                port.to_string().to_uppercase()
+            ),
+            Instruction::Int(vector) => format!("Raise interrupt {}", vector),
+            Instruction::Int3 => "Raise the breakpoint interrupt 3".to_string(),
+            Instruction::Into => "Raise interrupt 4 if OF is set".to_string(),
+            Instruction::Iret => "Pop IP, CS and the flags to return from an interrupt".to_string(),
+        }
+    }
+    pub(crate) fn memory_operands(&self) -> Vec<&Memory> {
+        let operands = match self {
+            Instruction::Mov(src, dest)
+            | Instruction::Add(src, dest)
+            | Instruction::Adc(src, dest)
+            | Instruction::Sbb(src, dest)
+            | Instruction::Sub(src, dest)
+            | Instruction::Cmp(src, dest)
+            | Instruction::And(src, dest)
+            | Instruction::Or(src, dest)
+            | Instruction::Xor(src, dest)
+            | Instruction::Test(src, dest)
+            | Instruction::Xchg(src, dest) => vec![src, dest],
+            Instruction::Inc(dest, _, _) | Instruction::Dec(dest, _, _) => vec![dest],
+            Instruction::Push(operand)
+            | Instruction::Pop(operand)
+            | Instruction::Shift(_, operand, _, _)
+            | Instruction::Not(operand, _)
+            | Instruction::Neg(operand, _)
+            | Instruction::Mul(operand, _)
+            | Instruction::Imul(operand, _)
+            | Instruction::Div(operand, _)
+            | Instruction::Idiv(operand, _) => vec![operand],
+            Instruction::Bound(_, pointer)
+            | Instruction::Lea(_, pointer)
+            | Instruction::JumpFar(pointer)
+            | Instruction::CallFar(pointer) => return vec![pointer],
+            _ => vec![],
+        };
+        operands
+            .into_iter()
+            .filter_map(|operand| match operand {
+                Location::Memory(memory) => Some(memory),
+                _ => None,
+            })
+            .collect()
+    }
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Instruction::Mov(_, _) => "mov",
+            Instruction::Add(_, _) => "add",
+            Instruction::Adc(_, _) => "adc",
+            Instruction::Sbb(_, _) => "sbb",
+            Instruction::Sub(_, _) => "sub",
+            Instruction::Cmp(_, _) => "cmp",
+            Instruction::And(_, _) => "and",
+            Instruction::Or(_, _) => "or",
+            Instruction::Xor(_, _) => "xor",
+            Instruction::Test(_, _) => "test",
+            Instruction::Xchg(
+                Location::Register(Register::AX),
+                Location::Register(Register::AX),
+            ) => "nop",
+            Instruction::Xchg(_, _) => "xchg",
+            Instruction::Jump(ty, _) => ty.mnemonic(),
+            Instruction::JumpNear(_) => "jmp",
+            Instruction::Daa => "daa",
+            Instruction::Aaa => "aaa",
+            Instruction::Inc(_, _, _) => "inc",
+            Instruction::Dec(_, _, _) => "dec",
+            Instruction::Bound(_, _) => "bound",
+            Instruction::Lea(_, _) => "lea",
+            Instruction::Push(_) => "push",
+            Instruction::Pop(_) => "pop",
+            Instruction::Salc => "salc",
+            Instruction::Shift(ty, _, _, _) => ty.mnemonic(),
+            Instruction::Not(_, _) => "not",
+            Instruction::Neg(_, _) => "neg",
+            Instruction::Mul(_, _) => "mul",
+            Instruction::Imul(_, _) => "imul",
+            Instruction::Div(_, _) => "div",
+            Instruction::Idiv(_, _) => "idiv",
+            Instruction::JumpFar(_) => "jmp",
+            Instruction::CallFar(_) => "call",
+            Instruction::JumpFarDirect(_, _) => "jmp",
+            Instruction::CallFarDirect(_, _) => "call",
+            Instruction::RetFar(_) => "retf",
+            Instruction::Call(_) => "call",
+            Instruction::Ret(_) => "ret",
+            Instruction::Hlt => "hlt",
+            Instruction::StringOp(ty, width, _, _) => ty.mnemonic(*width),
+            Instruction::Cld => "cld",
+            Instruction::Std => "std",
+            Instruction::Clc => "clc",
+            Instruction::Stc => "stc",
+            Instruction::Cmc => "cmc",
+            Instruction::Cli => "cli",
+            Instruction::Sti => "sti",
+            Instruction::Pushf => "pushf",
+            Instruction::Popf => "popf",
+            Instruction::Lahf => "lahf",
+            Instruction::Sahf => "sahf",
+            Instruction::Cbw => "cbw",
+            Instruction::Cwd => "cwd",
+            Instruction::In(_, _) => "in",
+            Instruction::Out(_, _) => "out",
+            Instruction::Int(_) => "int",
+            Instruction::Int3 => "int3",
+            Instruction::Into => "into",
+            Instruction::Iret => "iret",
+        }
+    }
+}
+
+/// An operand as it reads in a sentence: `AX`, `the value at [bx]` or `5`
+fn describe(location: &Location) -> String {
+    match location {
+        Location::Register(reg) => reg.to_string().to_uppercase(),
+        Location::Memory(memory) => format!("the value at {}", memory),
+        Location::Immediate(immediate) => immediate.data.to_string(),
+    }
+}
+
+fn describe_jump(disp: i16) -> String {
+    match disp {
+        0 => "to the next instruction".to_string(),
+        disp if disp > 0 => format!("{} bytes forward", disp),
+        disp => format!("{} bytes back", disp.unsigned_abs()),
+    }
+}
+
+/// A lone operand, with `byte`/`word` in front of memory whose size nothing else implies
+struct Sized<'a>(&'a Location, u8);
+
+impl Display for Sized<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.0, self.1) {
+            (Location::Memory(memory), 8) => write!(f, "byte {}", memory),
+            (Location::Memory(memory), _) => write!(f, "word {}", memory),
+            (location, _) => write!(f, "{}", location),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InvalidStackOperand {
+    pub mnemonic: &'static str,
+    pub operand: Location,
+}
+
+impl Display for InvalidStackOperand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid stack operand in `{} {}`",
+            self.mnemonic, self.operand
+        )
+    }
+}
+
+impl std::error::Error for InvalidStackOperand {}
+
 impl Display for Instruction {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -192,33 +848,99 @@ impl Display for Instruction {
             Instruction::Sbb(src, dest) => write!(f, "sbb {}, {}", dest, src),
             Instruction::Sub(src, dest) => write!(f, "sub {}, {}", dest, src),
             Instruction::Cmp(src, dest) => write!(f, "cmp {}, {}", dest, src),
-            Instruction::Jump(instruction, disp) => write!(
-                f,
-                "{} label_{} ; {}",
-                instruction,
-                {
-                    unsafe {
-                        LABEL_COUNTER += 1;
-                        LABEL_COUNTER
-                    }
-                },
-                disp
-            ),
+            Instruction::And(src, dest) => write!(f, "and {}, {}", dest, src),
+            Instruction::Or(src, dest) => write!(f, "or {}, {}", dest, src),
+            Instruction::Xor(src, dest) => write!(f, "xor {}, {}", dest, src),
+            Instruction::Test(src, dest) => write!(f, "test {}, {}", dest, src),
+            Instruction::Xchg(
+                Location::Register(Register::AX),
+                Location::Register(Register::AX),
+            ) => write!(f, "nop"),
+            Instruction::Xchg(src, dest) => write!(f, "xchg {}, {}", dest, src),
+            // Relative to the jump's own address like NASM's `$`, which is the
+            // displacement plus the length of the jump
+            Instruction::Jump(instruction, disp) => {
+                write!(f, "{} ${:+}", instruction, *disp as i16 + 2)
+            }
+            Instruction::JumpNear(disp) => write!(f, "jmp ${:+}", *disp as i32 + 3),
             Instruction::Daa => write!(f, "daa"),
             Instruction::Aaa => write!(f, "aaa"),
-            Instruction::Inc(dest, amount) => {
+            Instruction::Inc(dest, width, amount) => {
                 if let Some(amount) = amount {
-                    write!(f, "inc {}, {}", dest, amount)
+                    write!(f, "inc {}, {}", Sized(dest, *width), amount)
                 } else {
-                    write!(f, "inc {}", dest)
+                    write!(f, "inc {}", Sized(dest, *width))
                 }
             }
-            Instruction::Dec(dest, amount) => {
+            Instruction::Dec(dest, width, amount) => {
                 if let Some(amount) = amount {
-                    write!(f, "dec {}, {}", dest, amount)
+                    write!(f, "dec {}, {}", Sized(dest, *width), amount)
                 } else {
-                    write!(f, "dec {}", dest)
+                    write!(f, "dec {}", Sized(dest, *width))
+                }
+            }
+            Instruction::Bound(index, bounds) => write!(f, "bound {}, {}", index, bounds),
+            Instruction::Lea(dest, address) => write!(f, "lea {}, {}", dest, address),
+            Instruction::Push(src) => write!(f, "push {}", Sized(src, 16)),
+            Instruction::Pop(dest) => write!(f, "pop {}", Sized(dest, 16)),
+            Instruction::Salc => write!(f, "salc"),
+            Instruction::Not(src, width)
+            | Instruction::Neg(src, width)
+            | Instruction::Mul(src, width)
+            | Instruction::Imul(src, width)
+            | Instruction::Div(src, width)
+            | Instruction::Idiv(src, width) => {
+                write!(f, "{} {}", self.mnemonic(), Sized(src, *width))
+            }
+            Instruction::Shift(ty, dest, width, count) => {
+                write!(f, "{} {}, {}", ty.mnemonic(), Sized(dest, *width), count)
+            }
+            Instruction::JumpFar(pointer) => write!(f, "jmp far {}", pointer),
+            Instruction::CallFar(pointer) => write!(f, "call far {}", pointer),
+            Instruction::JumpFarDirect(segment, offset) => {
+                write!(f, "jmp {:#06x}:{:#06x}", segment, offset)
+            }
+            Instruction::CallFarDirect(segment, offset) => {
+                write!(f, "call {:#06x}:{:#06x}", segment, offset)
+            }
+            Instruction::RetFar(None) => write!(f, "retf"),
+            Instruction::RetFar(Some(bytes)) => write!(f, "retf {}", bytes),
+            Instruction::Call(disp) => write!(f, "call ${:+}", *disp as i32 + 3),
+            Instruction::Ret(None) => write!(f, "ret"),
+            Instruction::Ret(Some(bytes)) => write!(f, "ret {}", bytes),
+            Instruction::Hlt => write!(f, "hlt"),
+            Instruction::StringOp(ty, _, segment, repeat) => {
+                if let Some(repeat) = repeat {
+                    write!(f, "{} ", repeat.mnemonic(ty))?;
+                }
+                // NASM's spelling of an override on an instruction without operands
+                if let Some(segment) = segment {
+                    write!(f, "{} ", segment)?;
                 }
+                write!(f, "{}", self.mnemonic())
+            }
+            Instruction::Cld
+            | Instruction::Std
+            | Instruction::Clc
+            | Instruction::Stc
+            | Instruction::Cmc
+            | Instruction::Cli
+            | Instruction::Sti
+            | Instruction::Pushf
+            | Instruction::Popf
+            | Instruction::Lahf
+            | Instruction::Sahf
+            | Instruction::Cbw
+            | Instruction::Cwd
+            | Instruction::Int3
+            | Instruction::Into
+            | Instruction::Iret => write!(f, "{}", self.mnemonic()),
+            Instruction::Int(vector) => write!(f, "int {}", vector),
+            Instruction::In(width, port) => {
+                write!(f, "in {}, {}", if *width == 8 { "al" } else { "ax" }, port)
+            }
+            Instruction::Out(port, width) => {
+                write!(f, "out {}, {}", port, if *width == 8 { "al" } else { "ax" })
             }
         }
     }
@@ -246,11 +968,46 @@ pub enum JumpType {
     Jnloopzs,
     Loopnz,
     Jcxz,
+    Jmp,
 }
 
-impl Display for JumpType {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let out = match self {
+impl JumpType {
+    fn action(&self) -> &'static str {
+        match self {
+            JumpType::Loop | JumpType::Jnloopzs | JumpType::Loopnz => "Decrement CX and jump",
+            _ => "Jump",
+        }
+    }
+    /// When the jump is taken, e.g. "if equal (zero flag set)"
+    pub fn condition(&self) -> &'static str {
+        match self {
+            JumpType::Je => "if equal (zero flag set)",
+            JumpType::Jne => "if not equal (zero flag clear)",
+            JumpType::Jl => "if less (sign flag differs from overflow flag)",
+            JumpType::Jle => {
+                "if less or equal (zero flag set, or sign flag differs from overflow flag)"
+            }
+            JumpType::Jnl => "if not less (sign flag equals overflow flag)",
+            JumpType::Jnle => "if greater (zero flag clear and sign flag equals overflow flag)",
+            JumpType::Jb => "if below (carry flag set)",
+            JumpType::Jbe => "if below or equal (carry or zero flag set)",
+            JumpType::Jnb => "if not below (carry flag clear)",
+            JumpType::Jnbe => "if above (carry and zero flags clear)",
+            JumpType::Jp => "if parity flag set",
+            JumpType::Jnp => "if parity flag clear",
+            JumpType::Jo => "if overflow flag set",
+            JumpType::Jno => "if overflow flag clear",
+            JumpType::Js => "if sign flag set",
+            JumpType::Jns => "if sign flag clear",
+            JumpType::Loop => "if CX is not zero",
+            JumpType::Jnloopzs => "if CX is not zero and the zero flag is set",
+            JumpType::Loopnz => "if CX is not zero and the zero flag is clear",
+            JumpType::Jcxz => "if CX is zero",
+            JumpType::Jmp => "always",
+        }
+    }
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
             JumpType::Je => "je",
             JumpType::Jl => "jl",
             JumpType::Jle => "jle",
@@ -268,10 +1025,251 @@ impl Display for JumpType {
             JumpType::Jno => "jno",
             JumpType::Jns => "jns",
             JumpType::Loop => "loop",
-            JumpType::Jnloopzs => "jnloopzs",
+            JumpType::Jnloopzs => "loopz",
             JumpType::Loopnz => "loopnz",
             JumpType::Jcxz => "jcxz",
+            JumpType::Jmp => "jmp",
+        }
+    }
+}
+
+/// Shifts and rotates in the order of their reg field, without the undocumented 110
+#[derive(Debug)]
+pub enum ShiftType {
+    Rol,
+    Ror,
+    Rcl,
+    Rcr,
+    Shl,
+    Shr,
+    Sar,
+}
+
+impl ShiftType {
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            ShiftType::Rol => "rol",
+            ShiftType::Ror => "ror",
+            ShiftType::Rcl => "rcl",
+            ShiftType::Rcr => "rcr",
+            ShiftType::Shl => "shl",
+            ShiftType::Shr => "shr",
+            ShiftType::Sar => "sar",
+        }
+    }
+}
+
+/// String instructions, which address memory through SI and DI
+#[derive(Debug)]
+pub enum StringType {
+    Movs,
+    Cmps,
+    Stos,
+    Scas,
+}
+
+impl StringType {
+    /// Mnemonic with the `b` or `w` suffix for `width`
+    pub fn mnemonic(&self, width: u8) -> &'static str {
+        match (self, width) {
+            (StringType::Movs, 8) => "movsb",
+            (StringType::Movs, _) => "movsw",
+            (StringType::Cmps, 8) => "cmpsb",
+            (StringType::Cmps, _) => "cmpsw",
+            (StringType::Stos, 8) => "stosb",
+            (StringType::Stos, _) => "stosw",
+            (StringType::Scas, 8) => "scasb",
+            (StringType::Scas, _) => "scasw",
+        }
+    }
+    /// Whether it sets the flags, which a repeat prefix then also stops on
+    pub fn compares(&self) -> bool {
+        matches!(self, StringType::Cmps | StringType::Scas)
+    }
+}
+
+/// Repeat prefix of a string instruction, running it CX times. The comparing ones
+/// also stop early, on a mismatch for 0xF3 and on a match for 0xF2.
+#[derive(Debug)]
+pub enum Repeat {
+    /// 0xF3, `rep`, or `repe` on the comparing instructions
+    Rep,
+    /// 0xF2
+    Repne,
+}
+
+impl Repeat {
+    pub fn mnemonic(&self, ty: &StringType) -> &'static str {
+        match self {
+            Repeat::Rep if ty.compares() => "repe",
+            Repeat::Rep => "rep",
+            Repeat::Repne => "repne",
+        }
+    }
+}
+
+/// Port of `in` and `out`, a fixed one below 256 or the one in DX
+#[derive(Debug)]
+pub enum Port {
+    Immediate(u8),
+    Dx,
+}
+
+impl Display for Port {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Port::Immediate(port) => write!(f, "{}", port),
+            Port::Dx => write!(f, "dx"),
+        }
+    }
+}
+
+/// How far a shift or rotate moves its operand
+#[derive(Debug)]
+pub enum ShiftCount {
+    One,
+    Cl,
+}
+
+impl Display for ShiftCount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShiftCount::One => write!(f, "1"),
+            ShiftCount::Cl => write!(f, "cl"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownJumpType(pub String);
+
+impl Display for UnknownJumpType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown jump `{}`", self.0)
+    }
+}
+
+impl std::error::Error for UnknownJumpType {}
+
+impl FromStr for JumpType {
+    type Err = UnknownJumpType;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ty = match s {
+            "je" | "jz" => JumpType::Je,
+            "jl" | "jnge" => JumpType::Jl,
+            "jle" | "jng" => JumpType::Jle,
+            "jb" | "jnae" | "jc" => JumpType::Jb,
+            "jbe" | "jna" => JumpType::Jbe,
+            "jp" | "jpe" => JumpType::Jp,
+            "jo" => JumpType::Jo,
+            "js" => JumpType::Js,
+            "jne" | "jnz" => JumpType::Jne,
+            "jnl" | "jge" => JumpType::Jnl,
+            "jnle" | "jg" => JumpType::Jnle,
+            "jnb" | "jae" | "jnc" => JumpType::Jnb,
+            "jnbe" | "ja" => JumpType::Jnbe,
+            "jnp" | "jpo" => JumpType::Jnp,
+            "jno" => JumpType::Jno,
+            "jns" => JumpType::Jns,
+            "loop" => JumpType::Loop,
+            "loopz" | "loope" => JumpType::Jnloopzs,
+            "loopnz" | "loopne" => JumpType::Loopnz,
+            "jcxz" => JumpType::Jcxz,
+            "jmp" => JumpType::Jmp,
+            _ => return Err(UnknownJumpType(s.to_string())),
         };
-        write!(f, "{}", out)
+        Ok(ty)
+    }
+}
+
+impl Display for JumpType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.mnemonic())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registers_parse_from_their_display_names() {
+        use Register::*;
+        for reg in [
+            AL, CL, DL, BL, AH, CH, DH, BH, AX, CX, DX, BX, SP, BP, SI, DI, SS, DS, ES, CS,
+        ] {
+            let name = reg.to_string();
+            assert_eq!(name.parse::<Register>(), Ok(reg), "parsing `{}`", name);
+        }
+        assert_eq!(
+            "eax".parse::<Register>(),
+            Err(UnknownRegister("eax".to_string()))
+        );
+        assert!("AX".parse::<Register>().is_err());
+    }
+
+    #[test]
+    fn jump_types_round_trip_and_accept_aliases() {
+        for mnemonic in [
+            "je", "jl", "jle", "jb", "jbe", "jp", "jo", "js", "jne", "jnl", "jnle", "jnb", "jnbe",
+            "jnp", "jno", "jns", "loop", "loopz", "loopnz", "jcxz", "jmp",
+        ] {
+            let ty: JumpType = mnemonic.parse().unwrap();
+            assert_eq!(ty.to_string(), mnemonic);
+        }
+        for (alias, mnemonic) in [
+            ("jz", "je"),
+            ("jnz", "jne"),
+            ("jc", "jb"),
+            ("jnae", "jb"),
+            ("jae", "jnb"),
+            ("jnc", "jnb"),
+            ("ja", "jnbe"),
+            ("jna", "jbe"),
+            ("jg", "jnle"),
+            ("jge", "jnl"),
+            ("jng", "jle"),
+            ("jnge", "jl"),
+            ("jpe", "jp"),
+            ("jpo", "jnp"),
+            ("loope", "loopz"),
+            ("loopne", "loopnz"),
+        ] {
+            let ty: JumpType = alias.parse().unwrap();
+            assert_eq!(ty.to_string(), mnemonic, "parsing `{}`", alias);
+        }
+        assert_eq!(
+            "jnloopzs".parse::<JumpType>().unwrap_err(),
+            UnknownJumpType("jnloopzs".to_string())
+        );
+    }
+
+    #[test]
+    fn push_and_pop_reject_operands_the_stack_cannot_hold() {
+        assert!(Instruction::push(Register::AX).is_ok());
+        assert!(Instruction::pop(Memory::new(Some(Register::BX), None, 2)).is_ok());
+        assert!(Instruction::push(Register::CS).is_ok());
+        let error = Instruction::push(Register::AL).unwrap_err();
+        assert_eq!(error.to_string(), "invalid stack operand in `push al`");
+        assert!(Instruction::pop(Register::CS).is_err());
+        assert!(Instruction::pop(Register::BH).is_err());
+        let immediate = Immediate { data: 5, w: None };
+        assert!(Instruction::pop(immediate).is_err());
+    }
+
+    #[test]
+    fn explain_describes_the_effect_in_plain_english() {
+        use crate::decoder::decode;
+
+        let explain = |bytes: &[u8]| decode(bytes).unwrap().0.explain();
+        assert_eq!(explain(&[0x89, 0xC3]), "Copy AX into BX"); // mov bx, ax
+        assert_eq!(explain(&[0x05, 0x05, 0x00]), "Add 5 to AX"); // add ax, 5
+        assert_eq!(
+            explain(&[0x74, 0x03]),
+            "Jump 3 bytes forward if equal (zero flag set)"
+        ); // je $+5
+        assert_eq!(explain(&[0xEB, 0xFE]), "Jump 2 bytes back"); // jmp $
+        assert_eq!(explain(&[0xFE, 0x07]), "Add 1 to the value at [bx]"); // inc byte [bx]
     }
 }
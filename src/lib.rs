@@ -1,3 +1,7 @@
 pub mod cpu;
 pub mod decoder;
+pub mod disassembler;
+pub mod encoder;
 pub mod instruction;
+pub mod ports;
+pub mod trace;
@@ -1,30 +1,165 @@
 use std::{
     fs::File,
-    io::{BufReader, BufWriter},
+    io::{self, BufReader, BufWriter},
     path::Path,
 };
 
-use simulator_8086::cpu::Cpu;
+use simulator_8086::{
+    cpu::{Cpu, RunOutcome, StopReason},
+    disassembler::{disassemble, disassemble_annotated, DisplayOptions},
+    trace::{JsonTrace, Listing, Printer, Symbolic},
+};
+
+type Error = Box<dyn std::error::Error>;
+
+/// Guards against hanging the terminal on a program that never stops
+const DEFAULT_MAX_STEPS: usize = 1_000_000;
+
+const USAGE: &str =
+    "usage: simulator-8086 [--disasm | --listing | --trace-json | --symbolic] [--annotate] [--max-steps N] <program>
+
+  --disasm         print the program as NASM source instead of running it
+
+  --listing        print address, bytes and mnemonic for each executed instruction
+  --trace-json     print a JSON object per executed instruction with the registers
+                   and flags after it, and nothing else on stdout
+  --symbolic       like the default trace, but explain comparisons, e.g. `-> above`
+  --annotate       comment idioms in --disasm and --listing output, e.g. `; ax = 0`
+  --max-steps N    stop after N instructions (default 1000000, 0 for no limit)";
+
+/// What is printed while the program runs
+enum Trace {
+    /// Every value an instruction changes
+    Changes,
+    /// A hex bytes + mnemonic line per executed instruction
+    Listing,
+    /// A JSON object per executed instruction, for scripts
+    Json,
+    /// Changed values, with comparisons spelled out
+    Symbolic,
+}
+
+struct Options {
+    disassemble: bool,
+    /// Comment recognized idioms like `xor ax, ax`
+    annotate: bool,
+    trace: Trace,
+    max_steps: usize,
+}
+
+fn parse_args() -> Option<(String, Options)> {
+    let mut options = Options {
+        disassemble: false,
+        annotate: false,
+        trace: Trace::Changes,
+        max_steps: DEFAULT_MAX_STEPS,
+    };
+    let mut path = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--disasm" => options.disassemble = true,
+            "--listing" => options.trace = Trace::Listing,
+            "--trace-json" => options.trace = Trace::Json,
+            "--symbolic" => options.trace = Trace::Symbolic,
+            "--annotate" => options.annotate = true,
+            "--max-steps" => {
+                options.max_steps = match args.next()?.parse().ok()? {
+                    0 => usize::MAX,
+                    n => n,
+                }
+            }
+            _ => path = Some(arg),
+        }
+    }
+    Some((path?, options))
+}
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    if let Err(e) = run(&args[1]) {
-        eprint!("An error occurred {}", e);
+    let Some((path, options)) = parse_args() else {
+        eprintln!("{}", USAGE);
         std::process::exit(1);
+    };
+    if options.disassemble {
+        match disassemble_file(path, options.annotate) {
+            Ok(source) => print!("{}", source),
+            Err(e) => {
+                eprint!("An error occurred {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    match run(path, &options) {
+        Ok(outcome) => {
+            let summary = format!(
+                "stopped: {:?} at ip {:#06x} after {} instructions",
+                outcome.reason, outcome.ip, outcome.instructions
+            );
+            // Keep stdout pure JSON lines for scripts reading the trace
+            if matches!(options.trace, Trace::Json) {
+                eprintln!("{}", summary);
+            } else {
+                println!("{}", summary);
+            }
+            if outcome.reason == StopReason::StepLimit {
+                eprintln!(
+                    "warning: step limit of {} reached, the program may be stuck in an infinite loop",
+                    outcome.instructions
+                );
+                std::process::exit(2);
+            }
+        }
+        Err(e) => {
+            eprint!("An error occurred {}", e);
+            std::process::exit(1);
+        }
     }
 }
 
-fn run(path: impl AsRef<Path>) -> Result<(), std::io::Error> {
+fn disassemble_file(path: impl AsRef<Path>, annotate: bool) -> Result<String, Error> {
+    let bytes = std::fs::read(path)?;
+    if annotate {
+        Ok(disassemble_annotated(&bytes)?)
+    } else {
+        Ok(disassemble(&bytes)?)
+    }
+}
+
+fn run(path: impl AsRef<Path>, options: &Options) -> Result<RunOutcome, Error> {
     let file = File::open(path)?;
     let reader = BufReader::new(file);
-    let mut cpu = Cpu::new(reader);
-    cpu.run();
+    let mut cpu = Cpu::load(reader)?;
+    match options.trace {
+        Trace::Changes => cpu.set_tracer(Printer),
+        Trace::Listing => cpu.set_tracer(Listing::with_options(
+            io::stdout(),
+            DisplayOptions {
+                annotate: options.annotate,
+                ..DisplayOptions::default()
+            },
+        )),
+        Trace::Json => cpu.set_tracer(JsonTrace::stdout()),
+        Trace::Symbolic => cpu.set_tracer(Symbolic),
+    }
+    let outcome = cpu
+        .run_with_limit(options.max_steps)
+        .map_err(|e| -> Error {
+            match cpu.last_instruction() {
+                Some((ip, instruction)) => {
+                    format!("{} (after `{}` at {:#06x})", e, instruction, ip).into()
+                }
+                None => e.into(),
+            }
+        })?;
 
-    cpu.print_registers();
+    if !matches!(options.trace, Trace::Json) {
+        cpu.print_registers();
+    }
 
     let mut file = BufWriter::new(File::create("output.data")?);
 
     cpu.dump_memory(&mut file)?;
 
-    Ok(())
+    Ok(outcome)
 }
@@ -0,0 +1,14 @@
+/// The I/O address space `in` and `out` reach, for wiring up peripherals.
+/// Unconnected ports read as 0 and ignore writes.
+pub trait PortBus {
+    /// Read a byte, or a word if `word` is set, from `port`
+    fn read(&mut self, _port: u16, _word: bool) -> u16 {
+        0
+    }
+    fn write(&mut self, _port: u16, _word: bool, _value: u16) {}
+}
+
+/// A bus with nothing connected, which a `Cpu` starts out with
+pub struct NoPorts;
+
+impl PortBus for NoPorts {}
@@ -0,0 +1,603 @@
+use std::{
+    cmp::Ordering,
+    fmt::Display,
+    io::{self, Write},
+};
+
+use crate::{
+    cpu::{CpuState, Flags, REGISTER_NAMES},
+    disassembler::DisplayOptions,
+    instruction::{Instruction, Location},
+};
+
+/// Something observable that happened during execution
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// An arithmetic result wrapped around its width, which the hardware does silently
+    Wraparound {
+        ip: u16,
+        mnemonic: &'static str,
+        result: u16,
+    },
+    /// An instruction computed a new value for `dest`. `test` computes `after` without
+    /// storing it, and `flags` is set for instructions that update them.
+    Operation {
+        mnemonic: &'static str,
+        dest: Location,
+        before: u16,
+        after: u16,
+        flags: Option<Flags>,
+    },
+    /// An instruction read or wrote `width` bits of memory at physical `address`
+    MemoryAccess {
+        address: u32,
+        width: u8,
+        value: u16,
+        write: bool,
+    },
+    /// A word access at an odd address, which takes two bus cycles instead of one
+    MisalignedAccess { ip: u16, address: u32, write: bool },
+    /// `cmp` set the flags from `left - right` without storing the result
+    Compare {
+        mnemonic: &'static str,
+        left: Location,
+        right: Location,
+        left_value: u16,
+        right_value: u16,
+        width: u8,
+        result: u16,
+        flags: Flags,
+    },
+}
+
+/// How the operands of a comparison relate, read as unsigned (above/below)
+/// and as signed (greater/less) numbers like the conditional jumps do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Relation {
+    pub unsigned: Ordering,
+    pub signed: Ordering,
+}
+
+impl Relation {
+    pub fn new(left: u16, right: u16, width: u8) -> Self {
+        let signed = |value: u16| {
+            if width == 8 {
+                value as u8 as i8 as i16
+            } else {
+                value as i16
+            }
+        };
+        Self {
+            unsigned: left.cmp(&right),
+            signed: signed(left).cmp(&signed(right)),
+        }
+    }
+}
+
+impl Display for Relation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let unsigned = match self.unsigned {
+            Ordering::Equal => return write!(f, "equal"),
+            Ordering::Less => "below",
+            Ordering::Greater => "above",
+        };
+        let signed = match self.signed {
+            Ordering::Less => "less",
+            _ => "greater",
+        };
+        write!(f, "{}, {}", unsigned, signed)
+    }
+}
+
+/// Observes a running `Cpu`. Events are only produced while a tracer is installed.
+pub trait Tracer {
+    /// Called before each instruction executes, with its address and encoded bytes
+    fn instruction(&mut self, _ip: u16, _bytes: &[u8], _instruction: &Instruction) {}
+    /// Called after an instruction executed successfully
+    fn executed(&mut self, _state: &CpuState) {}
+    fn event(&mut self, _event: &Event) {}
+}
+
+/// Prints every value an instruction changes, e.g. `mov bx: 0x0000->0x0005`
+pub struct Printer;
+
+impl Tracer for Printer {
+    fn event(&mut self, event: &Event) {
+        if let Event::Operation {
+            mnemonic,
+            dest,
+            before,
+            after,
+            flags,
+        } = event
+        {
+            match flags {
+                Some(flags) => println!(
+                    "{} {}: {:#06x}->{:#06x} flags: {}",
+                    mnemonic, dest, before, after, flags
+                ),
+                None => println!("{} {}: {:#06x}->{:#06x}", mnemonic, dest, before, after),
+            }
+        }
+        if let Event::Compare {
+            mnemonic,
+            left,
+            left_value,
+            result,
+            flags,
+            ..
+        } = event
+        {
+            println!(
+                "{} {}: {:#06x}->{:#06x} flags: {}",
+                mnemonic, left, left_value, result, flags
+            );
+        }
+        if let Event::Wraparound {
+            ip,
+            mnemonic,
+            result,
+        } = event
+        {
+            println!(
+                "{} wrapped around to {:#06x} at {:#06x}",
+                mnemonic, result, ip
+            );
+        }
+    }
+}
+
+/// Like `Printer`, but spells out how compared values relate, e.g.
+/// `cmp ax(5), bx(3) -> above, greater (CF=0 ZF=0 SF=0 OF=0)`
+pub struct Symbolic;
+
+fn operand(location: &Location, value: u16) -> String {
+    match location {
+        Location::Immediate(_) => value.to_string(),
+        location => format!("{}({})", location, value),
+    }
+}
+
+impl Tracer for Symbolic {
+    fn event(&mut self, event: &Event) {
+        let Event::Compare {
+            mnemonic,
+            left,
+            right,
+            left_value,
+            right_value,
+            width,
+            flags,
+            ..
+        } = event
+        else {
+            return Printer.event(event);
+        };
+        println!(
+            "{} {}, {} -> {} (CF={} ZF={} SF={} OF={})",
+            mnemonic,
+            operand(left, *left_value),
+            operand(right, *right_value),
+            Relation::new(*left_value, *right_value, *width),
+            flags.cf as u8,
+            flags.zf as u8,
+            flags.sf as u8,
+            flags.of as u8
+        );
+    }
+}
+
+/// Writes a classic debug listing line per executed instruction, followed by the
+/// memory it accessed: `0100  88 47 02          mov [bx + 2], al ; write [0x0102] = 0x05`
+pub struct Listing<W> {
+    out: W,
+    options: DisplayOptions,
+    pending: Option<String>,
+    accesses: Vec<String>,
+}
+
+impl<W: Write> Listing<W> {
+    pub fn new(out: W) -> Self {
+        Self::with_options(out, DisplayOptions::default())
+    }
+    pub fn with_options(out: W, options: DisplayOptions) -> Self {
+        Self {
+            out,
+            options,
+            pending: None,
+            accesses: Vec::new(),
+        }
+    }
+}
+
+impl Listing<io::Stdout> {
+    pub fn stdout() -> Self {
+        Self::new(io::stdout())
+    }
+}
+
+impl<W: Write> Tracer for Listing<W> {
+    fn instruction(&mut self, ip: u16, bytes: &[u8], instruction: &Instruction) {
+        self.pending = Some(self.options.format_line(ip, bytes, instruction));
+        self.accesses.clear();
+    }
+    fn executed(&mut self, _state: &CpuState) {
+        let Some(line) = self.pending.take() else {
+            return;
+        };
+        let _ = if self.accesses.is_empty() {
+            writeln!(self.out, "{}", line)
+        } else {
+            writeln!(self.out, "{} ; {}", line, self.accesses.join(", "))
+        };
+    }
+    fn event(&mut self, event: &Event) {
+        if let Event::MemoryAccess {
+            address,
+            width,
+            value,
+            write,
+        } = event
+        {
+            let kind = if *write { "write" } else { "read" };
+            let value = if *width == 8 {
+                format!("{:#04x}", value)
+            } else {
+                format!("{:#06x}", value)
+            };
+            self.accesses
+                .push(format!("{} [{:#07x}] = {}", kind, address, value));
+        }
+    }
+}
+
+/// Writes one line per executed instruction in a stable format meant for diffing
+/// against traces from other emulators. Fields are separated by single tabs,
+/// e.g. `0003\t89C3\tmov bx, ax\tbx=0005\t-`:
+///
+/// 1. IP before execution, four uppercase hex digits
+/// 2. instruction bytes, uppercase hex without separators
+/// 3. the instruction as disassembled
+/// 4. `reg=value` for each register the instruction changed, in register file
+///    order and separated by spaces, or `-` if none changed
+/// 5. letters of the flags set afterwards in the order `CPAZSIDO`, or `-` if none
+pub struct TraceWriter<W> {
+    out: W,
+    pending: Option<String>,
+    previous: CpuState,
+}
+
+impl<W: Write> TraceWriter<W> {
+    /// `initial` is the state before the first instruction, e.g. `cpu.state()` once
+    /// the program is loaded, so setup isn't reported as changed by it
+    pub fn new(out: W, initial: CpuState) -> Self {
+        Self {
+            out,
+            pending: None,
+            previous: initial,
+        }
+    }
+}
+
+/// `Flags`' own letters, with `-` rather than nothing when no flag is set
+fn flag_letters(flags: &Flags) -> String {
+    let letters = flags.to_string();
+    if letters.is_empty() {
+        "-".to_string()
+    } else {
+        letters
+    }
+}
+
+impl<W: Write> Tracer for TraceWriter<W> {
+    fn instruction(&mut self, ip: u16, bytes: &[u8], instruction: &Instruction) {
+        let bytes: String = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        self.pending = Some(format!("{:04X}\t{}\t{}", ip, bytes, instruction));
+    }
+    fn executed(&mut self, state: &CpuState) {
+        let Some(line) = self.pending.take() else {
+            return;
+        };
+        let previous = self.previous;
+        let changes: Vec<String> = (0..state.registers.len())
+            .filter(|&i| state.registers[i] != previous.registers[i])
+            .map(|i| format!("{}={:04X}", REGISTER_NAMES[i], state.registers[i]))
+            .collect();
+        let changes = if changes.is_empty() {
+            "-".to_string()
+        } else {
+            changes.join(" ")
+        };
+        let _ = writeln!(
+            self.out,
+            "{}\t{}\t{}",
+            line,
+            changes,
+            flag_letters(&state.flags)
+        );
+        self.previous = *state;
+    }
+}
+
+/// Writes one JSON object per executed instruction, e.g.
+/// `{"ip":3,"bytes":"89C3","mnemonic":"mov","instruction":"mov bx, ax","regs_after":{"ax":5,..},"flags_after":{"cf":false,..}}`
+/// with registers and flags as they are after it executed. The JSON is written by
+/// hand rather than with serde to keep the crate free of dependencies.
+pub struct JsonTrace<W> {
+    out: W,
+    pending: Option<String>,
+}
+
+impl<W: Write> JsonTrace<W> {
+    pub fn new(out: W) -> Self {
+        Self { out, pending: None }
+    }
+}
+
+impl JsonTrace<io::Stdout> {
+    pub fn stdout() -> Self {
+        Self::new(io::stdout())
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl<W: Write> Tracer for JsonTrace<W> {
+    fn instruction(&mut self, ip: u16, bytes: &[u8], instruction: &Instruction) {
+        let bytes: String = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        self.pending = Some(format!(
+            "\"ip\":{},\"bytes\":\"{}\",\"mnemonic\":\"{}\",\"instruction\":{}",
+            ip,
+            bytes,
+            instruction.mnemonic(),
+            json_string(&instruction.to_string())
+        ));
+    }
+    fn executed(&mut self, state: &CpuState) {
+        let Some(line) = self.pending.take() else {
+            return;
+        };
+        let regs: Vec<String> = REGISTER_NAMES
+            .iter()
+            .zip(state.registers)
+            .map(|(name, value)| format!("\"{}\":{}", name, value))
+            .collect();
+        let flags = &state.flags;
+        let _ = writeln!(
+            self.out,
+            "{{{},\"regs_after\":{{{}}},\"flags_after\":{{\"cf\":{},\"pf\":{},\"af\":{},\"zf\":{},\"sf\":{},\"if\":{},\"df\":{},\"of\":{}}}}}",
+            line,
+            regs.join(","),
+            flags.cf,
+            flags.pf,
+            flags.af,
+            flags.zf,
+            flags.sf,
+            flags.if_,
+            flags.df,
+            flags.of
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::decode;
+
+    /// The line `TraceWriter` writes for `bytes` at `ip` leaving `state` behind
+    fn trace_line(ip: u16, bytes: &[u8], state: CpuState) -> String {
+        let initial = CpuState {
+            registers: [0; 12],
+            flags: Flags::default(),
+        };
+        let mut writer = TraceWriter::new(Vec::new(), initial);
+        writer.instruction(ip, bytes, &decode(bytes).unwrap().0);
+        writer.executed(&state);
+        String::from_utf8(writer.out).unwrap()
+    }
+
+    #[test]
+    fn trace_writer_flags_include_af() {
+        let mut registers = [0; 12];
+        registers[0] = 0x10;
+        let flags = Flags {
+            af: true,
+            ..Flags::default()
+        };
+        assert_eq!(
+            trace_line(0x0002, &[0xFE, 0xC0], CpuState { registers, flags }),
+            "0002\tFEC0\tinc al\tax=0010\tA\n"
+        );
+    }
+
+    #[test]
+    fn json_trace_reports_every_flag() {
+        let mut trace = JsonTrace::new(Vec::new());
+        trace.instruction(0, &[0xFB], &decode(&[0xFB]).unwrap().0);
+        let flags = Flags {
+            if_: true,
+            ..Flags::default()
+        };
+        trace.executed(&CpuState {
+            registers: [0; 12],
+            flags,
+        });
+        let line = String::from_utf8(trace.out).unwrap();
+        assert!(line.ends_with(
+            "\"flags_after\":{\"cf\":false,\"pf\":false,\"af\":false,\"zf\":false,\
+             \"sf\":false,\"if\":true,\"df\":false,\"of\":false}}\n"
+        ));
+    }
+
+    #[test]
+    fn tracer_sees_exactly_the_executed_instructions() {
+        use crate::cpu::Cpu;
+        use std::{cell::RefCell, rc::Rc};
+
+        /// Logs each instruction as it starts and counts the ones that finished
+        #[derive(Default)]
+        struct Log {
+            started: Vec<String>,
+            executed: usize,
+        }
+
+        struct Counter(Rc<RefCell<Log>>);
+
+        impl Tracer for Counter {
+            fn instruction(&mut self, ip: u16, bytes: &[u8], instruction: &Instruction) {
+                let text = format!("{:04X} {:02X?} {}", ip, bytes, instruction);
+                self.0.borrow_mut().started.push(text);
+            }
+            fn executed(&mut self, _state: &CpuState) {
+                self.0.borrow_mut().executed += 1;
+            }
+        }
+
+        // mov cx, 2; dec cx; jne $-1; mov ax, 1
+        let mut cpu = Cpu::from_bytes(&[0xB9, 0x02, 0x00, 0x49, 0x75, 0xFD, 0xB8, 0x01, 0x00]);
+        let log = Rc::default();
+        cpu.set_tracer(Counter(Rc::clone(&log)));
+        cpu.run().unwrap();
+        assert_eq!(
+            log.borrow().started,
+            [
+                "0000 [B9, 02, 00] mov cx, 2",
+                "0003 [49] dec cx",
+                "0004 [75, FD] jne $-1",
+                "0003 [49] dec cx",
+                "0004 [75, FD] jne $-1",
+                "0006 [B8, 01, 00] mov ax, 1",
+            ]
+        );
+        assert_eq!(log.borrow().executed, 6);
+
+        // Nothing is observed once the tracer is removed
+        let mut cpu = Cpu::from_bytes(&[0x90, 0x90]);
+        cpu.set_tracer(Counter(Rc::clone(&log)));
+        cpu.step().unwrap();
+        cpu.remove_tracer();
+        cpu.step().unwrap();
+        assert_eq!(log.borrow().executed, 7);
+        assert_eq!(log.borrow().started.len(), 7);
+    }
+
+    #[test]
+    fn trace_writer_diffs_against_the_state_before_the_first_instruction() {
+        use crate::{cpu::Cpu, instruction::Register};
+
+        let code = [0xB8, 0x05, 0x00, 0x89, 0xC3]; // mov ax, 5; mov bx, ax
+        let mut cpu = Cpu::from_bytes(&code);
+        cpu.set_register(Register::DS, 0x1000);
+        cpu.set_register(Register::CX, 7);
+        let mut writer = TraceWriter::new(Vec::new(), cpu.state());
+        for (ip, bytes) in [(0, &code[..3]), (3, &code[3..])] {
+            writer.instruction(ip, bytes, &decode(bytes).unwrap().0);
+            cpu.step().unwrap();
+            writer.executed(&cpu.state());
+        }
+        assert_eq!(
+            String::from_utf8(writer.out).unwrap(),
+            "0000\tB80500\tmov ax, 5\tax=0005\t-\n0003\t89C3\tmov bx, ax\tbx=0005\t-\n"
+        );
+    }
+
+    #[test]
+    fn wrapping_arithmetic_reports_wraparounds() {
+        use crate::cpu::Cpu;
+        use std::{cell::RefCell, rc::Rc};
+
+        /// ip, mnemonic and result of each wraparound
+        type Log = Vec<(u16, &'static str, u16)>;
+
+        struct Wraps(Rc<RefCell<Log>>);
+
+        impl Tracer for Wraps {
+            fn event(&mut self, event: &Event) {
+                if let Event::Wraparound {
+                    ip,
+                    mnemonic,
+                    result,
+                } = event
+                {
+                    self.0.borrow_mut().push((*ip, *mnemonic, *result));
+                }
+            }
+        }
+
+        // mov al, 0xff; add al, 1; sub al, 1; mov bx, 0xffff; inc bx; add al, 0
+        let code = [
+            0xB0, 0xFF, 0x04, 0x01, 0x2C, 0x01, 0xBB, 0xFF, 0xFF, 0x43, 0x04, 0x00,
+        ];
+        let mut cpu = Cpu::from_bytes(&code);
+        let wraps = Rc::default();
+        cpu.set_tracer(Wraps(Rc::clone(&wraps)));
+        cpu.run().unwrap();
+        assert_eq!(
+            *wraps.borrow(),
+            [
+                (0x0002, "add", 0x00),
+                (0x0004, "sub", 0xFF),
+                (0x0009, "inc", 0x0000)
+            ]
+        );
+    }
+
+    #[test]
+    fn relation_reads_compared_values_as_unsigned_and_signed() {
+        assert_eq!(Relation::new(5, 3, 16).to_string(), "above, greater");
+        assert_eq!(Relation::new(3, 5, 16).to_string(), "below, less");
+        assert_eq!(Relation::new(7, 7, 16).to_string(), "equal");
+        // 0xff is 255 unsigned but -1 signed at byte width, and 255 at word width
+        assert_eq!(Relation::new(0xFF, 1, 8).to_string(), "above, less");
+        assert_eq!(Relation::new(0xFF, 1, 16).to_string(), "above, greater");
+        assert_eq!(Relation::new(0xFFFF, 1, 16).to_string(), "above, less");
+    }
+
+    #[test]
+    fn listing_follows_each_instruction_with_its_memory_accesses() {
+        use crate::cpu::Cpu;
+        use std::{cell::RefCell, rc::Rc};
+
+        /// Output the test can still read once the listing is handed to the CPU
+        #[derive(Clone, Default)]
+        struct Shared(Rc<RefCell<Vec<u8>>>);
+
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        // mov bx, 0x100; mov al, 5; mov [bx + 2], al; mov cx, [bx + 2]
+        let code = [
+            0xBB, 0x00, 0x01, 0xB0, 0x05, 0x88, 0x47, 0x02, 0x8B, 0x4F, 0x02,
+        ];
+        let mut cpu = Cpu::from_bytes(&code);
+        let out = Shared::default();
+        cpu.set_tracer(Listing::new(out.clone()));
+        cpu.run().unwrap();
+        assert_eq!(
+            String::from_utf8(out.0.take()).unwrap(),
+            "0000  BB 00 01          mov bx, 256\n\
+             0003  B0 05             mov al, 5\n\
+             0005  88 47 02          mov [bx + 2], al ; write [0x00102] = 0x05\n\
+             0008  8B 4F 02          mov cx, [bx + 2] ; read [0x00102] = 0x0005\n"
+        );
+    }
+}